@@ -0,0 +1,48 @@
+// Extractor of self-extracting archives. The archive is appended to this executable by the sfx mode of fct4_rust.
+use libfct4::fct_archive::{ExtractAction, ExtractOptions, FctArchive};
+use std::path::PathBuf;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("Self-extracting FCT archive. Usage: {} [output directory (default: current directory)]\n\
+            \x20   -l, --list - List the contents instead of extracting them", args[0]);
+        return;
+    }
+    let executable_path = match std::env::current_exe() {
+        Ok(path) => path,
+        Err(e) => {
+            println!("Could not find the archive: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut archive = match FctArchive::open(&executable_path) {
+        Ok(archive) => archive,
+        Err(e) => {
+            println!("Could not open the archive: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if args.iter().any(|arg| arg == "-l" || arg == "--list") {
+        archive.list_files();
+        return;
+    }
+
+    let output_folder = match args.get(1) {
+        Some(path) => PathBuf::from(path),
+        None => PathBuf::from(".")
+    };
+    let mut indices: Vec<u32> = (0..archive.get_headers().len() as u32).collect();
+    let reports = archive.extract_files_with_options(&output_folder, &mut indices, &ExtractOptions::default());
+    let mut failed = 0;
+    for report in reports {
+        if let ExtractAction::Failed(e) = report.action {
+            println!("Failed to extract {}: {}", report.path.display(), e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        std::process::exit(1);
+    }
+    println!("All files have successfully been extracted to {}", output_folder.display());
+}
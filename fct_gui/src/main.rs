@@ -1,53 +1,360 @@
-use libfct4::{fs_operations, fct_archive::FctArchive};
-use std::path::PathBuf;
+use libfct4::{diff, fs_operations::{self, ExpandOptions, FileFilter, PathRewrite}, storage, watch::{ArchiveWatcher, WatchOptions}, fct_archive::{ArchiveOptions, FctArchive, ExtractAction, ExtractOptions, MergeAction, MergeConflictPolicy, OverwritePolicy}};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, UNIX_EPOCH};
 
 fn show_help(program_name: &String) {
     println!(
         "FCT File Container is an archival software used to pack files\n\
         Modes:\n\
         a - Append to archive. Usage: {0} a <path to archive> <paths to files or directories>\n\
-        c - Create archive. Usage: {0} c <chunk size (max: 65535)> <path to new archive> <paths to files or directories>\n\
+        c - Create archive. Usage: {0} c <chunk size (1 to 65535, or auto)> <path to new archive> <paths to files or directories>\n\
+            \x20   --dedup - Store identical chunks only once\n\
+            \x20   --sparse - Store chunks consisting of zeros only as holes\n\
+            \x20   --tail-pack - Pack the last partial chunks of files together instead of padding them\n\
+            \x20   --entry-chunk-size - Choose a chunk size for every file from its size, the given chunk size is the default\n\
+            \x20   --volume-size <bytes> - Split the archive into volumes (name.001, name.002, ...) of at most this size (K, M and G suffixes allowed)\n\
+            \x20   --mtime - Record the modification time of every file\n\
+            \x20   Options for a and c:\n\
+            \x20   --include <glob> - Only add files matching the glob, can be given multiple times\n\
+            \x20   --exclude <glob> - Skip files and directories matching the glob, can be given multiple times\n\
+            \x20   --exclude-ignored - Skip files listed in .gitignore, .ignore and .fctignore files\n\
+            \x20   --max-depth <n> - Do not descend more than n directories deep\n\
+            \x20   --newer-than <unix seconds or reference file> - Only add files modified after the given time\n\
+            \x20   --min-size <bytes> - Only add files of at least this size (K, M and G suffixes allowed)\n\
+            \x20   --max-size <bytes> - Only add files of at most this size (K, M and G suffixes allowed)\n\
+            \x20   --one-file-system - Stay on the file system of the first given path\n\
+            \x20   -T <list file> - Also add the paths listed in the file, one per line. Use - to read from stdin\n\
+            \x20   --null - Paths in the list are separated by NUL bytes instead of newlines, as printed by find -print0\n\
+            \x20   --threads <n> - Read the files with n threads at once, 0 for one per processor\n\
+        d - Compare an archive with a directory or another archive, exiting with 1 if they differ. Usage: {0} d <path to archive> <path to directory or archive>\n\
+            \x20   --json - Print the differences as JSON\n\
+            \x20   --strip-components, --transform, --flatten - Compare with the paths the entries would be extracted to, see e\n\
+            \x20   The filter options of a and c choose the files on disk that are compared\n\
         e - Extract from archive. Usage: {0} e <path to archive> <output directory> <file indices (if none, all is extracted)>\n\
+            \x20   --overwrite <skip|overwrite|newer|rename|fail> - What to do with existing files (default: overwrite)\n\
+            \x20       newer overwrites only with entries modified later, archives created without --mtime never are\n\
+            \x20   --strip-components <n> - Remove the first n components of every stored path\n\
+            \x20   --transform <s/regex/replacement/[g]> - Rename stored paths, can be given multiple times\n\
+            \x20   --flatten - Extract all files directly into the output directory\n\
+            \x20   --threads <n> - Extract with n threads at once, 0 for one per processor\n\
         h - Show help. Usage: {0} h\n\
-        l - List archive contents Usage: {0} l <path to archive> <file indices (if none, all is shown)>",
+        k - Rewrite the archive with a different chunk size. Usage: {0} k <path to archive> <chunk size (1 to 65535, or auto)> <path to new archive (if none, the archive is replaced)>\n\
+        l - List archive contents Usage: {0} l <path to archive> <file indices (if none, all is shown)>\n\
+        m - Merge archives into a target archive, which is created if missing. Usage: {0} m <path to target archive> <paths to source archives>\n\
+            \x20   --on-conflict <skip|replace|rename|keep|fail> - What to do with entries whose name is taken (default: fail)\n\
+        s - Show how the space of the archive is used. Usage: {0} s <path to archive>\n\
+            \x20   --json - Print the report as JSON\n\
+        v - Check that all entries of the archive can be read in full. Usage: {0} v <path to archive>\n\
+        w - Add files to an archive as they appear in a directory, until stopped. Usage: {0} w <path to archive> <directory>\n\
+            \x20   --debounce <seconds> - How long a file has to stay unchanged before it is added (default: 2)\n\
+            \x20   --rotate-size <bytes> - Move the archive aside and start a new one once it reaches this size (K, M and G suffixes allowed)\n\
+            \x20   --rotate-entries <n> - Move the archive aside and start a new one once it holds this many entries\n\
+            \x20   --chunk-size <n> - Chunk size to create the archive with if it does not exist\n\
+        x - Create a self-extracting executable from an archive. Usage: {0} x <path to archive> <path to executable>\n\
+            \x20   --stub <path> - The extractor to put in front of the archive (default: fct_sfx next to this program)\n\
+        y - Synchronize an archive with a directory: add new files, replace changed ones and remove entries of deleted files. Usage: {0} y <path to archive> <directory>\n\
+            \x20   The filter options of a and c choose the files that are added or updated, entries are only removed once their file is gone",
         //v - Can be added to all file modes for verbose output", 
         program_name
     )
 }
 
+// removes an option of the form "--name value" or "--name=value" from the arguments and returns its value
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>, String> {
+    let prefix = format!("{}=", name);
+    match args.iter().position(|arg| arg == name || arg.starts_with(&prefix)) {
+        Some(index) => {
+            let arg = args.remove(index);
+            if arg == name {
+                if index >= args.len() {
+                    return Err(format!("Option {} requires a value", name));
+                }
+                Ok(Some(args.remove(index)))
+            }
+            else {
+                Ok(Some(arg[prefix.len()..].to_string()))
+            }
+        },
+        None => Ok(None)
+    }
+}
+
+// removes every occurrence of an option and returns the values in order
+fn take_options(args: &mut Vec<String>, name: &str) -> Result<Vec<String>, String> {
+    let mut values: Vec<String> = Vec::new();
+    while let Some(value) = take_option(args, name)? {
+        values.push(value);
+    }
+    Ok(values)
+}
+
+// removes a flag without a value from the arguments and returns whether it was present
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    match args.iter().position(|arg| arg == name) {
+        Some(index) => {
+            args.remove(index);
+            true
+        },
+        None => false
+    }
+}
+
+fn parse_path_rewrites(args: &mut Vec<String>) -> Result<Vec<PathRewrite>, String> {
+    let mut rewrites: Vec<PathRewrite> = Vec::new();
+    if let Some(value) = take_option(args, "--strip-components")? {
+        match value.parse::<usize>() {
+            Ok(n) => rewrites.push(PathRewrite::StripComponents(n)),
+            Err(_) => return Err("Component count must be a number".to_string())
+        }
+    }
+    for expression in take_options(args, "--transform")? {
+        rewrites.push(PathRewrite::from_substitution(&expression).map_err(|e| e.to_string())?);
+    }
+    if take_flag(args, "--flatten") {
+        rewrites.push(PathRewrite::Flatten);
+    }
+    Ok(rewrites)
+}
+
+// parses the options shared by the append and create modes
+fn parse_expand_options(args: &mut Vec<String>) -> Result<ExpandOptions, String> {
+    let mut options = ExpandOptions {
+        include: take_options(args, "--include")?,
+        exclude: take_options(args, "--exclude")?,
+        respect_ignore_files: take_flag(args, "--exclude-ignored"),
+        ..Default::default()
+    };
+    if let Some(value) = take_option(args, "--max-depth")? {
+        match value.parse::<usize>() {
+            Ok(n) => options.max_depth = Some(n),
+            Err(_) => return Err("Maximum depth must be a number".to_string())
+        }
+    }
+    if let Some(value) = take_option(args, "--newer-than")? {
+        // either seconds since the unix epoch or a reference file
+        let filter = match value.parse::<u64>() {
+            Ok(seconds) => FileFilter::NewerThan(UNIX_EPOCH + Duration::from_secs(seconds)),
+            Err(_) => FileFilter::newer_than_file(&PathBuf::from(&value)).map_err(|e| e.to_string())?
+        };
+        options.filters.push(filter);
+    }
+    if let Some(value) = take_option(args, "--min-size")? {
+        options.filters.push(FileFilter::MinSize(parse_size(&value)?));
+    }
+    if let Some(value) = take_option(args, "--max-size")? {
+        options.filters.push(FileFilter::MaxSize(parse_size(&value)?));
+    }
+    options.one_file_system = take_flag(args, "--one-file-system");
+    Ok(options)
+}
+
+// parses a byte count with an optional K, M or G suffix
+fn parse_size(value: &str) -> Result<u64, String> {
+    let (digits, multiplier) = match value.chars().last() {
+        Some('K') | Some('k') => (&value[..value.len() - 1], 1u64 << 10),
+        Some('M') | Some('m') => (&value[..value.len() - 1], 1u64 << 20),
+        Some('G') | Some('g') => (&value[..value.len() - 1], 1u64 << 30),
+        _ => (value, 1u64)
+    };
+    match digits.parse::<u64>() {
+        Ok(n) => Ok(n * multiplier),
+        Err(_) => Err(format!("Invalid size: {}", value))
+    }
+}
+
+fn parse_watch_options(args: &mut Vec<String>) -> Result<WatchOptions, String> {
+    let mut options = WatchOptions::default();
+    if let Some(value) = take_option(args, "--debounce")? {
+        match value.parse::<f64>() {
+            Ok(seconds) if seconds >= 0.0 => options.debounce = Duration::from_secs_f64(seconds),
+            _ => return Err("Debounce time must be a number of seconds".to_string())
+        }
+    }
+    if let Some(value) = take_option(args, "--rotate-size")? {
+        options.rotation.max_size = Some(parse_size(&value)?);
+    }
+    if let Some(value) = take_option(args, "--rotate-entries")? {
+        match value.parse::<usize>() {
+            Ok(n) => options.rotation.max_entries = Some(n),
+            Err(_) => return Err("Entry count must be a number".to_string())
+        }
+    }
+    Ok(options)
+}
+
+// where input paths come from besides the arguments: "-T <list file>" or "-T -" for stdin, optionally with --null
+struct PathListSource {
+    list_file: Option<String>,
+    null_delimited: bool
+}
+
+// --threads <n> of a, c and e, None if the work is not to be spread over threads
+fn parse_threads(args: &mut Vec<String>) -> Result<Option<usize>, String> {
+    match take_option(args, "--threads")? {
+        Some(value) => match value.parse::<usize>() {
+            Ok(threads) => Ok(Some(threads)),
+            Err(_) => Err(String::from("Thread count must be a number"))
+        },
+        None => Ok(None)
+    }
+}
+
+fn parse_path_list_source(args: &mut Vec<String>) -> Result<PathListSource, String> {
+    Ok(PathListSource {
+        list_file: take_option(args, "-T")?,
+        null_delimited: take_flag(args, "--null")
+    })
+}
+
+// opens the path list, canonicalizing its entries as they are read. Invalid entries are reported and skipped.
+fn read_path_list(source: &PathListSource) -> Result<Box<dyn Iterator<Item = PathBuf>>, String> {
+    let reader: Box<dyn BufRead> = match source.list_file.as_deref() {
+        None => return Ok(Box::new(std::iter::empty())),
+        Some("-") => Box::new(std::io::stdin().lock()),
+        Some(list_file) => match File::open(list_file) {
+            Ok(f) => Box::new(BufReader::new(f)),
+            Err(_) => return Err(format!("Could not open path list: {}", list_file))
+        }
+    };
+    let paths = fs_operations::read_path_list(reader, source.null_delimited).filter_map(|entry| {
+        match entry {
+            Ok(path) => match std::fs::canonicalize(&path) {
+                Ok(p) => Some(p),
+                Err(_) => {
+                    println!("Path is invalid: {}", path.display());
+                    None
+                }
+            },
+            Err(e) => {
+                println!("Error reading path list: {}", e);
+                None
+            }
+        }
+    });
+    Ok(Box::new(paths))
+}
+
+// canonicalizes the given paths and lazily expands them, together with the path list, into the files to add
+fn collect_paths(path_args: &[String], source: &PathListSource, options: &ExpandOptions) -> Option<Box<dyn Iterator<Item = PathBuf>>> {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    for path in path_args {
+        match std::fs::canonicalize(PathBuf::from(&path)) {
+            Ok(p) => paths.push(p),
+            Err(_) => {
+                println!("Path is invalid: {}", path);
+                return None;
+            }
+        }
+    }
+    let mut options = options.clone();
+    if options.one_file_system {
+        // files given directly must live on the same file system as the first input
+        let reference = match paths.first() {
+            Some(p) => p.clone(),
+            None => PathBuf::from(".")
+        };
+        match FileFilter::same_device_as(&reference) {
+            Ok(filter) => options.filters.push(filter),
+            Err(e) => {
+                println!("{}", e);
+                return None;
+            }
+        }
+    }
+    let listed_paths = match read_path_list(source) {
+        Ok(p) => p,
+        Err(e) => {
+            println!("{}", e);
+            return None;
+        }
+    };
+    match fs_operations::expand_paths_iter(paths.into_iter().chain(listed_paths), &options) {
+        Ok(mut files) => Some(Box::new(std::iter::from_fn(move || {
+            let file = files.next();
+            for (path, e) in files.take_failed() {
+                println!("{}: {}", e, path.display());
+            }
+            file
+        }))),
+        Err(e) => {
+            println!("{}", e);
+            None
+        }
+    }
+}
+
+fn parse_overwrite_policy(value: &str) -> Option<OverwritePolicy> {
+    match value {
+        "skip" => Some(OverwritePolicy::Skip),
+        "overwrite" => Some(OverwritePolicy::Overwrite),
+        "newer" => Some(OverwritePolicy::OverwriteIfNewer),
+        "rename" => Some(OverwritePolicy::Rename),
+        "fail" => Some(OverwritePolicy::Fail),
+        _ => None
+    }
+}
+
+fn parse_merge_conflict_policy(value: &str) -> Option<MergeConflictPolicy> {
+    match value {
+        "skip" => Some(MergeConflictPolicy::Skip),
+        "replace" => Some(MergeConflictPolicy::Replace),
+        "rename" => Some(MergeConflictPolicy::Rename),
+        "keep" => Some(MergeConflictPolicy::KeepBoth),
+        "fail" => Some(MergeConflictPolicy::Fail),
+        _ => None
+    }
+}
+
+// the canonical path of the file an archive starts with, so that an archive can be recognized under any path
+fn archive_start_file(archive_path: &Path) -> Option<PathBuf> {
+    std::fs::canonicalize(storage::first_file(archive_path)).ok()
+}
+
 fn main() {
-    // print current directory
-    println!("Current directory: {}", std::env::current_dir().unwrap().display());
-    let args: Vec<String> = std::env::args().collect();
+    // print current directory, to stderr so that reports like stats --json stay parseable
+    eprintln!("Current directory: {}", std::env::current_dir().unwrap().display());
+    let mut args: Vec<String> = std::env::args().collect();
     if args.len() == 1 {
         show_help(&args[0]);
         return;
     }
     match args[1].as_str() {
         "a" | "append" => {
+            let mut expand_options = match parse_expand_options(&mut args) {
+                Ok(o) => o,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
+            let list_source = match parse_path_list_source(&mut args) {
+                Ok(s) => s,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
+            let threads = match parse_threads(&mut args) {
+                Ok(t) => t,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
             let archive_path: PathBuf = PathBuf::from(args.get(2).expect("No archive path specified"));
             
-            if args.len() < 4 {
+            if args.len() < 4 && list_source.list_file.is_none() {
                 println!("No files or directories specified");
                 return;
             }
-            let mut paths: Vec<PathBuf> = Vec::new();
-            for path in &args[3..] {
-                let path: PathBuf = match std::fs::canonicalize(PathBuf::from(&path)) {
-                    Ok(p) => p,
-                    Err(_) => {
-                        println!("Path is invalid");
-                        return;
-                    }
-                };
-
-                if path.is_dir() {
-                    let mut expanded_paths: Vec<PathBuf> = fs_operations::expand_directory(&path);
-                    paths.append(&mut expanded_paths);
-                } else {
-                    paths.push(path);
-                }
-            }
+            expand_options.exclude_paths.push(archive_path.clone());
+            let paths = match collect_paths(args.get(3..).unwrap_or_default(), &list_source, &expand_options) {
+                Some(p) => p,
+                None => return
+            };
             let mut archive = match FctArchive::open(&archive_path) {
                 Ok(archive) => archive,
                 Err(e) => {
@@ -55,8 +362,11 @@ fn main() {
                     return;
                 }
             };
-            let failed_files = archive.add_files(&paths);
-            if failed_files.len() > 0 {
+            let failed_files = match threads {
+                Some(threads) => archive.add_files_parallel(paths, threads),
+                None => archive.add_files(paths)
+            };
+            if !failed_files.is_empty() {
                 println!("Failed to add files:");
                 for file in failed_files {
                     println!("{}", file.display());
@@ -65,44 +375,105 @@ fn main() {
             else {
                 println!("All files have successfully been added to the archive");
             }
-            return;
         }
         "c" | "create" => {
-            // get next argument and parse to u16
-            let chunk_size: u16 = match args.get(2).expect("No chunk size specified!").parse::<u16>() {
-                Ok(n) => n,
-                Err(_) => {
-                    println!("Chunk size must be a number between 0 and 65535");
+            let mut expand_options = match parse_expand_options(&mut args) {
+                Ok(o) => o,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
+            let list_source = match parse_path_list_source(&mut args) {
+                Ok(s) => s,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
+            let threads = match parse_threads(&mut args) {
+                Ok(t) => t,
+                Err(e) => {
+                    println!("{}", e);
                     return;
                 }
             };
+            let volume_size = match take_option(&mut args, "--volume-size") {
+                Ok(Some(value)) => match parse_size(&value) {
+                    Ok(n) if n > 0 => Some(n),
+                    Ok(_) => {
+                        println!("Volume size must not be 0");
+                        return;
+                    },
+                    Err(e) => {
+                        println!("{}", e);
+                        return;
+                    }
+                },
+                Ok(None) => None,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
+            let archive_options = ArchiveOptions {
+                dedup: take_flag(&mut args, "--dedup"),
+                sparse: take_flag(&mut args, "--sparse"),
+                tail_pack: take_flag(&mut args, "--tail-pack"),
+                entry_chunk_size: take_flag(&mut args, "--entry-chunk-size"),
+                volume_size,
+                modified_time: take_flag(&mut args, "--mtime")
+            };
+            // get next argument and parse to u16, "auto" is resolved once the files are known
+            let chunk_size: Option<u16> = match args.get(2).expect("No chunk size specified!").as_str() {
+                "auto" => None,
+                value => match value.parse::<u16>() {
+                    Ok(n) if n > 0 => Some(n),
+                    _ => {
+                        println!("Chunk size must be a number between 1 and 65535 or auto");
+                        return;
+                    }
+                }
+            };
             // get next argument and parse to PathBuf
             let archive_path: PathBuf = PathBuf::from(args.get(3).expect("No archive path specified"));
 
-            if args.len() < 5 {
+            if args.len() < 5 && list_source.list_file.is_none() {
                 println!("No files or directories specified");
                 return;
             }
-            let mut paths: Vec<PathBuf> = Vec::new();
-            for path in &args[4..] {
-                let path: PathBuf = match std::fs::canonicalize(PathBuf::from(&path)) {
-                    Ok(p) => p,
-                    Err(_) => {
-                        println!("Path is invalid");
-                        return;
+            expand_options.exclude_paths.push(archive_path.clone());
+            let mut paths = match collect_paths(args.get(4..).unwrap_or_default(), &list_source, &expand_options) {
+                Some(p) => p,
+                None => return
+            };
+            let chunk_size = match chunk_size {
+                Some(n) => n,
+                None => {
+                    // the file sizes have to be known up front, so the paths are collected
+                    let collected: Vec<PathBuf> = paths.collect();
+                    let recommendation = match FctArchive::recommend_chunk_size(&collected, &archive_options) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            println!("{}", e);
+                            return;
+                        }
+                    };
+                    for skipped_file in &recommendation.skipped_files {
+                        println!("Could not read file, it is left out of the chunk size choice: {}", skipped_file.display());
                     }
-                };
-
-                if path.is_dir() {
-                    let mut expanded_paths: Vec<PathBuf> = fs_operations::expand_directory(&path);
-                    paths.append(&mut expanded_paths);
-                } else {
-                    paths.push(path);
+                    println!(
+                        "Chosen chunk size: {} (projected archive size: {} bytes)",
+                        recommendation.chunk_size,
+                        recommendation.projected_size
+                    );
+                    paths = Box::new(collected.into_iter());
+                    recommendation.chunk_size
                 }
-            }
+            };
 
             // create archive
-            let mut archive =  match FctArchive::create_new(&archive_path, chunk_size) {
+            let mut archive =  match FctArchive::create_with_options(&archive_path, chunk_size, &archive_options) {
                 Ok(created_archive) => {
                     println!("Archive created");
                     created_archive
@@ -113,8 +484,11 @@ fn main() {
                 },
             };
 
-            let failed_files = archive.add_files(&paths);
-            if failed_files.len() > 0 {
+            let failed_files = match threads {
+                Some(threads) => archive.add_files_parallel(paths, threads),
+                None => archive.add_files(paths)
+            };
+            if !failed_files.is_empty() {
                 for failed_file in failed_files {
                     println!("Failed to add file: {}", failed_file.display());
                 }
@@ -124,6 +498,39 @@ fn main() {
             }
         }
         "e" | "extract" => {
+            let mut options = ExtractOptions::default();
+            match take_option(&mut args, "--overwrite") {
+                Ok(Some(value)) => match parse_overwrite_policy(&value) {
+                    Some(policy) => options.overwrite = policy,
+                    None => {
+                        println!("Overwrite policy must be one of: skip, overwrite, newer, rename, fail");
+                        return;
+                    }
+                },
+                Ok(None) => {},
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            }
+            match parse_path_rewrites(&mut args) {
+                Ok(rewrites) => options.path_rewrites = rewrites,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            }
+            let threads = match parse_threads(&mut args) {
+                Ok(t) => t,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
+            if args.len() < 4 {
+                println!("No archive path or output directory specified");
+                return;
+            }
             let archive_path: PathBuf = PathBuf::from(&args[2]);
             let output_folder = PathBuf::from(&args[3]);
             let mut file_indices: Vec<u32> = Vec::new();
@@ -156,16 +563,26 @@ fn main() {
                 },
             };
 
-            let failed_files = archive.extract_files(&output_folder, &mut file_indices);
-            if failed_files.len() > 0 {
-                for failed_file in failed_files {
-                    println!("Failed to extract file: {}", failed_file.display());
+            let reports = match threads {
+                Some(threads) => archive.extract_files_parallel(&output_folder, &mut file_indices, &options, threads),
+                None => archive.extract_files_with_options(&output_folder, &mut file_indices, &options)
+            };
+            let mut failed = false;
+            for report in reports {
+                match report.action {
+                    ExtractAction::Extracted => println!("Extracted: {}", report.path.display()),
+                    ExtractAction::Overwritten => println!("Overwritten: {}", report.path.display()),
+                    ExtractAction::Skipped => println!("Skipped: {}", report.path.display()),
+                    ExtractAction::Renamed(path) => println!("Renamed to: {}", path.display()),
+                    ExtractAction::Failed(e) => {
+                        println!("Failed to extract file: {} ({})", report.path.display(), e);
+                        failed = true;
+                    }
                 }
             }
-            else {
+            if !failed {
                 println!("All files have successfully been extracted from the archive")
             }
-            return;
         }
         "h" | "help" => {
             show_help(&args[0]);
@@ -190,6 +607,235 @@ fn main() {
             };
             archive.list_files();
         }
+        "s" | "stats" => {
+            let json = take_flag(&mut args, "--json");
+            if args.len() < 3 {
+                println!("No archive path specified");
+                return;
+            }
+            let archive_path: PathBuf = PathBuf::from(&args[2]);
+            let mut archive = match FctArchive::open(&archive_path) {
+                Ok(opened_archive) => opened_archive,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                },
+            };
+            match archive.stats() {
+                Ok(stats) if json => println!("{}", stats.to_json()),
+                Ok(stats) => println!("{}", stats),
+                Err(e) => println!("{}", e)
+            }
+        }
+        "d" | "diff" => {
+            let json = take_flag(&mut args, "--json");
+            let path_rewrites = match parse_path_rewrites(&mut args) {
+                Ok(rewrites) => rewrites,
+                Err(e) => {
+                    println!("{}", e);
+                    std::process::exit(2);
+                }
+            };
+            let mut expand_options = match parse_expand_options(&mut args) {
+                Ok(o) => o,
+                Err(e) => {
+                    println!("{}", e);
+                    std::process::exit(2);
+                }
+            };
+            if args.len() < 4 {
+                println!("No archive path or path to compare with specified");
+                std::process::exit(2);
+            }
+            let archive_path: PathBuf = PathBuf::from(&args[2]);
+            let mut archive = match FctArchive::open(&archive_path) {
+                Ok(opened_archive) => opened_archive,
+                Err(e) => {
+                    println!("Failed to open archive: {}", e);
+                    std::process::exit(2);
+                },
+            };
+            expand_options.exclude_paths.extend(storage::archive_files(&storage::archive_base_path(&archive_path)));
+            let other_path = PathBuf::from(&args[3]);
+            let compared = match other_path.is_dir() {
+                true => archive.diff_directory(&other_path, &ExtractOptions { path_rewrites, ..Default::default() }, &expand_options),
+                false => match FctArchive::open(&other_path) {
+                    Ok(mut other) => archive.diff_archive(&mut other),
+                    Err(e) => Err(e)
+                }
+            };
+            match &compared {
+                Ok(report) if json => println!("{}", report.to_json()),
+                Ok(report) if report.has_differences() => println!("{}", report),
+                Ok(_) => {},
+                Err(e) => println!("Failed to compare: {}", e)
+            }
+            std::process::exit(diff::exit_code(&compared));
+        }
+        "w" | "watch" => {
+            let options = match parse_watch_options(&mut args) {
+                Ok(o) => o,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
+            let chunk_size = match take_option(&mut args, "--chunk-size") {
+                Ok(Some(value)) => match value.parse::<u16>() {
+                    Ok(n) if n > 0 => Some(n),
+                    _ => {
+                        println!("Chunk size must be a number between 1 and 65535");
+                        return;
+                    }
+                },
+                Ok(None) => None,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
+            if args.len() < 4 {
+                println!("No archive path or directory specified");
+                return;
+            }
+            let archive_path: PathBuf = storage::archive_base_path(&PathBuf::from(&args[2]));
+            let directory = PathBuf::from(&args[3]);
+            let archive_exists = archive_path.exists() || storage::is_volume_set(&archive_path);
+            let opened = match (archive_exists, chunk_size) {
+                (true, _) => FctArchive::open(&archive_path),
+                (false, Some(chunk_size)) => FctArchive::create_new(&archive_path, chunk_size),
+                (false, None) => {
+                    println!("The archive does not exist, give a chunk size to create it");
+                    return;
+                }
+            };
+            let archive = match opened {
+                Ok(a) => a,
+                Err(e) => {
+                    println!("Failed to open archive: {}", e);
+                    return;
+                }
+            };
+            let mut watcher = match ArchiveWatcher::new(archive, &directory, options) {
+                Ok(w) => w,
+                Err(e) => {
+                    println!("Failed to watch directory: {}", e);
+                    return;
+                }
+            };
+            println!("Watching {}", directory.display());
+            // runs until the process is stopped
+            if let Err(e) = watcher.run(&AtomicBool::new(false)) {
+                println!("Stopped watching: {}", e);
+            }
+        }
+        "x" | "sfx" => {
+            let stub_path = match take_option(&mut args, "--stub") {
+                Ok(Some(path)) => PathBuf::from(path),
+                // the extractor is built next to this program
+                Ok(None) => match std::env::current_exe() {
+                    Ok(path) => path.with_file_name(format!("fct_sfx{}", std::env::consts::EXE_SUFFIX)),
+                    Err(_) => {
+                        println!("Could not find the extractor, give it with --stub");
+                        return;
+                    }
+                },
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
+            if args.len() < 4 {
+                println!("No archive path or executable path specified");
+                return;
+            }
+            let archive_path: PathBuf = PathBuf::from(&args[2]);
+            let output_path: PathBuf = PathBuf::from(&args[3]);
+            let mut archive = match FctArchive::open(&archive_path) {
+                Ok(opened_archive) => opened_archive,
+                Err(e) => {
+                    println!("Failed to open archive: {}", e);
+                    return;
+                },
+            };
+            match archive.write_self_extracting(&stub_path, &output_path) {
+                Ok(_) => println!("Self-extracting archive written to {}", output_path.display()),
+                Err(e) => println!("Failed to create self-extracting archive: {}", e)
+            }
+        }
+        "y" | "sync" => {
+            let mut expand_options = match parse_expand_options(&mut args) {
+                Ok(o) => o,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
+            if args.len() < 4 {
+                println!("No archive path or directory specified");
+                return;
+            }
+            let archive_path: PathBuf = storage::archive_base_path(&PathBuf::from(&args[2]));
+            let directory = PathBuf::from(&args[3]);
+            expand_options.exclude_paths.extend(storage::archive_files(&archive_path));
+            let mut archive = match FctArchive::open(&archive_path) {
+                Ok(archive) => archive,
+                Err(e) => {
+                    println!("Failed to open archive: {}", e);
+                    return;
+                }
+            };
+            let report = match archive.sync(&directory, &expand_options) {
+                Ok(r) => r,
+                Err(e) => {
+                    println!("Failed to synchronize archive, it has been left unchanged: {}", e);
+                    return;
+                }
+            };
+            for path in &report.added {
+                println!("Added: {}", path.display());
+            }
+            for path in &report.replaced {
+                println!("Replaced: {}", path.display());
+            }
+            for path in &report.removed {
+                println!("Removed: {}", path.display());
+            }
+            for path in &report.failed {
+                println!("Could not read file, its entry is left as it is: {}", path.display());
+            }
+            println!(
+                "{} added, {} replaced, {} removed, {} unchanged, {} failed",
+                report.added.len(),
+                report.replaced.len(),
+                report.removed.len(),
+                report.unchanged,
+                report.failed.len()
+            );
+        }
+        "v" | "verify" => {
+            if args.len() < 3 {
+                println!("No archive path specified");
+                return;
+            }
+            let archive_path: PathBuf = PathBuf::from(&args[2]);
+            let mut archive = match FctArchive::open(&archive_path) {
+                Ok(opened_archive) => opened_archive,
+                Err(e) => {
+                    println!("Failed to open archive: {}", e);
+                    return;
+                },
+            };
+            let problems = archive.verify();
+            if problems.is_empty() {
+                println!("All entries are intact");
+                return;
+            }
+            for (path, problem) in &problems {
+                println!("{}: {}", path.display(), problem);
+            }
+            println!("Found {} problems", problems.len());
+        }
         "r" | "remove" => {
             let archive_path: PathBuf = PathBuf::from(&args[2]);
             let mut archive = match FctArchive::open(&archive_path) {
@@ -223,7 +869,123 @@ fn main() {
                 Ok(()) => println!("All files have successfully been removed from the archive"),
                 Err(e) => println!("Failed to remove files: {}", e)
             }
-            return;
+        }
+        "m" | "merge" => {
+            let policy = match take_option(&mut args, "--on-conflict") {
+                Ok(Some(value)) => match parse_merge_conflict_policy(&value) {
+                    Some(policy) => policy,
+                    None => {
+                        println!("Conflict policy must be one of: skip, replace, rename, keep, fail");
+                        return;
+                    }
+                },
+                Ok(None) => MergeConflictPolicy::default(),
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
+            if args.len() < 4 {
+                println!("No target archive or source archives specified");
+                return;
+            }
+            let archive_path: PathBuf = storage::archive_base_path(&PathBuf::from(&args[2]));
+            let archive_exists = archive_path.exists() || storage::is_volume_set(&archive_path);
+            let mut sources: Vec<FctArchive> = Vec::new();
+            for source_path in &args[3..] {
+                let source_path = storage::archive_base_path(&PathBuf::from(source_path));
+                if archive_exists && archive_start_file(&source_path) == archive_start_file(&archive_path) {
+                    println!("Cannot merge an archive into itself: {}", source_path.display());
+                    return;
+                }
+                match FctArchive::open(&source_path) {
+                    Ok(source) => sources.push(source),
+                    Err(e) => {
+                        println!("Failed to open archive {}: {}", source_path.display(), e);
+                        return;
+                    }
+                }
+            }
+            // a missing target is created like the first source
+            let opened = match archive_exists {
+                true => FctArchive::open(&archive_path),
+                false => FctArchive::create_with_options(&archive_path, sources[0].chunk_size, &sources[0].options())
+            };
+            let mut archive = match opened {
+                Ok(a) => {
+                    println!("Archive opened");
+                    a
+                },
+                Err(e) => {
+                    println!("Failed to open archive: {}", e);
+                    return;
+                }
+            };
+            match archive.merge_from(&mut sources, policy) {
+                Ok(reports) => {
+                    for report in reports {
+                        match report.action {
+                            MergeAction::Added => {},
+                            MergeAction::Replaced => println!("Replaced: {}", report.path.display()),
+                            MergeAction::Skipped => println!("Skipped: {}", report.path.display()),
+                            MergeAction::Renamed(name) => println!("Renamed: {} -> {}", report.path.display(), name.display())
+                        }
+                    }
+                    println!("All archives have successfully been merged");
+                },
+                Err(e) => println!("Failed to merge archives: {}", e)
+            }
+        }
+        "k" | "rechunk" => {
+            if args.len() < 4 {
+                println!("No archive path or chunk size specified");
+                return;
+            }
+            // the first volume of a split archive stands for the whole volume set
+            let archive_path: PathBuf = storage::archive_base_path(&PathBuf::from(&args[2]));
+            let mut archive = match FctArchive::open(&archive_path) {
+                Ok(opened_archive) => {
+                    println!("Archive opened");
+                    opened_archive
+                },
+                Err(e) => {
+                    println!("Failed to open archive: {}", e);
+                    return;
+                },
+            };
+            let chunk_size: u16 = match args[3].as_str() {
+                "auto" => match archive.recommend_rechunk_size() {
+                    Ok(recommendation) => {
+                        println!(
+                            "Chosen chunk size: {} (projected archive size: {} bytes)",
+                            recommendation.chunk_size,
+                            recommendation.projected_size
+                        );
+                        recommendation.chunk_size
+                    },
+                    Err(e) => {
+                        println!("{}", e);
+                        return;
+                    }
+                },
+                value => match value.parse::<u16>() {
+                    Ok(n) if n > 0 => n,
+                    _ => {
+                        println!("Chunk size must be a number between 1 and 65535 or auto");
+                        return;
+                    }
+                }
+            };
+            // without a target, the archive is replaced once the new one is complete
+            let target_path = match args.get(4) {
+                Some(path) => PathBuf::from(path),
+                None => archive_path.clone()
+            };
+            if let Err(e) = archive.rechunk(&target_path, chunk_size) {
+                println!("Failed to rechunk archive: {}", e);
+                return;
+            }
+            println!("All files have successfully been rechunked");
         }
         &_ => show_help(&args[0])
     }
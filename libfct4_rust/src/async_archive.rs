@@ -0,0 +1,626 @@
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use crate::fct_archive::{self, ArchiveOptions, DedupIndex, ExtractAction, ExtractOptions, ExtractReport, FctArchive, OverwritePolicy};
+use crate::fct_archive::{EXTENDED_ARCHIVE_HEADER_SIZE, VOLUME_SIZE_FIELD_SIZE};
+use crate::file_parser::{self, ArchiveLayout, Extent, ExtentSource, FileParser};
+use crate::storage::COPY_BUFFER_SIZE;
+use crate::view;
+use crate::error::*;
+
+// bytes read at once while reading the entry headers, so that the headers of small entries need no read of their own
+const HEADER_WINDOW_SIZE: usize = 64 * 1024;
+
+/// Archive read and written with tokio's asynchronous I/O, for async code that the blocking FctArchive would hold up.
+/// The archive is a file or any stream that can be read and seeked in, and entries can be added to streams that can
+/// be written as well. The entry headers are parsed by the same FileParser as in FctArchive.
+/// Split and self-extracting archives are not supported.
+pub struct AsyncFctArchive<S = File> {
+    pub chunk_size: u16,
+    stream: S,
+    // empty for archives read from a stream
+    archive_path: PathBuf,
+    headers: Vec<FileParser>,
+    features: u16,
+    data_start: u64,
+    layout: ArchiveLayout,
+    dedup_index: Option<DedupIndex>,
+    // bytes of the last tail block taken by entries, new tails are appended to it while they fit
+    tail_block_used: u16
+}
+
+/// Reads the contents of an entry of an asynchronous archive
+pub struct AsyncEntryReader<'a, S> {
+    stream: &'a mut S,
+    extents: Vec<Extent>,
+    extent_index: usize,
+    extent_position: u64,
+    seek: SeekState
+}
+
+// whether the stream is at the current read position of an entry reader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SeekState {
+    Unpositioned,
+    Seeking,
+    Positioned
+}
+
+impl AsyncFctArchive<File> {
+    /// Open an existing archive file, for reading only if it cannot be written to
+    pub async fn open<P: AsRef<Path>>(archive_path: P) -> Result<Self, &'static str> {
+        let file = match OpenOptions::new().read(true).write(true).open(archive_path.as_ref()).await {
+            Ok(file) => file,
+            Err(_) => unwrap_or_return_error!(File::open(archive_path.as_ref()).await, "Error opening archive file")
+        };
+        let mut archive = Self::from_stream(file).await?;
+        archive.archive_path = archive_path.as_ref().to_path_buf();
+        Ok(archive)
+    }
+
+    /// Create a new archive file using the given format options
+    pub async fn create<P: AsRef<Path>>(archive_path: P, chunk_size: u16, options: &ArchiveOptions) -> Result<Self, &'static str> {
+        let file = unwrap_or_return_error!(
+            OpenOptions::new().read(true).write(true).create(true).truncate(true).open(archive_path.as_ref()).await,
+            "Error creating archive: Could not create file."
+        );
+        let mut archive = Self::create_in(file, chunk_size, options).await?;
+        archive.archive_path = archive_path.as_ref().to_path_buf();
+        Ok(archive)
+    }
+
+    /// Add a file, storing its path relative to the current directory like FctArchive::add_file
+    pub async fn add_file<P: AsRef<Path>>(&mut self, file_path: P) -> Result<(), &'static str> {
+        let file_path = file_path.as_ref().to_path_buf();
+        let (features, chunk_size) = (self.features, self.chunk_size);
+        // opening the file and working out its stored path are blocking calls
+        let prepared = tokio::task::spawn_blocking(move || {
+            let current_dir = unwrap_or_return_error!(std::env::current_dir(), "Could not get current directory");
+            FctArchive::prepare_file(&file_path, &current_dir, None, features, chunk_size)
+        }).await;
+        let prepared = match prepared {
+            Ok(prepared) => prepared?,
+            Err(_) => return Err("Error adding file: Could not open file")
+        };
+        println!("Adding file: {}", prepared.parser.file_path.display());
+        let mut file = File::from_std(prepared.file);
+        self.write_entry(&mut file, prepared.parser).await
+    }
+
+    /// Add files and return the list of failed files
+    pub async fn add_files<I, P>(&mut self, file_paths: I) -> Vec<PathBuf> where I: IntoIterator<Item = P>, P: AsRef<Path> {
+        let mut failed_files: Vec<PathBuf> = Vec::new();
+        for file_path in file_paths {
+            if let Err(e) = self.add_file(file_path.as_ref()).await {
+                println!("Error adding file {}: {}", file_path.as_ref().display(), e);
+                failed_files.push(file_path.as_ref().to_path_buf());
+            }
+        }
+        failed_files
+    }
+}
+
+impl<S: AsyncRead + AsyncSeek + Unpin> AsyncFctArchive<S> {
+    /// Read an archive from a stream, which starts with the archive header, and the headers of all its entries
+    pub async fn from_stream(mut stream: S) -> Result<Self, &'static str> {
+        unwrap_or_return_error!(stream.seek(SeekFrom::Start(0)).await, "Could not seek to start of archive");
+        let mut archive_header = Vec::new();
+        let header_size = (EXTENDED_ARCHIVE_HEADER_SIZE + VOLUME_SIZE_FIELD_SIZE) as u64;
+        unwrap_or_return_error!((&mut stream).take(header_size).read_to_end(&mut archive_header).await, "Invalid archive header");
+        let (chunk_size, features, data_start) = view::parse_archive_header(&archive_header)?;
+        if features & file_parser::FEATURE_MULTI_VOLUME != 0 {
+            return Err("Split archives are not supported");
+        }
+        let mut archive = AsyncFctArchive {
+            chunk_size,
+            stream,
+            archive_path: PathBuf::new(),
+            headers: Vec::new(),
+            features,
+            data_start: data_start as u64,
+            layout: ArchiveLayout { chunk_size, features, chunk_offsets: Vec::new(), tail_blocks: Vec::new() },
+            dedup_index: None,
+            tail_block_used: 0
+        };
+        archive.read_headers().await?;
+        Ok(archive)
+    }
+
+    pub fn archive_path(&self) -> &Path {
+        &self.archive_path
+    }
+
+    /// The file headers of the entries in the archive
+    pub fn get_headers(&self) -> &Vec<FileParser> {
+        &self.headers
+    }
+
+    /// Index of the last entry with the given stored path, which is the one extraction leaves behind
+    pub fn find<P: AsRef<Path>>(&self, path: P) -> Option<u32> {
+        self.headers.iter().rposition(|header| header.file_path == path.as_ref()).map(|index| index as u32)
+    }
+
+    /// The stream the archive is stored in
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    // read the headers of all entries and tail blocks. The archive is read in windows, which are read again
+    // from a header reaching past the end of the window. Like FctArchive, the entries end at the first damaged header.
+    async fn read_headers(&mut self) -> Result<(), &'static str> {
+        self.headers.clear();
+        self.layout.chunk_offsets.clear();
+        self.layout.tail_blocks.clear();
+        let end = unwrap_or_return_error!(self.stream.seek(SeekFrom::End(0)).await, "Could not seek to end of archive");
+        let mut window: Vec<u8> = Vec::new();
+        let mut window_start = self.data_start;
+        let mut window_size = HEADER_WINDOW_SIZE as u64;
+        let mut offset = self.data_start;
+        while offset < end {
+            let window_end = window_start + window.len() as u64;
+            let parsed = match offset < window_end {
+                true => {
+                    let mut reader = &window[(offset - window_start) as usize..];
+                    let available = reader.len();
+                    FileParser::from_archive_with_features(&mut reader, self.features, self.chunk_size)
+                        .map(|header| (header, (available - reader.len()) as u64))
+                },
+                false => Err("File header is incomplete")
+            };
+            let header = match parsed {
+                Ok((mut header, header_size)) => {
+                    header.data_offset = offset + header_size;
+                    header
+                },
+                Err(_) if window_end == end => break,
+                Err(_) => {
+                    // a header bigger than the window needs a bigger one
+                    if offset == window_start && !window.is_empty() {
+                        window_size *= 2;
+                    }
+                    window.clear();
+                    window_start = offset;
+                    unwrap_or_return_error!(self.stream.seek(SeekFrom::Start(offset)).await, "Could not seek to file header");
+                    unwrap_or_return_error!(
+                        (&mut self.stream).take(window_size).read_to_end(&mut window).await,
+                        "Could not read file header"
+                    );
+                    continue;
+                }
+            };
+            offset = header.data_offset + header.stored_size();
+            self.layout.add_record(&header);
+            if !header.is_tail_block() {
+                self.headers.push(header);
+            }
+        }
+        self.tail_block_used = self.layout.tail_block_used(&self.headers);
+        Ok(())
+    }
+
+    /// Open a reader over the contents of the entry at the given index
+    pub fn entry_reader(&mut self, index: u32) -> Result<AsyncEntryReader<'_, S>, &'static str> {
+        let extents = match self.headers.get(index as usize) {
+            Some(header) => header.extents(&self.layout)?,
+            None => return Err("Could not find entry")
+        };
+        Ok(AsyncEntryReader {
+            stream: &mut self.stream,
+            extents,
+            extent_index: 0,
+            extent_position: 0,
+            seek: SeekState::Unpositioned
+        })
+    }
+
+    /// Read the contents of the entry at the given index
+    pub async fn read_entry(&mut self, index: u32) -> Result<Vec<u8>, &'static str> {
+        let mut reader = self.entry_reader(index)?;
+        let mut contents = Vec::with_capacity(reader.len() as usize);
+        unwrap_or_return_error!(reader.read_to_end(&mut contents).await, "Could not read file");
+        Ok(contents)
+    }
+
+    /// Extract files from the archive to the output folder, creating subdirectories if necessary,
+    /// and return the list of failed files
+    pub async fn extract_files(&mut self, output_folder: &Path, indices: &mut Vec<u32>) -> Vec<PathBuf> {
+        let options = ExtractOptions { overwrite: OverwritePolicy::Overwrite, ..Default::default() };
+        self.extract_files_with_options(output_folder, indices, &options).await
+            .into_iter()
+            .filter(|report| matches!(report.action, ExtractAction::Failed(_)))
+            .map(|report| report.path)
+            .collect()
+    }
+
+    /// Extract files like FctArchive::extract_files_with_options and report the action taken for every selected entry
+    pub async fn extract_files_with_options(&mut self, output_folder: &Path, indices: &mut Vec<u32>, options: &ExtractOptions) -> Vec<ExtractReport> {
+        if indices.is_empty() {
+            indices.extend(0..self.headers.len() as u32);
+        }
+        indices.sort();
+        indices.dedup();
+        let output_folder = output_folder.to_path_buf();
+        if let Err(e) = tokio::fs::create_dir_all(&output_folder).await {
+            println!("Error extracting files: Could not create output folder: {}", e);
+            // report all selected entries as failed
+            return indices.iter().filter_map(|i| self.headers.get(*i as usize)).map(|header| {
+                ExtractReport {
+                    path: output_folder.join(&header.file_path),
+                    action: ExtractAction::Failed("Could not create output folder")
+                }
+            }).collect();
+        }
+
+        let mut reports: Vec<ExtractReport> = Vec::new();
+        let mut prev_directory: PathBuf = output_folder.clone();
+        for index in indices.iter() {
+            let header = match self.headers.get(*index as usize) {
+                Some(header) => header.clone(),
+                None => continue
+            };
+            let file_path = match FctArchive::map_output_path(&output_folder, &header.file_path, options) {
+                Ok(Some(path)) => path,
+                Ok(None) => {
+                    reports.push(ExtractReport { path: header.file_path.clone(), action: ExtractAction::Skipped });
+                    continue;
+                },
+                Err(e) => {
+                    println!("Error extracting file {}: {}", header.file_path.display(), e);
+                    reports.push(ExtractReport { path: header.file_path.clone(), action: ExtractAction::Failed(e) });
+                    continue;
+                }
+            };
+            let cur_directory = file_path.parent().unwrap();
+            if cur_directory != prev_directory {
+                if let Err(e) = tokio::fs::create_dir_all(cur_directory).await {
+                    println!("Error extracting files: Could not create output folder: {}", e);
+                    reports.push(ExtractReport { path: file_path, action: ExtractAction::Failed("Could not create output folder") });
+                    continue;
+                }
+                prev_directory = cur_directory.to_path_buf();
+            }
+            println!("Extracting file: {}", file_path.display());
+            reports.push(self.extract_entry(&header, &file_path, options.overwrite).await);
+        }
+        reports
+    }
+
+    // extract a single entry to the given path like FctArchive does, with the data read from the stream
+    async fn extract_entry(&mut self, header: &FileParser, file_path: &Path, policy: OverwritePolicy) -> ExtractReport {
+        // looking at the existing file and creating the new one take blocking calls
+        let (created_header, created_path) = (header.clone(), file_path.to_path_buf());
+        let created = tokio::task::spawn_blocking(move || {
+            FctArchive::create_output_file(&created_header, &created_path, policy)
+        }).await;
+        let (report, mut out_file) = match created {
+            Ok((report, Some(out_file))) => (report, File::from_std(out_file)),
+            Ok((report, None)) => return report,
+            Err(_) => return ExtractReport { path: file_path.to_path_buf(), action: ExtractAction::Failed("Could not create file") }
+        };
+        if let Err(e) = self.copy_entry_data(header, &mut out_file).await {
+            return ExtractReport { path: report.path, action: ExtractAction::Failed(e) };
+        }
+        if out_file.flush().await.is_err() {
+            return ExtractReport { path: report.path, action: ExtractAction::Failed("Error extracting file: Could not write file") };
+        }
+        let (finished_header, out_file) = (header.clone(), out_file.into_std().await);
+        match tokio::task::spawn_blocking(move || FctArchive::finish_output_file(&finished_header, &out_file)).await {
+            Ok(Ok(_)) => report,
+            Ok(Err(e)) => ExtractReport { path: report.path, action: ExtractAction::Failed(e) },
+            Err(_) => ExtractReport { path: report.path, action: ExtractAction::Failed("Could not set modification time") }
+        }
+    }
+
+    // copy the contents of an entry to a writer positioned at its start, seeking over holes
+    async fn copy_entry_data<W: AsyncWrite + AsyncSeek + Unpin>(&mut self, header: &FileParser, file: &mut W) -> Result<(), &'static str> {
+        for extent in header.extents(&self.layout)? {
+            let offset = match extent.source {
+                ExtentSource::Archive(offset) => offset,
+                ExtentSource::Zero => {
+                    unwrap_or_return_error!(file.seek(SeekFrom::Current(extent.length as i64)).await, "Error extracting file: Could not seek in file");
+                    continue;
+                }
+            };
+            unwrap_or_return_error!(self.stream.seek(SeekFrom::Start(offset)).await, "Could not seek to file data");
+            let copied = unwrap_or_return_error!(
+                tokio::io::copy(&mut (&mut self.stream).take(extent.length), file).await,
+                "Error extracting file: Could not write file"
+            );
+            if copied != extent.length {
+                return Err("Could not read file");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + AsyncSeek + Unpin> AsyncFctArchive<S> {
+    /// Create a new archive in an empty stream using the given format options
+    pub async fn create_in(mut stream: S, chunk_size: u16, options: &ArchiveOptions) -> Result<Self, &'static str> {
+        if chunk_size == 0 {
+            return Err("Chunk size must not be 0");
+        }
+        if options.volume_size.is_some() {
+            return Err("Split archives are not supported");
+        }
+        let features = options.to_features();
+        let archive_header = view::generate_archive_header(chunk_size, features, None);
+        unwrap_or_return_error!(stream.seek(SeekFrom::Start(0)).await, "Could not seek to start of archive");
+        unwrap_or_return_error!(stream.write_all(&archive_header).await, "Failed to write archive header");
+        unwrap_or_return_error!(stream.flush().await, "Failed to write archive header");
+        Ok(AsyncFctArchive {
+            chunk_size,
+            stream,
+            archive_path: PathBuf::new(),
+            headers: Vec::new(),
+            features,
+            data_start: archive_header.len() as u64,
+            layout: ArchiveLayout { chunk_size, features, chunk_offsets: Vec::new(), tail_blocks: Vec::new() },
+            dedup_index: None,
+            tail_block_used: 0
+        })
+    }
+
+    /// Add the contents of a reader as an entry with the given stored path, size and modification time in seconds
+    /// since the Unix epoch. If the reader ends early, the entry is completed with zeros and an error is returned.
+    pub async fn add_entry<P, R>(&mut self, file_path: P, reader: &mut R, len: u64, modified: u64) -> Result<(), &'static str>
+    where P: AsRef<Path>, R: AsyncRead + Unpin {
+        if file_path.as_ref().to_str().is_none() {
+            return Err("File path is not valid UTF-8");
+        }
+        let chunk_size = match self.features & file_parser::FEATURE_ENTRY_CHUNK_SIZE {
+            0 => self.chunk_size,
+            _ => FctArchive::entry_chunk_size(self.features, self.chunk_size, len)
+        };
+        let mut parser = FileParser { file_path: file_path.as_ref().to_path_buf(), chunk_size, modified, ..Default::default() };
+        parser.set_logical_size(len)?;
+        self.write_entry(reader, parser).await
+    }
+
+    // write an entry whose header describes the contents of the reader to the end of the archive.
+    // If writing fails, the headers are read again, so that they match what made it into the archive.
+    async fn write_entry<R: AsyncRead + Unpin>(&mut self, reader: &mut R, parser: FileParser) -> Result<(), &'static str> {
+        let result = self.append_entry(reader, parser).await;
+        if result.is_err() {
+            self.dedup_index = None;
+            let _ = self.read_headers().await;
+        }
+        result
+    }
+
+    async fn append_entry<R: AsyncRead + Unpin>(&mut self, reader: &mut R, mut parser: FileParser) -> Result<(), &'static str> {
+        // chunk references and holes are only known once the data is written, so the header is written twice
+        parser.set_format(self.features, self.chunk_size);
+        if self.features & file_parser::FEATURE_DEDUP != 0 {
+            self.load_dedup_index().await?;
+        }
+        let mut position = unwrap_or_return_error!(self.stream.seek(SeekFrom::End(0)).await, "Could not seek to end of archive");
+        if parser.has_packed_tail() && fct_archive::place_tail(&mut parser, &self.layout, &mut self.tail_block_used, self.chunk_size) {
+            let (record, data_start) = fct_archive::tail_block_record(self.features, self.chunk_size)?;
+            unwrap_or_return_error!(self.stream.write_all(&record).await, "Could not write tail block");
+            self.layout.tail_blocks.push(position + data_start);
+            position += record.len() as u64;
+        }
+        let header_offset = position;
+        unwrap_or_return_error!(self.stream.write_all(&parser.generate_header()?).await, "Could not write file header");
+        parser.data_offset = header_offset + parser.get_header_size() as u64;
+
+        let complete = self.write_chunks(reader, &mut parser).await?;
+        unwrap_or_return_error!(self.stream.seek(SeekFrom::Start(header_offset)).await, "Could not seek to file header");
+        unwrap_or_return_error!(self.stream.write_all(&parser.generate_header()?).await, "Could not write file header");
+        unwrap_or_return_error!(self.stream.seek(SeekFrom::End(0)).await, "Could not seek to end of archive");
+        unwrap_or_return_error!(self.stream.flush().await, "Could not write to archive");
+        self.layout.add_record(&parser);
+        self.headers.push(parser);
+        match complete {
+            true => Ok(()),
+            false => Err("File ended before its size was reached")
+        }
+    }
+
+    // write the data of an entry behind its header in batches of whole chunks placed like FctArchive places them,
+    // and return whether the reader held all of the contents. Contents missing at the end are stored as zeros.
+    async fn write_chunks<R: AsyncRead + Unpin>(&mut self, reader: &mut R, header: &mut FileParser) -> Result<bool, &'static str> {
+        let chunk_size = header.chunk_size as usize;
+        let data_chunk_count = header.data_chunk_count();
+        let data_end = std::cmp::min(header.logical_size(), data_chunk_count as u64 * chunk_size as u64);
+        let batch_chunks = std::cmp::max(COPY_BUFFER_SIZE / chunk_size, 1) as u32;
+        let mut batch: Vec<u8> = Vec::new();
+        let mut complete = true;
+        let mut first_chunk = 0;
+        while first_chunk < data_chunk_count {
+            let chunks = std::cmp::min(batch_chunks, data_chunk_count - first_chunk);
+            let batch_start = first_chunk as u64 * chunk_size as u64;
+            let length = std::cmp::min(chunks as u64 * chunk_size as u64, data_end - batch_start);
+            batch.clear();
+            let read = unwrap_or_return_error!((&mut *reader).take(length).read_to_end(&mut batch).await, "Could not read file");
+            complete &= read as u64 == length;
+            batch.resize(chunks as usize * chunk_size, 0);
+            for run in fct_archive::place_chunks(header, self.dedup_index.as_mut(), first_chunk, &batch) {
+                unwrap_or_return_error!(self.stream.write_all(&batch[run]).await, "Could not write to archive");
+            }
+            first_chunk += chunks;
+        }
+        if header.has_packed_tail() {
+            let mut tail = Vec::new();
+            let read = unwrap_or_return_error!((&mut *reader).take(header.last_chunk_size as u64).read_to_end(&mut tail).await, "Could not read file");
+            complete &= read == header.last_chunk_size as usize;
+            tail.resize(header.last_chunk_size as usize, 0);
+            let tail_offset = self.layout.tail_blocks[header.tail_block as usize] + header.tail_offset as u64;
+            unwrap_or_return_error!(self.stream.seek(SeekFrom::Start(tail_offset)).await, "Could not seek to tail block");
+            unwrap_or_return_error!(self.stream.write_all(&tail).await, "Could not write to tail block");
+        }
+        Ok(complete)
+    }
+
+    // hash the chunks already stored in the archive, once before the first deduplicated write
+    async fn load_dedup_index(&mut self) -> Result<(), &'static str> {
+        if self.dedup_index.is_some() {
+            return Ok(());
+        }
+        let mut dedup_index = DedupIndex::new();
+        let mut buffer = Vec::new();
+        for (run, chunk_size) in DedupIndex::stored_runs(&self.headers) {
+            buffer.resize((run.end - run.start) as usize, 0);
+            unwrap_or_return_error!(self.stream.seek(SeekFrom::Start(run.start)).await, "Could not seek to chunk");
+            unwrap_or_return_error!(self.stream.read_exact(&mut buffer).await, "Could not read chunk");
+            dedup_index.push(&buffer, chunk_size);
+        }
+        self.dedup_index = Some(dedup_index);
+        Ok(())
+    }
+}
+
+impl<'a, S> AsyncEntryReader<'a, S> {
+    /// Size of the entry's contents
+    pub fn len(&self) -> u64 {
+        self.extents.iter().map(|extent| extent.length).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<'a, S: AsyncRead + AsyncSeek + Unpin> AsyncRead for AsyncEntryReader<'a, S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let reader = self.get_mut();
+        while reader.extent_index < reader.extents.len() && reader.extent_position == reader.extents[reader.extent_index].length {
+            reader.extent_index += 1;
+            reader.extent_position = 0;
+            reader.seek = SeekState::Unpositioned;
+        }
+        if reader.extent_index == reader.extents.len() || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let extent = reader.extents[reader.extent_index];
+        let wanted = std::cmp::min(buf.remaining() as u64, extent.length - reader.extent_position) as usize;
+        let read = match extent.source {
+            ExtentSource::Archive(offset) => {
+                if reader.seek == SeekState::Unpositioned {
+                    Pin::new(&mut *reader.stream).start_seek(SeekFrom::Start(offset + reader.extent_position))?;
+                    reader.seek = SeekState::Seeking;
+                }
+                if reader.seek == SeekState::Seeking {
+                    match Pin::new(&mut *reader.stream).poll_complete(cx) {
+                        Poll::Ready(Ok(_)) => reader.seek = SeekState::Positioned,
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Pending => return Poll::Pending
+                    }
+                }
+                // the stream must not read past the extent, so it gets a buffer of the extent's remaining size
+                let mut limited = ReadBuf::new(buf.initialize_unfilled_to(wanted));
+                match Pin::new(&mut *reader.stream).poll_read(cx, &mut limited) {
+                    Poll::Ready(Ok(_)) => limited.filled().len(),
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending
+                }
+            },
+            ExtentSource::Zero => {
+                buf.initialize_unfilled_to(wanted).fill(0);
+                wanted
+            }
+        };
+        if read == 0 {
+            return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Entry data is truncated")));
+        }
+        buf.advance(read);
+        reader.extent_position += read as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::fct_archive::tests::{assert_contents, create_archive, noise, test_dir};
+    use crate::view::FctView;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(future)
+    }
+
+    fn test_files() -> Vec<(&'static str, Vec<u8>)> {
+        let mut sparse = vec![0u8; 10000];
+        sparse.extend_from_slice(&noise(1500, 27));
+        vec![("first", noise(70000, 28)), ("copy", noise(70000, 28)), ("sparse", sparse), ("small", noise(300, 29)), ("empty", Vec::new())]
+    }
+
+    fn all_features() -> ArchiveOptions {
+        ArchiveOptions { dedup: true, sparse: true, tail_pack: true, entry_chunk_size: true, ..Default::default() }
+    }
+
+    // check the entries of an archive in memory against the files, through a view and an async archive
+    async fn assert_bytes_contents(bytes: Vec<u8>, files: &[(&str, Vec<u8>)]) {
+        let view = FctView::new(&bytes).unwrap();
+        let entries: Vec<_> = view.entries().map(|entry| entry.unwrap()).collect();
+        assert_eq!(entries.len(), files.len());
+        for (entry, (name, contents)) in entries.iter().zip(files) {
+            assert_eq!(entry.header.file_path, Path::new(name));
+            assert!(entry.contents()[..] == contents[..], "viewed contents of {} differ", name);
+        }
+        let mut archive = AsyncFctArchive::from_stream(Cursor::new(bytes.clone())).await.unwrap();
+        assert_eq!(archive.get_headers().len(), files.len());
+        for (index, (name, contents)) in files.iter().enumerate() {
+            assert!(archive.read_entry(index as u32).await.unwrap() == *contents, "async contents of {} differ", name);
+        }
+    }
+
+    #[test]
+    fn async_writer_round_trips_in_memory() {
+        block_on(async {
+            let files = test_files();
+            for options in [ArchiveOptions::default(), all_features()] {
+                let mut archive = AsyncFctArchive::create_in(Cursor::new(Vec::new()), 1024, &options).await.unwrap();
+                for (name, contents) in &files {
+                    archive.add_entry(name, &mut &contents[..], contents.len() as u64, 0).await.unwrap();
+                }
+                assert_eq!(archive.find("small"), Some(3));
+                // a reader ending early leaves an entry completed with zeros
+                let short = noise(100, 30);
+                assert!(archive.add_entry("short", &mut &short[..], 3000, 0).await.is_err());
+                let mut padded = short.clone();
+                padded.resize(3000, 0);
+                assert!(archive.read_entry(5).await.unwrap() == padded);
+
+                let mut files = files.clone();
+                files.push(("short", padded));
+                assert_bytes_contents(archive.into_inner().into_inner(), &files).await;
+            }
+        });
+    }
+
+    #[test]
+    fn async_and_sync_archives_match() {
+        block_on(async {
+            let dir = test_dir("async");
+            let files = test_files();
+            let options = all_features();
+            // archives written by either side are read by the other and by views
+            drop(create_archive(&dir, 1024, &options, &files));
+            let sync_bytes = std::fs::read(dir.join("test.fct")).unwrap();
+            assert_bytes_contents(sync_bytes.clone(), &files).await;
+
+            let async_path = dir.join("async.fct");
+            let mut async_archive = AsyncFctArchive::create(&async_path, 1024, &options).await.unwrap();
+            for (name, contents) in &files {
+                async_archive.add_entry(name, &mut &contents[..], contents.len() as u64, 0).await.unwrap();
+            }
+            let output = dir.join("output");
+            assert!(async_archive.extract_files(&output, &mut Vec::new()).await.is_empty());
+            for (name, contents) in &files {
+                assert!(std::fs::read(output.join(name)).unwrap() == *contents, "extracted {} differs", name);
+            }
+            drop(async_archive);
+            let mut archive = FctArchive::open(&async_path).unwrap();
+            assert_contents(&mut archive, &files);
+            // both lay out the entries the same way
+            assert!(std::fs::read(&async_path).unwrap() == sync_bytes);
+            std::fs::remove_dir_all(&dir).unwrap();
+        });
+    }
+}
@@ -0,0 +1,230 @@
+use std::fmt;
+use std::path::PathBuf;
+use serde::Serialize;
+
+/// What an archive is compared against
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffTarget {
+    Directory,
+    Archive
+}
+
+/// How a path differs between the archive and the compared directory or archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffKind {
+    OnlyInArchive,
+    /// Only on disk, or only in the other archive
+    OnlyInOther,
+    /// On both sides, with at least one difference
+    Changed
+}
+
+/// A property in which the two sides of a path differ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Difference {
+    Size,
+    /// Only compared if both sides know the modification time
+    ModifiedTime,
+    /// Only compared if the sizes are the same
+    Content
+}
+
+/// A path that differs between the archive and the compared directory or archive
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DiffEntry {
+    /// The path relative to the compared directory, or the stored path when comparing archives
+    pub path: PathBuf,
+    pub kind: DiffKind,
+    /// Empty unless the path changed
+    pub differences: Vec<Difference>
+}
+
+/// Result of comparing an archive against a directory or another archive, sorted by path
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DiffReport {
+    pub target: DiffTarget,
+    pub entries: Vec<DiffEntry>
+}
+
+impl DiffReport {
+    /// Whether the two sides differ at all
+    pub fn has_differences(&self) -> bool {
+        !self.entries.is_empty()
+    }
+
+    /// The report as pretty printed JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Could not serialize diff report")
+    }
+}
+
+/// Exit status of a comparison, like the one of diff: 0 if both sides are the same, 1 if they differ and 2 if the
+/// comparison failed
+pub fn exit_code(compared: &Result<DiffReport, &'static str>) -> i32 {
+    match compared {
+        Ok(report) if report.has_differences() => 1,
+        Ok(_) => 0,
+        Err(_) => 2
+    }
+}
+
+impl fmt::Display for DiffReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let other = match self.target {
+            DiffTarget::Directory => "only on disk",
+            DiffTarget::Archive => "only in the other archive"
+        };
+        let mut first = true;
+        for entry in &self.entries {
+            if !first {
+                writeln!(f)?;
+            }
+            first = false;
+            match entry.kind {
+                DiffKind::OnlyInArchive => write!(f, "- {} (only in the archive)", entry.path.display())?,
+                DiffKind::OnlyInOther => write!(f, "+ {} ({})", entry.path.display(), other)?,
+                DiffKind::Changed => {
+                    let differences: Vec<&str> = entry.differences.iter().map(|difference| match difference {
+                        Difference::Size => "size",
+                        Difference::ModifiedTime => "modification time",
+                        Difference::Content => "content"
+                    }).collect();
+                    let verb = if differences.len() == 1 { "differs" } else { "differ" };
+                    write!(f, "~ {} ({} {})", entry.path.display(), differences.join(", "), verb)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+    use std::time::{Duration, UNIX_EPOCH};
+    use crate::fct_archive::{ArchiveOptions, ExtractOptions, FctArchive};
+    use crate::fct_archive::tests::{noise, test_dir};
+    use crate::fs_operations::ExpandOptions;
+
+    // writes the files with the given modification times below the folder and archives them with their times
+    fn archive_of(folder: &Path, files: &[(&str, Vec<u8>, u64)]) -> FctArchive {
+        fs::create_dir_all(folder).unwrap();
+        let mut archive = FctArchive::create_with_options(&folder.with_extension("fct"), 1024, &ArchiveOptions { modified_time: true, ..Default::default() }).unwrap();
+        for (name, contents, modified) in files {
+            write_file(&folder.join(name), contents, *modified);
+            archive.add_file_relative_to(&folder.join(name), &folder.to_path_buf()).unwrap();
+        }
+        drop(archive);
+        FctArchive::open(&folder.with_extension("fct")).unwrap()
+    }
+
+    fn write_file(path: &Path, contents: &[u8], modified: u64) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+        fs::File::options().write(true).open(path).unwrap().set_modified(UNIX_EPOCH + Duration::from_secs(modified)).unwrap();
+    }
+
+    fn kinds(report: &DiffReport) -> Vec<(&str, DiffKind, Vec<Difference>)> {
+        report.entries.iter().map(|entry| (entry.path.to_str().unwrap(), entry.kind, entry.differences.clone())).collect()
+    }
+
+    #[test]
+    fn directory_differences_are_found_by_kind() {
+        let dir = test_dir("diff-directory");
+        let input = dir.join("input");
+        let time = 1_600_000_000;
+        let mut archive = archive_of(&input, &[
+            ("same", noise(3000, 1), time),
+            ("size", noise(3000, 2), time),
+            ("mtime", noise(3000, 3), time),
+            ("content", noise(3000, 4), time),
+            ("sub/gone", noise(10, 5), time)
+        ]);
+        let compare = |archive: &mut FctArchive, expand_options: &ExpandOptions| archive.diff_directory(&input, &ExtractOptions::default(), expand_options);
+        let unchanged = compare(&mut archive, &ExpandOptions::default());
+        assert_eq!(exit_code(&unchanged), 0);
+        assert_eq!(unchanged.unwrap().to_string(), "");
+
+        write_file(&input.join("size"), &noise(3001, 2), time);
+        write_file(&input.join("mtime"), &noise(3000, 3), time + 60);
+        // the same size and time, only the bytes differ
+        write_file(&input.join("content"), &noise(3000, 40), time);
+        fs::remove_file(input.join("sub/gone")).unwrap();
+        write_file(&input.join("sub/new"), b"new", time);
+        let compared = compare(&mut archive, &ExpandOptions::default());
+        assert_eq!(exit_code(&compared), 1);
+        let report = compared.unwrap();
+        assert_eq!(report.target, DiffTarget::Directory);
+        assert_eq!(kinds(&report), [
+            ("content", DiffKind::Changed, vec![Difference::Content]),
+            ("mtime", DiffKind::Changed, vec![Difference::ModifiedTime]),
+            ("size", DiffKind::Changed, vec![Difference::Size]),
+            ("sub/gone", DiffKind::OnlyInArchive, vec![]),
+            ("sub/new", DiffKind::OnlyInOther, vec![])
+        ]);
+        assert_eq!(report.to_string(), "~ content (content differs)\n~ mtime (modification time differs)\n~ size (size differs)\n- sub/gone (only in the archive)\n+ sub/new (only on disk)");
+
+        // files left out by the options are not compared, while entries of missing files are still reported
+        let options = ExpandOptions { exclude: vec!["size".to_string(), "sub/new".to_string()], ..Default::default() };
+        let report = compare(&mut archive, &options).unwrap();
+        assert_eq!(kinds(&report).iter().map(|(path, _, _)| *path).collect::<Vec<&str>>(), ["content", "mtime", "sub/gone"]);
+    }
+
+    #[test]
+    fn archive_differences_are_found_by_kind() {
+        let dir = test_dir("diff-archive");
+        let time = 1_600_000_000;
+        let mut archive = archive_of(&dir.join("first"), &[
+            ("same", noise(3000, 1), time),
+            ("size", noise(3000, 2), time),
+            ("both", noise(3000, 3), time),
+            ("only-first", noise(10, 4), time)
+        ]);
+        let mut other = archive_of(&dir.join("second"), &[
+            ("same", noise(3000, 1), time),
+            ("size", noise(2000, 2), time),
+            ("both", noise(3000, 30), time + 1),
+            ("only-second", noise(10, 5), time)
+        ]);
+        let report = archive.diff_archive(&mut other).unwrap();
+        assert_eq!(report.target, DiffTarget::Archive);
+        assert_eq!(kinds(&report), [
+            ("both", DiffKind::Changed, vec![Difference::ModifiedTime, Difference::Content]),
+            ("only-first", DiffKind::OnlyInArchive, vec![]),
+            ("only-second", DiffKind::OnlyInOther, vec![]),
+            ("size", DiffKind::Changed, vec![Difference::Size])
+        ]);
+        assert_eq!(report.to_string().lines().nth(2), Some("+ only-second (only in the other archive)"));
+        assert_eq!(report.to_string().lines().next(), Some("~ both (modification time, content differ)"));
+        assert_eq!(exit_code(&archive.diff_archive(&mut FctArchive::open(&dir.join("first.fct")).unwrap())), 0);
+    }
+
+    #[test]
+    fn reports_serialize_with_snake_case_names() {
+        let report = DiffReport {
+            target: DiffTarget::Directory,
+            entries: vec![
+                DiffEntry { path: PathBuf::from("a/b"), kind: DiffKind::Changed, differences: vec![Difference::Size, Difference::ModifiedTime] },
+                DiffEntry { path: PathBuf::from("c"), kind: DiffKind::OnlyInArchive, differences: vec![] },
+                DiffEntry { path: PathBuf::from("d"), kind: DiffKind::OnlyInOther, differences: vec![] }
+            ]
+        };
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(json, serde_json::json!({
+            "target": "directory",
+            "entries": [
+                { "path": "a/b", "kind": "changed", "differences": ["size", "modified_time"] },
+                { "path": "c", "kind": "only_in_archive", "differences": [] },
+                { "path": "d", "kind": "only_in_other", "differences": [] }
+            ]
+        }));
+        assert_eq!(exit_code(&Ok(report)), 1);
+        assert_eq!(exit_code(&Ok(DiffReport { target: DiffTarget::Archive, entries: Vec::new() })), 0);
+        assert_eq!(exit_code(&Err("Could not read file")), 2);
+    }
+}
@@ -0,0 +1,272 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use crate::fct_archive::{ArchiveOptions, FctArchive};
+use crate::file_parser::{self, FileParser};
+use crate::{fs_operations, view};
+use crate::error::*;
+
+// default chunk size of packed archives, entries get their own fitting chunk size anyway
+const EMBED_CHUNK_SIZE: u16 = 4096;
+// features whose entries are not stored as one run of bytes after their header
+const SCATTERED_FEATURES: u16 = file_parser::FEATURE_DEDUP | file_parser::FEATURE_SPARSE | file_parser::FEATURE_TAIL_PACK | file_parser::FEATURE_MULTI_VOLUME;
+
+/// Pack all files below a directory into an archive with the given name in the build script's OUT_DIR and return its path.
+/// Cargo is told to run the build script again when anything in the directory changes.
+pub fn pack_directory<P: AsRef<Path>>(directory: P, archive_name: &str) -> Result<PathBuf, &'static str> {
+    let out_dir = match std::env::var_os("OUT_DIR") {
+        Some(out_dir) => PathBuf::from(out_dir),
+        None => return Err("OUT_DIR is not set, packing is meant to run in a build script")
+    };
+    let archive_path = out_dir.join(archive_name);
+    pack_directory_to(directory.as_ref(), &archive_path)?;
+    println!("cargo:rerun-if-changed={}", directory.as_ref().display());
+    Ok(archive_path)
+}
+
+/// Pack all files below a directory into an archive at the given path, storing their paths relative to the directory.
+/// Every entry gets the chunk size that pads it the least, and the same files always give the same archive.
+pub fn pack_directory_to(directory: &Path, archive_path: &Path) -> Result<(), &'static str> {
+    let directory = unwrap_or_return_error!(std::fs::canonicalize(directory), "Could not find directory to pack");
+    let options = ArchiveOptions { entry_chunk_size: true, ..Default::default() };
+    let mut archive = FctArchive::create_with_options(archive_path, EMBED_CHUNK_SIZE, &options)?;
+    // the files are sorted by name, and no modification times are stored
+    for file_path in fs_operations::expand_directory_with_options(&directory, &fs_operations::ExpandOptions::default())? {
+        archive.add_file_relative_to(&file_path, &directory)?;
+    }
+    unwrap_or_return_error!(archive.archive_file.flush(), "Could not write to archive");
+    Ok(())
+}
+
+/// Read-only archive in static memory, usually included with include_bytes!, that never allocates.
+/// Entry data is handed out as slices of the archive, so only archives storing every entry in one piece are supported,
+/// which are the ones without deduplication, sparse files, tail packing and volumes.
+#[derive(Debug, Clone, Copy)]
+pub struct StaticArchive {
+    data: &'static [u8],
+    chunk_size: u16,
+    features: u16,
+    data_start: usize
+}
+
+/// An entry of a static archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaticEntry {
+    /// The path as stored in the archive
+    pub path: &'static str,
+    /// The contents without the padding of the last chunk
+    pub data: &'static [u8],
+    /// Modification time in seconds since the Unix epoch, 0 unless the archive records it
+    pub modified: u64
+}
+
+impl StaticArchive {
+    /// Read the archive header and check that all entries lie within the data
+    pub fn new(data: &'static [u8]) -> Result<Self, &'static str> {
+        let (chunk_size, features, data_start) = view::parse_archive_header(data)?;
+        if features & SCATTERED_FEATURES != 0 {
+            return Err("Archive does not store its entries in one piece");
+        }
+        let archive = StaticArchive { data, chunk_size, features, data_start };
+        // entries are parsed again on every lookup, so they only need to be checked once
+        let mut offset = data_start;
+        while offset < data.len() {
+            let (_, next_offset) = archive.parse_entry(offset)?;
+            offset = next_offset;
+        }
+        Ok(archive)
+    }
+
+    /// Iterate over the entries in the order they are stored in
+    pub fn entries(&self) -> StaticEntries {
+        StaticEntries { archive: *self, offset: self.data_start }
+    }
+
+    /// The contents of the entry with the given stored path
+    pub fn get(&self, path: &str) -> Option<&'static [u8]> {
+        self.entries().find(|entry| entry.path == path).map(|entry| entry.data)
+    }
+
+    /// Number of entries in the archive
+    pub fn len(&self) -> usize {
+        self.entries().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data_start == self.data.len()
+    }
+
+    // parse the entry at the given offset and return it with the offset of the next one
+    fn parse_entry(&self, offset: usize) -> Result<(StaticEntry, usize), &'static str> {
+        let data = self.data;
+        let field = |start: usize, length: usize| -> Result<&'static [u8], &'static str> {
+            match start.checked_add(length) {
+                Some(end) if end <= data.len() => Ok(&data[start..end]),
+                _ => Err("File header is incomplete")
+            }
+        };
+        let properties = field(offset, 8)?;
+        // only the sizes are needed to find the data, which allocates nothing as the path and the lists stay empty
+        let mut parser = FileParser {
+            features: self.features,
+            chunk_size: self.chunk_size,
+            chunk_count: u32::from_le_bytes(properties[..4].try_into().unwrap()),
+            last_chunk_size: u16::from_le_bytes(properties[4..6].try_into().unwrap()),
+            ..Default::default()
+        };
+        let path_len = u16::from_le_bytes(properties[6..8].try_into().unwrap()) as usize;
+        let path = match std::str::from_utf8(field(offset + 8, path_len)?) {
+            Ok(path) => path,
+            Err(_) => return Err("File path is not valid UTF-8")
+        };
+        let mut position = offset + 8 + path_len;
+        if self.features & file_parser::FEATURE_ENTRY_CHUNK_SIZE != 0 {
+            let chunk_size = field(position, 2)?;
+            parser.chunk_size = u16::from_le_bytes(chunk_size.try_into().unwrap());
+            position += 2;
+        }
+        if self.features & file_parser::FEATURE_MODIFIED_TIME != 0 {
+            let modified = field(position, 8)?;
+            parser.modified = u64::from_le_bytes(modified.try_into().unwrap());
+            position += 8;
+        }
+        // the stored data is padded to whole chunks, of which only the entry's size is its contents,
+        // counted in u64 as a damaged header can have a chunk count that overflows with the last chunk
+        let chunk_count = parser.chunk_count as u64 + if parser.last_chunk_size > 0 {1} else {0};
+        let stored_size = match usize::try_from(chunk_count * parser.chunk_size as u64) {
+            Ok(size) => size,
+            Err(_) => return Err("Entry data is incomplete")
+        };
+        let stored = match field(position, stored_size) {
+            Ok(stored) => stored,
+            Err(_) => return Err("Entry data is incomplete")
+        };
+        // a last chunk bigger than the chunks would reach past the padding
+        let contents = match stored.get(..parser.logical_size() as usize) {
+            Some(contents) => contents,
+            None => return Err("Final Chunk is too big")
+        };
+        let entry = StaticEntry { path, data: contents, modified: parser.modified };
+        Ok((entry, position + stored_size))
+    }
+}
+
+/// Iterator over the entries of a static archive
+#[derive(Debug, Clone)]
+pub struct StaticEntries {
+    archive: StaticArchive,
+    offset: usize
+}
+
+impl Iterator for StaticEntries {
+    type Item = StaticEntry;
+
+    fn next(&mut self) -> Option<StaticEntry> {
+        if self.offset >= self.archive.data.len() {
+            return None;
+        }
+        match self.archive.parse_entry(self.offset) {
+            Ok((entry, next_offset)) => {
+                self.offset = next_offset;
+                Some(entry)
+            },
+            Err(_) => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fct_archive::tests::{create_archive, noise, test_dir};
+    use crate::storage;
+
+    // static archives are meant for include_bytes!, which tests get by leaking the file
+    fn leak(path: &Path) -> &'static [u8] {
+        Box::leak(std::fs::read(path).unwrap().into_boxed_slice())
+    }
+
+    #[test]
+    fn packed_directories_read_back_as_static_archives() {
+        let dir = test_dir("embed-pack");
+        let input = dir.join("input");
+        let files = vec![
+            ("a.txt", noise(10, 1)),
+            ("assets/logo.bin", noise(70000, 2)),
+            ("assets/nested/empty", Vec::new()),
+            ("b", noise(4096, 3))
+        ];
+        for (name, contents) in &files {
+            std::fs::create_dir_all(input.join(name).parent().unwrap()).unwrap();
+            std::fs::write(input.join(name), contents).unwrap();
+        }
+        pack_directory_to(&input, &dir.join("packed.fct")).unwrap();
+        pack_directory_to(&input, &dir.join("again.fct")).unwrap();
+        let data = leak(&dir.join("packed.fct"));
+        assert_eq!(data, std::fs::read(dir.join("again.fct")).unwrap());
+
+        let archive = StaticArchive::new(data).unwrap();
+        assert_eq!(archive.len(), files.len());
+        assert!(!archive.is_empty());
+        for (entry, (name, contents)) in archive.entries().zip(&files) {
+            assert_eq!(entry.path, *name);
+            assert_eq!(entry.data, &contents[..]);
+            assert_eq!(entry.modified, 0);
+        }
+        assert_eq!(archive.get("assets/logo.bin"), Some(&files[1].1[..]));
+        assert_eq!(archive.get("missing"), None);
+        // every entry gets its own chunk size, so the small files are not padded to the default one
+        assert!(data.len() < 70000 + 4096 + 2 * EMBED_CHUNK_SIZE as usize);
+    }
+
+    #[test]
+    fn archives_with_scattered_entries_are_rejected() {
+        let files = vec![("a", noise(5000, 1)), ("b", noise(5000, 1))];
+        let options = [
+            ArchiveOptions { dedup: true, ..Default::default() },
+            ArchiveOptions { sparse: true, ..Default::default() },
+            ArchiveOptions { tail_pack: true, ..Default::default() },
+            ArchiveOptions { volume_size: Some(1 << 20), ..Default::default() }
+        ];
+        for (index, options) in options.iter().enumerate() {
+            let dir = test_dir(&format!("embed-rejected-{}", index));
+            create_archive(&dir, 1024, options, &files);
+            let first_volume = storage::archive_files(&dir.join("test.fct"))[0].clone();
+            assert_eq!(StaticArchive::new(leak(&first_volume)).unwrap_err(), "Archive does not store its entries in one piece");
+        }
+        // the features that keep entries in one piece are fine
+        let dir = test_dir("embed-accepted");
+        create_archive(&dir, 1024, &ArchiveOptions { entry_chunk_size: true, modified_time: true, ..Default::default() }, &files);
+        let archive = StaticArchive::new(leak(&dir.join("test.fct"))).unwrap();
+        assert_eq!(archive.get("b"), Some(&files[1].1[..]));
+        assert!(archive.entries().all(|entry| entry.modified > 0));
+    }
+
+    #[test]
+    fn truncated_and_damaged_archives_are_errors() {
+        let dir = test_dir("embed-truncated");
+        let files = vec![("a", noise(3000, 1)), ("b", noise(100, 2))];
+        create_archive(&dir, 1024, &ArchiveOptions { entry_chunk_size: true, modified_time: true, ..Default::default() }, &files);
+        let data = leak(&dir.join("test.fct"));
+        // only cuts right after the archive header or an entry leave a valid archive
+        let archive = StaticArchive::new(data).unwrap();
+        let mut boundaries = vec![archive.data_start];
+        while *boundaries.last().unwrap() < data.len() {
+            boundaries.push(archive.parse_entry(*boundaries.last().unwrap()).unwrap().1);
+        }
+        for length in 0..data.len() {
+            match StaticArchive::new(&data[..length]) {
+                Ok(archive) => assert_eq!(boundaries.iter().position(|end| *end == length), Some(archive.len())),
+                Err(_) => assert!(!boundaries.contains(&length), "{} bytes are a valid archive", length)
+            }
+        }
+
+        // a chunk count that overflows with the last chunk and a last chunk bigger than the chunks
+        let mut damaged = data.to_vec();
+        damaged[7..11].copy_from_slice(&u32::MAX.to_le_bytes());
+        damaged[11..13].copy_from_slice(&1u16.to_le_bytes());
+        assert_eq!(StaticArchive::new(Box::leak(damaged.into_boxed_slice())).unwrap_err(), "Entry data is incomplete");
+        let mut damaged = data.to_vec();
+        damaged[11..13].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(StaticArchive::new(Box::leak(damaged.into_boxed_slice())).is_err());
+    }
+}
@@ -1,50 +1,470 @@
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Write, Seek, SeekFrom, BufWriter, BufReader};
+use std::io::{Read, Write, Seek, SeekFrom, BufWriter, BufReader, Cursor};
+use std::ops::Range;
+use std::sync::{mpsc, Mutex};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use bufreaderwriter::BufReaderWriter;
-use std::path::PathBuf;
-use crate::file_parser::FileParser;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use crate::file_parser::{self, ArchiveLayout, Extent, ExtentSource, FileParser};
+use crate::fs_operations::{self, ExpandOptions, PathRewrite};
+use crate::diff::{DiffEntry, DiffKind, DiffReport, DiffTarget, Difference};
+use crate::stats::{ArchiveStats, EntryStats, TailBlockStats};
+use crate::storage::{self, ArchiveStorage};
+use crate::shared::SharedArchive;
+use crate::view;
 use crate::error::*;
 
 //const DEFAULT_CHUNK_SIZE: u16 = 256;
-const MAX_CHUNK_SIZE: u16 = 65535;
-const ARCHIVE_HEADER_SIZE: usize = 5;
-const ARCHIVE_HEADER_MAGIC: &str = "FCT";
+pub(crate) const MAX_CHUNK_SIZE: u16 = 65535;
+// smaller chunks make reading and writing slow because of the overhead per chunk
+const MIN_AUTO_CHUNK_SIZE: u16 = 512;
+// files up to this size are read into memory ahead of being written when adding files on several threads
+const READ_AHEAD_FILE_SIZE: u64 = 8 * 1024 * 1024;
+pub(crate) const ARCHIVE_HEADER_SIZE: usize = 5;
+pub(crate) const ARCHIVE_HEADER_MAGIC: &str = "FCT";
+// extended archives carry 2 more bytes of feature flags after the chunk size
+pub(crate) const EXTENDED_ARCHIVE_HEADER_SIZE: usize = 7;
+pub(crate) const EXTENDED_ARCHIVE_HEADER_MAGIC: &str = "FCX";
+// split archives carry the maximum volume size after the feature flags
+pub(crate) const VOLUME_SIZE_FIELD_SIZE: usize = 8;
+
+/// Format options of a new archive. Archives using any of them are written with the extended header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ArchiveOptions {
+    /// Store identical chunks only once, with entries referencing chunks by index
+    pub dedup: bool,
+    /// Record chunks consisting of zeros only as holes instead of storing them
+    pub sparse: bool,
+    /// Pack the partial last chunks of entries together instead of padding each of them to a full chunk
+    pub tail_pack: bool,
+    /// Let every entry use its own chunk size, chosen from the file's size unless given when adding it
+    pub entry_chunk_size: bool,
+    /// Split the archive into volumes of at most this many bytes
+    pub volume_size: Option<u64>,
+    /// Record the modification time of every file
+    pub modified_time: bool
+}
+
+impl ArchiveOptions {
+    pub(crate) fn to_features(self) -> u16 {
+        let mut features = 0;
+        if self.dedup {
+            features |= file_parser::FEATURE_DEDUP;
+        }
+        if self.sparse {
+            features |= file_parser::FEATURE_SPARSE;
+        }
+        if self.tail_pack {
+            features |= file_parser::FEATURE_TAIL_PACK;
+        }
+        if self.entry_chunk_size {
+            features |= file_parser::FEATURE_ENTRY_CHUNK_SIZE;
+        }
+        if self.volume_size.is_some() {
+            features |= file_parser::FEATURE_MULTI_VOLUME;
+        }
+        if self.modified_time {
+            features |= file_parser::FEATURE_MODIFIED_TIME;
+        }
+        features
+    }
+
+    fn from_features(features: u16, volume_size: u64) -> Self {
+        ArchiveOptions {
+            dedup: features & file_parser::FEATURE_DEDUP != 0,
+            sparse: features & file_parser::FEATURE_SPARSE != 0,
+            tail_pack: features & file_parser::FEATURE_TAIL_PACK != 0,
+            entry_chunk_size: features & file_parser::FEATURE_ENTRY_CHUNK_SIZE != 0,
+            volume_size: match features & file_parser::FEATURE_MULTI_VOLUME {
+                0 => None,
+                _ => Some(volume_size)
+            },
+            modified_time: features & file_parser::FEATURE_MODIFIED_TIME != 0
+        }
+    }
+}
+
+/// Chunk size chosen for a set of files by FctArchive::recommend_chunk_size
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkSizeRecommendation {
+    pub chunk_size: u16,
+    /// Size of an archive holding the files with this chunk size, assuming no chunk is deduplicated or a hole
+    pub projected_size: u64,
+    /// Files that could not be read, which are left out of the projection
+    pub skipped_files: Vec<PathBuf>
+}
+
+/// How much space deduplication saves in an archive
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DedupStats {
+    /// Chunks referenced by all entries together
+    pub referenced_chunks: u64,
+    /// Chunks actually stored in the archive
+    pub stored_chunks: u64,
+    /// Bytes that would have been stored additionally without deduplication
+    pub saved_bytes: u64
+}
+
+// maps the hash of every stored chunk to its index in the chunk store
+pub(crate) struct DedupIndex {
+    chunks: HashMap<[u8; 32], u32>,
+    next_index: u32
+}
+
+impl DedupIndex {
+    pub(crate) fn new() -> Self {
+        DedupIndex { chunks: HashMap::new(), next_index: 0 }
+    }
+
+    // record the whole chunks of a run read from the chunk store, chunks stored twice keep the first index
+    pub(crate) fn push(&mut self, chunks: &[u8], chunk_size: usize) {
+        for chunk in chunks.chunks(chunk_size) {
+            let hash: [u8; 32] = Sha256::digest(chunk).into();
+            self.chunks.entry(hash).or_insert(self.next_index);
+            self.next_index += 1;
+        }
+    }
+
+    // the runs of the chunk store to read to fill the index, as byte ranges of the archive and the size of their chunks.
+    // The chunks are as big as the chunks of the entry storing them, and a run holds at most a copy buffer of them.
+    pub(crate) fn stored_runs(headers: &[FileParser]) -> Vec<(Range<u64>, usize)> {
+        let mut runs = Vec::new();
+        for header in headers {
+            let chunk_size = header.chunk_size as u64;
+            let run_chunks = std::cmp::max(storage::COPY_BUFFER_SIZE as u64 / std::cmp::max(chunk_size, 1), 1);
+            let mut first_chunk = 0;
+            while first_chunk < header.stored_chunk_count as u64 {
+                let chunks = std::cmp::min(run_chunks, header.stored_chunk_count as u64 - first_chunk);
+                let start = header.data_offset + first_chunk * chunk_size;
+                runs.push((start..start + chunks * chunk_size, chunk_size as usize));
+                first_chunk += chunks;
+            }
+        }
+        runs
+    }
+
+    // index of the stored chunk with the same contents, and whether the chunk is new and has to be stored next
+    pub(crate) fn insert(&mut self, chunk: &[u8]) -> (u32, bool) {
+        let hash: [u8; 32] = Sha256::digest(chunk).into();
+        if let Some(stored_index) = self.chunks.get(&hash) {
+            return (*stored_index, false);
+        }
+        let stored_index = self.next_index;
+        self.chunks.insert(hash, stored_index);
+        self.next_index += 1;
+        (stored_index, true)
+    }
+}
+
+// decide how the whole chunks of a batch, which starts at the given chunk of the entry, are stored and return the runs
+// of consecutive chunks to write as byte ranges of the batch. Chunks of zeros are left out as holes of sparse archives,
+// and with the index of a deduplicated archive, chunks that are already stored are referenced. Nothing is read or
+// written here, so the archive and the async archive lay out entries the same way.
+pub(crate) fn place_chunks(header: &mut FileParser, mut dedup_index: Option<&mut DedupIndex>, first_chunk: u32, batch: &[u8]) -> Vec<Range<usize>> {
+    let sparse = header.features & file_parser::FEATURE_SPARSE != 0;
+    let chunk_size = header.chunk_size as usize;
+    let mut runs: Vec<Range<usize>> = Vec::new();
+    for (batch_index, chunk) in batch.chunks(chunk_size).enumerate() {
+        let chunk_index = first_chunk + batch_index as u32;
+        if sparse && chunk.iter().all(|byte| *byte == 0) {
+            header.set_hole(chunk_index);
+            if dedup_index.is_some() {
+                header.chunk_refs[chunk_index as usize] = file_parser::ZERO_CHUNK;
+            }
+            continue;
+        }
+        if let Some(index) = dedup_index.as_deref_mut() {
+            let (stored_index, is_new) = index.insert(chunk);
+            header.chunk_refs[chunk_index as usize] = stored_index;
+            if !is_new {
+                continue;
+            }
+            header.stored_chunk_count += 1;
+        }
+        let start = batch_index * chunk_size;
+        match runs.last_mut() {
+            Some(run) if run.end == start => run.end += chunk.len(),
+            _ => runs.push(start..start + chunk.len())
+        }
+    }
+    runs
+}
+
+// give the packed tail of an entry its place in the last tail block of the archive and return whether a new tail block
+// has to be written in front of the entry first, because the tail does not fit into the last one. Its data offset is
+// added to the layout once it is written.
+pub(crate) fn place_tail(header: &mut FileParser, layout: &ArchiveLayout, tail_block_used: &mut u16, chunk_size: u16) -> bool {
+    let new_block = layout.tail_blocks.is_empty() || *tail_block_used as u32 + header.last_chunk_size as u32 > chunk_size as u32;
+    if new_block {
+        *tail_block_used = 0;
+    }
+    header.tail_block = layout.tail_blocks.len() as u32 - if new_block {0} else {1};
+    header.tail_offset = *tail_block_used;
+    *tail_block_used += header.last_chunk_size;
+    new_block
+}
+
+// an empty tail block with its header, and the offset of its data within
+pub(crate) fn tail_block_record(features: u16, chunk_size: u16) -> Result<(Vec<u8>, u64), &'static str> {
+    let tail_block = FileParser::tail_block(features, chunk_size);
+    let mut record = tail_block.generate_header()?;
+    record.resize(record.len() + chunk_size as usize, 0);
+    Ok((record, tail_block.get_header_size() as u64))
+}
+
+/// What to do when an extracted entry's output file already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverwritePolicy {
+    /// Keep the existing file and skip the entry
+    Skip,
+    /// Truncate the existing file and replace its contents
+    #[default]
+    Overwrite,
+    /// Overwrite only if the archive copy is newer than the existing file. Entries of archives without modification
+    /// times are never newer, so existing files are kept.
+    OverwriteIfNewer,
+    /// Extract next to the existing file under a free name with a numeric suffix
+    Rename,
+    /// Report the entry as failed
+    Fail
+}
+
+/// The action taken for an entry during extraction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtractAction {
+    Extracted,
+    Overwritten,
+    Skipped,
+    Renamed(PathBuf),
+    Failed(&'static str)
+}
+
+/// Describes what happened to a single entry during extraction
+#[derive(Debug, Clone)]
+pub struct ExtractReport {
+    pub path: PathBuf,
+    pub action: ExtractAction
+}
+
+/// Options for extracting entries from an archive
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptions {
+    pub overwrite: OverwritePolicy,
+    /// Rules applied in order to each stored path before it is joined onto the output folder
+    pub path_rewrites: Vec<PathRewrite>
+}
+
+/// What to do when an entry merged into an archive has the name of an entry already in it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MergeConflictPolicy {
+    /// Keep the existing entry and leave out the new one
+    Skip,
+    /// Remove the existing entry and add the new one
+    Replace,
+    /// Add the new entry under a free name with a numeric suffix
+    Rename,
+    /// Add the new entry next to the existing one under the same name
+    KeepBoth,
+    /// Abort the merge before changing the archive
+    #[default]
+    Fail
+}
+
+/// The action taken for an entry of a source archive during a merge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeAction {
+    Added,
+    Replaced,
+    Skipped,
+    Renamed(PathBuf)
+}
+
+/// Describes what happened to a single entry of a source archive during a merge
+#[derive(Debug, Clone)]
+pub struct MergeReport {
+    pub path: PathBuf,
+    pub action: MergeAction
+}
+
+/// What synchronizing an archive with a directory changed, by stored path
+#[derive(Debug, Clone, Default)]
+pub struct SyncReport {
+    pub added: Vec<PathBuf>,
+    pub replaced: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    /// Files that could not be opened, whose entries are left as they are
+    pub failed: Vec<PathBuf>,
+    /// Number of entries that matched their files
+    pub unchanged: usize
+}
 
 pub struct FctArchive {
     pub chunk_size: u16,
-    pub archive_file: BufReaderWriter<File>,
+    pub archive_file: BufReaderWriter<ArchiveStorage>,
     pub archive_path: PathBuf, 
     headers: Vec<FileParser>,
-    headers_stale: bool
+    headers_stale: bool,
+    features: u16,
+    data_start: u64,
+    // maximum size of a volume, 0 if the archive is not split
+    volume_size: u64,
+    pub(crate) layout: ArchiveLayout,
+    dedup_index: Option<DedupIndex>,
+    // bytes of the last tail block taken by entries, new tails are appended to it while they fit
+    tail_block_used: u16
 }
 
+// a file opened for adding, with the header of its entry
+pub(crate) struct PreparedFile {
+    pub(crate) file: File,
+    pub(crate) parser: FileParser,
+    pub(crate) data_regions: Option<Vec<(u64, u64)>>
+}
+
+// a prepared file and, for small files, its contents read ahead by a worker thread
+type PreparedResult = Result<(PreparedFile, Option<Vec<u8>>), &'static str>;
+
+/// Reads the contents of an archive entry
+pub struct EntryReader<'a> {
+    archive: &'a mut FctArchive,
+    extents: Vec<Extent>,
+    extent_index: usize,
+    extent_position: u64,
+    // whether the archive cursor is known to be at the current read position
+    positioned: bool
+}
+
+impl<'a> EntryReader<'a> {
+    /// Size of the entry's contents
+    pub fn len(&self) -> u64 {
+        self.extents.iter().map(|extent| extent.length).sum()
+    }
+
+    /// Whether the entry has no contents
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<'a> Read for EntryReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.extent_index < self.extents.len() && self.extent_position == self.extents[self.extent_index].length {
+            self.extent_index += 1;
+            self.extent_position = 0;
+            self.positioned = false;
+        }
+        if self.extent_index == self.extents.len() || buf.is_empty() {
+            return Ok(0);
+        }
+        let extent = self.extents[self.extent_index];
+        let wanted = std::cmp::min(buf.len() as u64, extent.length - self.extent_position) as usize;
+        let read = match extent.source {
+            ExtentSource::Archive(offset) => {
+                if !self.positioned {
+                    self.archive.archive_file.seek(SeekFrom::Start(offset + self.extent_position))?;
+                    self.positioned = true;
+                }
+                self.archive.archive_file.read(&mut buf[..wanted])?
+            },
+            ExtentSource::Zero => {
+                buf[..wanted].fill(0);
+                wanted
+            }
+        };
+        if read == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Entry data is truncated"));
+        }
+        self.extent_position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<'a> Seek for EntryReader<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let current: u64 = self.extents[..self.extent_index].iter().map(|extent| extent.length).sum::<u64>() + self.extent_position;
+        let target = match pos {
+            SeekFrom::Start(offset) => offset as i128,
+            SeekFrom::Current(offset) => current as i128 + offset as i128,
+            SeekFrom::End(offset) => self.len() as i128 + offset as i128
+        };
+        if target < 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seek before the start of the entry"));
+        }
+        // find the extent containing the target, positions past the end stay at the end
+        let mut remaining = target as u64;
+        self.extent_index = 0;
+        while self.extent_index < self.extents.len() && remaining >= self.extents[self.extent_index].length {
+            remaining -= self.extents[self.extent_index].length;
+            self.extent_index += 1;
+        }
+        self.extent_position = if self.extent_index < self.extents.len() { remaining } else { 0 };
+        self.positioned = false;
+        Ok(target as u64)
+    }
+}
+
+// contents of a file being added, which can be copied inside the kernel if they come from a file
+trait EntrySource: Read + Seek {
+    // the file the contents are read from and the offset of the reader in it
+    fn source_file(&mut self) -> Option<(&File, u64)> {
+        None
+    }
+}
+
+impl EntrySource for BufReader<File> {
+    fn source_file(&mut self) -> Option<(&File, u64)> {
+        let position = self.stream_position().ok()?;
+        Some((self.get_ref(), position))
+    }
+}
+
+impl EntrySource for Cursor<Vec<u8>> {}
+
+impl<'a> EntrySource for EntryReader<'a> {}
+
 #[allow(dead_code)]
 /// The main archive class
 impl FctArchive {
 
     // Create a new archive from the given path and the chunk size
     pub fn create_new(archive_path: &PathBuf, chunk_size: u16) -> Result<Self, &'static str>{
+        Self::create_with_options(archive_path, chunk_size, &ArchiveOptions::default())
+    }
+
+    /// Create a new archive using the given format options
+    pub fn create_with_options(archive_path: &Path, chunk_size: u16, options: &ArchiveOptions) -> Result<Self, &'static str>{
         if chunk_size > MAX_CHUNK_SIZE {
             return Err("Chunk size is too big");
         }
+        if chunk_size == 0 {
+            return Err("Chunk size must not be 0");
+        }
+        if options.volume_size == Some(0) {
+            return Err("Volume size must not be 0");
+        }
         let chunk_size = chunk_size;
-        match OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(archive_path) {
-            Ok(file) => {
-                let mut archive_file = BufReaderWriter::new_writer(file);
+        match ArchiveStorage::create(archive_path, options.volume_size.is_some()) {
+            Ok(storage) => {
+                let mut archive_file = BufReaderWriter::new_writer(storage);
                 //let mut archive_file = file;
-                archive_file.write(ARCHIVE_HEADER_MAGIC.as_bytes()).expect("Failed to write archive header");
-                archive_file.write(&chunk_size.to_le_bytes()).expect("Failed to write chunk size");
+                let features = options.to_features();
+                let archive_header = view::generate_archive_header(chunk_size, features, options.volume_size);
+                archive_file.write_all(&archive_header).expect("Failed to write archive header");
+                let data_start = archive_header.len();
                 Ok(FctArchive {
                     chunk_size: chunk_size,
                     archive_file: archive_file,
                     archive_path: archive_path.to_path_buf(),
                     headers: Vec::new(),
-                    headers_stale: false
+                    headers_stale: false,
+                    features,
+                    data_start: data_start as u64,
+                    volume_size: options.volume_size.unwrap_or(0),
+                    layout: ArchiveLayout { chunk_size, features, chunk_offsets: Vec::new(), tail_blocks: Vec::new() },
+                    dedup_index: None,
+                    tail_block_used: 0
                 })
             },
             Err(_) => {
@@ -55,29 +475,53 @@ impl FctArchive {
         }
     }
 
-    // Open an existing archive from the given path and get the chunk size from its metadata
+    // Open an existing archive from the given path and get the chunk size from its metadata.
+    // Split archives can be opened by their base path or the path of their first volume.
     pub fn open(archive_path: &PathBuf) -> Result<Self, &'static str>{
         let mut file_header_buffer = [0u8; ARCHIVE_HEADER_SIZE];
-        match OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(archive_path)
-        {
-            Ok(file) => {
-                let mut archive_file = BufReaderWriter::new_reader(file);
+        let archive_path = storage::archive_base_path(archive_path);
+        match ArchiveStorage::open(&archive_path) {
+            Ok(storage) => {
+                let mut archive_file = BufReaderWriter::new_reader(storage);
                 //let mut archive_file = file;
-                archive_file.read_exact(&mut file_header_buffer).expect("Could not read archive header.");
-                if &file_header_buffer[..3] != ARCHIVE_HEADER_MAGIC.as_bytes() {
+                unwrap_or_return_error!(archive_file.read_exact(&mut file_header_buffer), "Could not read archive header");
+                let (features, data_start) = if &file_header_buffer[..3] == ARCHIVE_HEADER_MAGIC.as_bytes() {
+                    (0, ARCHIVE_HEADER_SIZE)
+                }
+                else if &file_header_buffer[..3] == EXTENDED_ARCHIVE_HEADER_MAGIC.as_bytes() {
+                    let mut features_buffer = [0u8; 2];
+                    unwrap_or_return_error!(archive_file.read_exact(&mut features_buffer), "Invalid archive header");
+                    (u16::from_le_bytes(features_buffer), EXTENDED_ARCHIVE_HEADER_SIZE)
+                }
+                else {
                     println!("Invalid archive header");
                     return Err("Invalid archive header");
+                };
+                if features & !file_parser::KNOWN_FEATURES != 0 {
+                    println!("Archive uses unsupported features");
+                    return Err("Archive uses unsupported features");
                 }
+                let (volume_size, data_start) = match features & file_parser::FEATURE_MULTI_VOLUME {
+                    0 => (0, data_start),
+                    _ => {
+                        let mut volume_size_buffer = [0u8; VOLUME_SIZE_FIELD_SIZE];
+                        unwrap_or_return_error!(archive_file.read_exact(&mut volume_size_buffer), "Invalid archive header");
+                        (u64::from_le_bytes(volume_size_buffer), data_start + VOLUME_SIZE_FIELD_SIZE)
+                    }
+                };
                 let chunk_size = u16::from_le_bytes(file_header_buffer[3..].try_into().expect("Invalid chunk size read!"));
                 let mut archive = FctArchive {
                     chunk_size: chunk_size,
                     archive_file: archive_file,
                     archive_path: archive_path.to_path_buf(),
                     headers: Vec::new(),
-                    headers_stale: true
+                    headers_stale: true,
+                    features,
+                    data_start: data_start as u64,
+                    volume_size,
+                    layout: ArchiveLayout { chunk_size, features, chunk_offsets: Vec::new(), tail_blocks: Vec::new() },
+                    dedup_index: None,
+                    tail_block_used: 0
                 };
                 archive.get_headers();
 
@@ -90,19 +534,128 @@ impl FctArchive {
         }
     }
 
+    /// Choose the chunk size that makes an archive of the files with the given options the smallest, counting the
+    /// padding of the chunks, the packed tails and the entry headers. Chunks are kept at least 512 bytes big to keep
+    /// I/O efficient. Only the file sizes are read, so the projection assumes that no chunk is deduplicated or left
+    /// out as a hole. Files that cannot be read are listed in the recommendation instead of failing it.
+    pub fn recommend_chunk_size(file_paths: &[PathBuf], options: &ArchiveOptions) -> Result<ChunkSizeRecommendation, &'static str> {
+        let current_dir = unwrap_or_return_error!(std::env::current_dir(), "Could not get current directory");
+        let mut files: Vec<(FileParser, u64)> = Vec::new();
+        let mut skipped_files: Vec<PathBuf> = Vec::new();
+        for file_path in file_paths {
+            // only the sizes are needed, so the files are not opened
+            match (std::fs::metadata(file_path), fs_operations::format_path(&current_dir, file_path)) {
+                (Ok(metadata), Ok(stored_path)) if metadata.is_file() => {
+                    files.push((FileParser { file_path: stored_path, ..Default::default() }, metadata.len()));
+                },
+                _ => skipped_files.push(file_path.clone())
+            }
+        }
+        let mut recommendation = Self::recommend_chunk_size_for(&mut files, options.to_features())?;
+        recommendation.skipped_files = skipped_files;
+        Ok(recommendation)
+    }
+
+    /// Choose the chunk size for the entries of this archive like recommend_chunk_size, for use with rechunk
+    pub fn recommend_rechunk_size(&mut self) -> Result<ChunkSizeRecommendation, &'static str> {
+        if self.headers_stale {
+            self.get_headers();
+        }
+        let mut files: Vec<(FileParser, u64)> = self.headers.iter().map(|header| {
+            (FileParser { file_path: header.file_path.clone(), ..Default::default() }, header.logical_size())
+        }).collect();
+        Self::recommend_chunk_size_for(&mut files, self.features)
+    }
+
+    // find the chunk size for files given by a header holding their stored path and their size
+    pub(crate) fn recommend_chunk_size_for(files: &mut [(FileParser, u64)], features: u16) -> Result<ChunkSizeRecommendation, &'static str> {
+        if files.is_empty() {
+            return Err("No files to choose a chunk size for");
+        }
+
+        let mut candidates: Vec<u16> = (1..=MAX_CHUNK_SIZE / MIN_AUTO_CHUNK_SIZE).map(|n| n * MIN_AUTO_CHUNK_SIZE).collect();
+        candidates.push(MAX_CHUNK_SIZE);
+        let mut best: Option<ChunkSizeRecommendation> = None;
+        for chunk_size in candidates {
+            let projected_size = Self::projected_size(files, features, chunk_size)?;
+            // bigger chunks win ties, as they take fewer reads and writes
+            match best {
+                Some(ref recommendation) if recommendation.projected_size < projected_size => {},
+                _ => best = Some(ChunkSizeRecommendation { chunk_size, projected_size, skipped_files: Vec::new() })
+            }
+        }
+        Ok(best.unwrap())
+    }
+
+    // size of an archive holding the files, laid out the way add_file would write them
+    fn projected_size(files: &mut [(FileParser, u64)], features: u16, chunk_size: u16) -> Result<u64, &'static str> {
+        let mut total = match features {
+            0 => ARCHIVE_HEADER_SIZE,
+            _ if features & file_parser::FEATURE_MULTI_VOLUME != 0 => EXTENDED_ARCHIVE_HEADER_SIZE + VOLUME_SIZE_FIELD_SIZE,
+            _ => EXTENDED_ARCHIVE_HEADER_SIZE
+        } as u64;
+        let tail_block_size = tail_block_record(features, chunk_size)?.0.len() as u64;
+        let mut tail_block_used: Option<u16> = None;
+        for (header, len) in files.iter_mut() {
+            header.chunk_size = match features & file_parser::FEATURE_ENTRY_CHUNK_SIZE {
+                0 => chunk_size,
+                _ => Self::entry_chunk_size(features, chunk_size, *len)
+            };
+            header.features = features;
+            header.set_logical_size(*len)?;
+            // like set_format, without making room for the chunk references and holes that are not needed here
+            header.tail_block = match header.last_chunk_size > chunk_size {
+                true => file_parser::NO_TAIL_BLOCK,
+                false => 0
+            };
+            total += header.get_header_size() as u64 + header.data_chunk_count() as u64 * header.chunk_size as u64;
+            if header.has_packed_tail() {
+                // a tail that does not fit into the last tail block starts a new one, like place_tail does it
+                match tail_block_used {
+                    Some(used) if used as u32 + header.last_chunk_size as u32 <= chunk_size as u32 => {
+                        tail_block_used = Some(used + header.last_chunk_size);
+                    },
+                    _ => {
+                        total += tail_block_size;
+                        tail_block_used = Some(header.last_chunk_size);
+                    }
+                }
+            }
+        }
+        Ok(total)
+    }
+
+    /// The format options the archive was created with
+    pub fn options(&self) -> ArchiveOptions {
+        ArchiveOptions::from_features(self.features, self.volume_size)
+    }
+
     // Seek to the start of the file entries
     fn seek_to_start(&mut self) {
-        self.archive_file.seek(SeekFrom::Start(ARCHIVE_HEADER_SIZE as u64))
+        self.archive_file.seek(SeekFrom::Start(self.data_start))
             .expect("Could not seek to start of archive");
     }
 
-    // Seek over file while reading the header
+    // Seek over file while reading the header, skipping tail blocks
     fn seek_file(&mut self) -> Option<FileParser> {
+        loop {
+            let parsed_file = self.seek_record()?;
+            if !parsed_file.is_tail_block() {
+                return Some(parsed_file);
+            }
+        }
+    }
 
-        let parsed_file = match FileParser::from_archive(&mut self.archive_file){
+    // Seek over the next entry or tail block while reading its header
+    fn seek_record(&mut self) -> Option<FileParser> {
+        let mut parsed_file = match FileParser::from_archive_with_features(&mut self.archive_file, self.features, self.chunk_size){
             Ok(file) => file,
             Err(_) => return None
         };
+        parsed_file.data_offset = match self.archive_file.stream_position() {
+            Ok(position) => position,
+            Err(_) => return None
+        };
         // TODO: fast seeking
         // seek chunks
         match self.seek_data(&parsed_file) {
@@ -116,112 +669,261 @@ impl FctArchive {
     }
 
     fn seek_data(&mut self, file_parser: &FileParser) -> Result<(), &'static str> {
-        // seek chunks
-        let byte_count: i64 = (if file_parser.last_chunk_size > 0 {1} else {0} + file_parser.chunk_count as i64) * self.chunk_size as i64;
-        if byte_count < 0 {
-            println!("Negative byte count, probably overflow, attempting slow seek");
-            for _ in 0..(file_parser.chunk_count + 1) {
-                match self.archive_file.seek(SeekFrom::Current(self.chunk_size as i64)){
-                    Ok(_) => {},
-                    Err(e) => {
-                        println!("Error seeking over file: {}", e);
-                        return Err("Error seeking over file");
-                    }
-                }
-            }
-            Ok(())
-        }
-        else {
-            match self.archive_file.seek(SeekFrom::Current(byte_count)){
-                Ok(_) => Ok(()),
-                Err(e) => {
-                    println!("Error seeking over file: {}", e);
-                    Err("Error seeking over file")
-                }
+        // seek over the stored data in steps that fit into a relative seek
+        let mut remaining = file_parser.stored_size();
+        while remaining > 0 {
+            let step = remaining.min(i64::MAX as u64);
+            if let Err(e) = self.archive_file.seek(SeekFrom::Current(step as i64)) {
+                println!("Error seeking over file: {}", e);
+                return Err("Error seeking over file");
             }
+            remaining -= step;
         }
+        Ok(())
     }
 
+    // seek to the header of the entry at the given index
     fn seek_to_entry(&mut self, entry_index: u32) -> Result<(), &'static str> {
-        let mut current_index = 0;
-        self.seek_to_start();
-        // limit by length of vector for safety
-        for index in 0..self.headers.len() {
-            if current_index == entry_index {
-                return Ok(());
-            }
-            for _ in 0..(self.headers[index].chunk_count + 1) {
-                unwrap_or_return_error!(self.archive_file.seek(SeekFrom::Current(self.chunk_size as i64)), "Error seeking over file");
-            }
-            current_index += 1;
+        if self.headers_stale {
+            self.get_headers();
         }
-        Err("Could not find entry")
+        let header = match self.headers.get(entry_index as usize) {
+            Some(header) => header,
+            None => return Err("Could not find entry")
+        };
+        let header_offset = header.data_offset - header.get_header_size() as u64;
+        unwrap_or_return_error!(self.archive_file.seek(SeekFrom::Start(header_offset)), "Error seeking to entry");
+        Ok(())
     }
 
-    // TODO: Implement with large buffers to avoid overhead
     // writes file data to the archive
-    fn write_file_to_archive<Reader: Read + Seek>(&mut self, file: &mut Reader, header: &FileParser) -> Result<(), &'static str>{
-        let mut file_buffer = Vec::with_capacity(self.chunk_size as usize);
-        for _ in 0..header.chunk_count {
-            file_buffer.clear();
-            unwrap_or_return_error!(
-                std::io::Read::by_ref(file).take(self.chunk_size as u64).read_to_end(&mut file_buffer), 
-                "Could not read file"
+    fn write_file_to_archive<Reader: EntrySource>(&mut self, file: &mut Reader, header: &FileParser) -> Result<(), &'static str>{
+        self.copy_into_archive(file, header.logical_size(), header.stored_size())
+    }
+
+    // copies the length of bytes from the reader to the archive's cursor and pads them with zeros to the stored length,
+    // which also fills in for a source that ends early. Files are copied inside the kernel where the file systems
+    // allow it, everything else in big runs whatever the chunk size is.
+    fn copy_into_archive<Reader: EntrySource>(&mut self, file: &mut Reader, length: u64, stored_length: u64) -> Result<(), &'static str> {
+        unwrap_or_return_error!(self.archive_file.flush(), "Could not write to archive");
+        let position = unwrap_or_return_error!(self.archive_file.stream_position(), "Could not get archive position");
+        let mut copied: u64 = 0;
+        if let (Some((source, source_offset)), ArchiveStorage::Single(archive)) = (file.source_file(), self.archive_file.get_ref()) {
+            copied = unwrap_or_return_error!(
+                fs_operations::copy_file_range(source, source_offset, archive, position, length),
+                "Could not write to archive"
             );
-            unwrap_or_return_error!(self.archive_file.write(&file_buffer),"Could not write to archive");
+            if copied > 0 {
+                unwrap_or_return_error!(file.seek(SeekFrom::Start(source_offset + copied)), "Could not seek in file");
+            }
         }
-        if header.last_chunk_size > 0 {
-            file_buffer.clear();
-            unwrap_or_return_error!(
-                std::io::Read::by_ref(file).take(self.chunk_size as u64).read_to_end(&mut file_buffer), 
-                "Could not read file"
-            );
-            file_buffer.resize(self.chunk_size as usize, 0);
-            unwrap_or_return_error!(self.archive_file.write(&file_buffer),"Could not write to archive");
+        unwrap_or_return_error!(self.archive_file.seek(SeekFrom::Start(position + copied)), "Could not seek in archive");
+        let mut buffer = Vec::new();
+        while copied < length {
+            buffer.clear();
+            let wanted = std::cmp::min(length - copied, storage::COPY_BUFFER_SIZE as u64);
+            let read = unwrap_or_return_error!(Read::by_ref(file).take(wanted).read_to_end(&mut buffer), "Could not read file");
+            if read == 0 {
+                break;
+            }
+            unwrap_or_return_error!(self.archive_file.write_all(&buffer), "Could not write to archive");
+            copied += read as u64;
+        }
+        if copied < stored_length {
+            buffer.clear();
+            buffer.resize(std::cmp::min(stored_length - copied, storage::COPY_BUFFER_SIZE as u64) as usize, 0);
+            while copied < stored_length {
+                let padding = std::cmp::min(stored_length - copied, buffer.len() as u64) as usize;
+                unwrap_or_return_error!(self.archive_file.write_all(&buffer[..padding]), "Could not write to archive");
+                copied += padding as u64;
+            }
         }
         Ok(())
     }
 
-    fn write_file_from_archive<Writer: Write + Seek>(&mut self, file: &mut Writer, header: &FileParser, fill: bool) -> Result<(), &'static str>{
-        let mut file_buffer = Vec::with_capacity(self.chunk_size as usize);
-        for _ in 0..header.chunk_count {
-            file_buffer.clear();
-            unwrap_or_return_error!(
-                std::io::Read::by_ref(&mut self.archive_file)
-                    .take(self.chunk_size as u64)
-                    .read_to_end(&mut file_buffer), 
-                "Could not read file"
-            );
+    // copies the data of an entry whose header has just been read to the cursor of the target archive, without the padding
+    // of the last chunk unless filled, and leaves the cursor behind the entry's data
+    fn write_file_from_archive(&mut self, target: &mut FctArchive, header: &FileParser, fill: bool) -> Result<(), &'static str>{
+        let length = if fill { header.stored_size() } else { header.logical_size() };
+        let position = unwrap_or_return_error!(self.archive_file.stream_position(), "Could not get archive position");
+        self.copy_data_to(position, length, target)?;
+        unwrap_or_return_error!(self.archive_file.seek(SeekFrom::Start(position + header.stored_size())), "Error seeking over file");
+        Ok(())
+    }
+
+    // copies bytes of the archive at the given position to the cursor of the target archive, which is left behind them
+    fn copy_data_to(&mut self, position: u64, length: u64, target: &mut FctArchive) -> Result<(), &'static str> {
+        unwrap_or_return_error!(self.archive_file.flush(), "Could not write to archive");
+        unwrap_or_return_error!(target.archive_file.flush(), "Could not write to archive");
+        let target_position = unwrap_or_return_error!(target.archive_file.stream_position(), "Could not get archive position");
+        let mut buffer = Vec::new();
+        // archives that are being written to are never embedded, and split ones have to be written through their volumes
+        if let ArchiveStorage::Single(target_file) = target.archive_file.get_mut() {
+            unwrap_or_return_error!(target_file.seek(SeekFrom::Start(target_position)), "Could not seek in archive");
             unwrap_or_return_error!(
-                file.write(&file_buffer), 
-                "Error extracting file: Could not write file"
+                self.archive_file.get_ref().copy_to(position, length, target_file, &mut buffer),
+                "Could not copy file data"
             );
         }
-        if header.last_chunk_size > 0 {
-            file_buffer.clear();
-            unwrap_or_return_error!(
-                std::io::Read::by_ref(&mut self.archive_file)
-                    .take(self.chunk_size as u64)
-                    .read_to_end(&mut file_buffer),
-                "Could not read file"
-            );
-            if fill {
-                file_buffer.resize(self.chunk_size as usize, 0);
+        else {
+            buffer.resize(std::cmp::min(length, storage::COPY_BUFFER_SIZE as u64) as usize, 0);
+            let mut copied: u64 = 0;
+            while copied < length {
+                let wanted = std::cmp::min(length - copied, buffer.len() as u64) as usize;
+                let read = unwrap_or_return_error!(self.archive_file.get_ref().read_at(&mut buffer[..wanted], position + copied), "Could not read file");
+                if read == 0 {
+                    return Err("Could not read file");
+                }
+                unwrap_or_return_error!(target.archive_file.write_all(&buffer[..read]), "Could not copy file data");
+                copied += read as u64;
             }
-            else {
-                file_buffer.resize(header.last_chunk_size as usize, 0);
+        }
+        unwrap_or_return_error!(target.archive_file.seek(SeekFrom::Start(target_position + length)), "Could not seek in archive");
+        Ok(())
+    }
+
+    // writes file data of an extended archive in batches of whole chunks, leaving out holes and, when deduplicating,
+    // chunks that are already stored. Afterwards the header written at header_offset is completed.
+    fn write_extended_file_to_archive<Reader: EntrySource>(&mut self, file: &mut Reader, header: &mut FileParser, header_offset: u64, data_regions: Option<&Vec<(u64, u64)>>) -> Result<(), &'static str>{
+        let dedup = self.features & file_parser::FEATURE_DEDUP != 0;
+        let sparse = self.features & file_parser::FEATURE_SPARSE != 0;
+        let chunk_size = header.chunk_size as usize;
+        let data_chunk_count = header.data_chunk_count();
+        let data_end = std::cmp::min(header.logical_size(), data_chunk_count as u64 * chunk_size as u64);
+        let mut file_position: u64 = 0;
+        let mut first_chunk = 0;
+        // chunks that are neither checked for holes and duplicates nor kept within volumes are stored as they are
+        if !dedup && !sparse && self.volume_size == 0 {
+            self.copy_into_archive(file, data_end, data_chunk_count as u64 * chunk_size as u64)?;
+            file_position = data_end;
+            first_chunk = data_chunk_count;
+        }
+        let batch_chunks = std::cmp::max(storage::COPY_BUFFER_SIZE / chunk_size, 1) as u32;
+        let mut batch: Vec<u8> = Vec::new();
+        let mut region_index = 0;
+        while first_chunk < data_chunk_count {
+            let chunks = std::cmp::min(batch_chunks, data_chunk_count - first_chunk);
+            let batch_start = first_chunk as u64 * chunk_size as u64;
+            batch.clear();
+            batch.resize(chunks as usize * chunk_size, 0);
+            // chunks lying completely in a hole of a sparse input are not read and stay zeros
+            let mut reads: Vec<Range<u64>> = Vec::new();
+            for chunk_start in (batch_start..batch_start + batch.len() as u64).step_by(chunk_size) {
+                let chunk_end = std::cmp::min(chunk_start + chunk_size as u64, data_end);
+                if let (true, Some(regions)) = (sparse, data_regions) {
+                    while region_index < regions.len() && regions[region_index].0 + regions[region_index].1 <= chunk_start {
+                        region_index += 1;
+                    }
+                    if region_index == regions.len() || regions[region_index].0 >= chunk_end {
+                        continue;
+                    }
+                }
+                match reads.last_mut() {
+                    Some(read) if read.end == chunk_start => read.end = chunk_end,
+                    _ => reads.push(chunk_start..chunk_end)
+                }
             }
-            match file.write(&file_buffer) {
-                Ok(_) => Ok(()),
-                Err(e) => {
-                    println!("Error extracting file: {}", e);
-                    Err("Error extracting file")
+            for read in reads {
+                if file_position != read.start {
+                    unwrap_or_return_error!(file.seek(SeekFrom::Start(read.start)), "Could not seek in file");
                 }
+                let range = (read.start - batch_start) as usize..(read.end - batch_start) as usize;
+                unwrap_or_return_error!(file.read_exact(&mut batch[range]), "Could not read file");
+                file_position = read.end;
             }
+            for run in place_chunks(header, self.dedup_index.as_mut(), first_chunk, &batch) {
+                self.write_chunks(&batch[run], chunk_size)?;
+            }
+            first_chunk += chunks;
         }
-        else {
-            Ok(())
+        if header.has_packed_tail() {
+            let tail_start = header.chunk_count as u64 * chunk_size as u64;
+            if file_position != tail_start {
+                unwrap_or_return_error!(file.seek(SeekFrom::Start(tail_start)), "Could not seek in file");
+            }
+            let mut tail = vec![0u8; header.last_chunk_size as usize];
+            unwrap_or_return_error!(file.read_exact(&mut tail), "Could not read file");
+            let tail_offset = self.layout.tail_blocks[header.tail_block as usize] + header.tail_offset as u64;
+            unwrap_or_return_error!(self.archive_file.seek(SeekFrom::Start(tail_offset)), "Could not seek to tail block");
+            unwrap_or_return_error!(self.archive_file.write_all(&tail), "Could not write to tail block");
+        }
+        unwrap_or_return_error!(self.archive_file.seek(SeekFrom::Start(header_offset)), "Could not seek to file header");
+        unwrap_or_return_error!(
+            self.archive_file.write_all(&header.generate_header()?),
+            "Could not write file header"
+        );
+        unwrap_or_return_error!(self.archive_file.seek(SeekFrom::End(0)), "Could not seek to end of archive");
+        Ok(())
+    }
+
+    // writes whole chunks to the archive's cursor with as few writes as the volumes allow, without splitting a chunk
+    // between two volumes
+    fn write_chunks(&mut self, chunks: &[u8], chunk_size: usize) -> Result<(), &'static str> {
+        let mut written = 0;
+        while written < chunks.len() {
+            self.reserve(chunk_size as u64)?;
+            let mut length = chunks.len() - written;
+            if self.volume_size > 0 {
+                let position = unwrap_or_return_error!(self.archive_file.stream_position(), "Could not get archive position");
+                let space = self.archive_file.get_ref().volume_space(position, self.volume_size);
+                length = std::cmp::min(length as u64, std::cmp::max(space / chunk_size as u64, 1) * chunk_size as u64) as usize;
+            }
+            unwrap_or_return_error!(self.archive_file.write_all(&chunks[written..written + length]), "Could not write to archive");
+            written += length;
+        }
+        Ok(())
+    }
+
+    // move on to a new volume if the given number of bytes written at the current position would not fit into the last one,
+    // so that headers and chunks never straddle two volumes
+    fn reserve(&mut self, length: u64) -> Result<(), &'static str> {
+        let position = unwrap_or_return_error!(self.archive_file.stream_position(), "Could not get archive position");
+        if !self.archive_file.get_ref().needs_new_volume(position, length, self.volume_size) {
+            return Ok(());
+        }
+        unwrap_or_return_error!(self.archive_file.flush(), "Could not write to archive");
+        unwrap_or_return_error!(self.archive_file.get_mut().start_volume(position), "Could not create a new volume");
+        unwrap_or_return_error!(self.archive_file.seek(SeekFrom::Start(position)), "Could not seek to the new volume");
+        Ok(())
+    }
+
+    // hash the chunks already stored in the archive, once before the first deduplicated write
+    fn load_dedup_index(&mut self) -> Result<(), &'static str> {
+        if self.dedup_index.is_some() {
+            return Ok(());
+        }
+        if self.headers_stale {
+            self.get_headers();
+        }
+        let mut dedup_index = DedupIndex::new();
+        let mut buffer = Vec::new();
+        for (run, chunk_size) in DedupIndex::stored_runs(&self.headers) {
+            buffer.resize((run.end - run.start) as usize, 0);
+            unwrap_or_return_error!(self.archive_file.seek(SeekFrom::Start(run.start)), "Could not seek to chunk");
+            unwrap_or_return_error!(self.archive_file.read_exact(&mut buffer), "Could not read chunk");
+            dedup_index.push(&buffer, chunk_size);
+        }
+        unwrap_or_return_error!(self.archive_file.seek(SeekFrom::End(0)), "Could not seek to end of archive");
+        self.dedup_index = Some(dedup_index);
+        Ok(())
+    }
+
+    /// Open a reader over the contents of the entry at the given index
+    pub fn entry_reader(&mut self, index: u32) -> Result<EntryReader<'_>, &'static str> {
+        if self.headers_stale {
+            self.get_headers();
         }
+        let extents = match self.headers.get(index as usize) {
+            Some(header) => header.extents(&self.layout)?,
+            None => return Err("Could not find entry")
+        };
+        Ok(EntryReader {
+            archive: self,
+            extents,
+            extent_index: 0,
+            extent_position: 0,
+            positioned: false
+        })
     }
 
     /// Get the file headers of the entries in the archive and refresh them if necessary
@@ -230,53 +932,151 @@ impl FctArchive {
             return &self.headers;
         }
         self.headers.clear();
+        self.layout.chunk_offsets.clear();
+        self.layout.tail_blocks.clear();
         self.seek_to_start();
         loop {
-            match self.seek_file() {
+            match self.seek_record() {
                 Some(file) => {
-                    self.headers.push(file);
+                    self.layout.add_record(&file);
+                    if !file.is_tail_block() {
+                        self.headers.push(file);
+                    }
                 },
                 None => {
                     break;
                 }
             }
         }
+        self.tail_block_used = self.layout.tail_block_used(&self.headers);
         self.headers_stale = false;
         return &self.headers;
     }        
 
-    /// Add a file to the archive and mark the file headers as stale
+    // archives embedded in executables are read only, as rewriting them would replace the executable with the archive
+    fn check_writable(&self) -> Result<(), &'static str> {
+        match self.archive_file.get_ref() {
+            ArchiveStorage::Embedded(_) => Err("Embedded archives are read only"),
+            _ => Ok(())
+        }
+    }
+
+    /// Add a file to the archive and mark the file headers as stale.
+    /// Archives with per-entry chunk sizes choose the chunk size from the file's size.
     pub fn add_file(&mut self, file_path: &PathBuf) -> Result<(), &'static str>{
-        self.archive_file.seek(SeekFrom::End(0)).expect("Could not seek to end of archive");
-        
-        let mut file = match File::open(file_path){
-            Ok(f) => BufReader::new(f),
+        self.add_file_with_chunk_size(file_path, None)
+    }
+
+    /// Add a file to the archive, cutting it into chunks of the given size if the archive has per-entry chunk sizes
+    pub fn add_file_with_chunk_size(&mut self, file_path: &PathBuf, chunk_size: Option<u16>) -> Result<(), &'static str>{
+        let current_dir = std::env::current_dir().unwrap();
+        self.add_file_from(file_path, &current_dir, chunk_size)
+    }
+
+    /// Add a file to the archive, storing its path relative to the given directory instead of the current one
+    pub fn add_file_relative_to(&mut self, file_path: &PathBuf, root_dir: &PathBuf) -> Result<(), &'static str>{
+        self.add_file_from(file_path, root_dir, None)
+    }
+
+    fn add_file_from(&mut self, file_path: &PathBuf, root_dir: &PathBuf, chunk_size: Option<u16>) -> Result<(), &'static str>{
+        self.check_writable()?;
+        let prepared = Self::prepare_file(file_path, root_dir, chunk_size, self.features, self.chunk_size)?;
+        println!("Adding file: {}", prepared.parser.file_path.display());
+        self.add_entry(&mut BufReader::new(prepared.file), prepared.parser, prepared.data_regions.as_ref())
+    }
+
+    // open a file to add and work out its header. This only depends on the archive's format and not on its contents,
+    // so that files can be prepared on other threads while entries are written.
+    pub(crate) fn prepare_file(file_path: &PathBuf, root_dir: &PathBuf, chunk_size: Option<u16>, features: u16, archive_chunk_size: u16) -> Result<PreparedFile, &'static str>{
+        let file = match File::open(file_path){
+            Ok(f) => f,
             Err(_) => {
                 return Err("Error adding file: Could not open file");
             }
         };
-        let current_dir = std::env::current_dir().unwrap();
+        // a directory opens like a file, but reading it fails only after its header is written
+        if file.metadata().map(|metadata| metadata.is_dir()).unwrap_or(false) {
+            return Err("Error adding file: Path is a directory");
+        }
+        // holes of sparse files are known without reading them
+        let data_regions = match features & file_parser::FEATURE_SPARSE {
+            0 => None,
+            _ => fs_operations::data_regions(&file)
+        };
+        let chunk_size = match (features & file_parser::FEATURE_ENTRY_CHUNK_SIZE, chunk_size) {
+            (0, _) => archive_chunk_size,
+            (_, Some(0)) => return Err("Error adding file: Chunk size must not be 0"),
+            (_, Some(chunk_size)) => chunk_size,
+            (_, None) => Self::entry_chunk_size(features, archive_chunk_size, file.metadata().map(|metadata| metadata.len()).unwrap_or(0))
+        };
         let parser = unwrap_or_return_error!(
             FileParser::from_file(
                 &file_path,
-                &current_dir,
-                self.chunk_size
+                root_dir,
+                chunk_size
             ),
             "Error adding file: Could not create file parser"
         );
-        println!("Adding file: {}", parser.file_path.display());
+        Ok(PreparedFile { file, parser, data_regions })
+    }
+
+    // choose a chunk size for contents of the given size in an archive with per-entry chunk sizes
+    pub(crate) fn entry_chunk_size(features: u16, archive_chunk_size: u16, len: u64) -> u16 {
+        // deduplication only finds identical chunks of the same size
+        if features & file_parser::FEATURE_DEDUP != 0 {
+            return archive_chunk_size;
+        }
+        file_parser::fitting_chunk_size(len, archive_chunk_size)
+    }
+
+    // write an entry whose header describes the contents of the reader to the end of the archive
+    fn add_entry<Reader: EntrySource>(&mut self, file: &mut Reader, mut parser: FileParser, data_regions: Option<&Vec<(u64, u64)>>) -> Result<(), &'static str>{
+        self.check_writable()?;
+        // chunk references and holes are only known once the data is written, so the header is written twice
+        parser.set_format(self.features, self.chunk_size);
+        if self.features & file_parser::FEATURE_DEDUP != 0 {
+            // loading the index may reread the headers, which has to happen before the new header is written
+            self.load_dedup_index()?;
+        }
+        unwrap_or_return_error!(
+            self.archive_file.seek(SeekFrom::End(0)),
+            "Could not seek to end of archive"
+        );
+        if parser.has_packed_tail() {
+            // the tail only takes its place once a new tail block it needs is written
+            let mut tail_block_used = self.tail_block_used;
+            if place_tail(&mut parser, &self.layout, &mut tail_block_used, self.chunk_size) {
+                let (record, data_start) = tail_block_record(self.features, self.chunk_size)?;
+                self.reserve(record.len() as u64)?;
+                let block_offset = unwrap_or_return_error!(self.archive_file.stream_position(), "Could not get archive position");
+                unwrap_or_return_error!(self.archive_file.write_all(&record), "Could not write tail block");
+                self.layout.tail_blocks.push(block_offset + data_start);
+            }
+            self.tail_block_used = tail_block_used;
+        }
+        self.reserve(parser.get_header_size() as u64)?;
+        let header_offset = unwrap_or_return_error!(self.archive_file.stream_position(), "Could not get archive position");
         unwrap_or_return_error!(
-            self.archive_file.write(&parser.generate_header().unwrap()),
+            self.archive_file.write(&parser.generate_header()?),
             "Could not write file header"
         );
         self.headers_stale = true;
-        self.write_file_to_archive(&mut file, &parser)
+        if self.features != 0 {
+            return self.write_extended_file_to_archive(file, &mut parser, header_offset, data_regions);
+        }
+        self.write_file_to_archive(file, &parser)
     }
 
-    /// Add files and return list of failed files, then mark the file headers as stale
-    pub fn add_files(&mut self, file_paths: &Vec<PathBuf>) -> Vec<PathBuf>{
+    /// Add files and return list of failed files, then mark the file headers as stale.
+    /// The paths are consumed one at a time, so they can come from a lazy source such as a path list.
+    pub fn add_files<I, P>(&mut self, file_paths: I) -> Vec<PathBuf> where I: IntoIterator<Item = P>, P: AsRef<Path> {
+        if let Err(e) = self.check_writable() {
+            println!("Error adding files: {}", e);
+            return file_paths.into_iter().map(|path| path.as_ref().to_path_buf()).collect();
+        }
         let mut failed_files: Vec<PathBuf> = Vec::new();
         for file_path in file_paths {
+            let file_path = file_path.as_ref().to_path_buf();
             match self.add_file(&file_path) {
                 Ok(_) => {},
                 Err(e) => {
@@ -289,9 +1089,133 @@ impl FctArchive {
         failed_files
     }
 
-    // This function probably isn't needed
-    pub fn extract_file(&mut self, output_folder: PathBuf, index: u32, output_path: &PathBuf) -> Result<(), &'static str>{
-        self.seek_to_start();
+    /// Add files like add_files, with the given number of threads opening and reading the files ahead while the entries
+    /// are written one after the other in the order of the paths. The archive is the same as the one add_files writes.
+    /// With 0 threads, one thread per processor is used. Files bigger than 8 MiB are not read ahead but written
+    /// straight from the file, which keeps the memory used low.
+    pub fn add_files_parallel<I, P>(&mut self, file_paths: I, threads: usize) -> Vec<PathBuf> where I: IntoIterator<Item = P>, P: AsRef<Path> {
+        if let Err(e) = self.check_writable() {
+            println!("Error adding files: {}", e);
+            return file_paths.into_iter().map(|path| path.as_ref().to_path_buf()).collect();
+        }
+        let threads = match threads {
+            0 => std::thread::available_parallelism().map(|count| count.get()).unwrap_or(1),
+            count => count
+        };
+        // files being prepared or waiting to be written, bounding the memory taken by files read ahead
+        let window = threads * 2;
+        let current_dir = std::env::current_dir().unwrap();
+        let (features, chunk_size) = (self.features, self.chunk_size);
+        let mut failed_files: Vec<PathBuf> = Vec::new();
+        let (job_sender, job_receiver) = mpsc::channel::<(usize, PathBuf)>();
+        let (result_sender, result_receiver) = mpsc::channel::<(usize, PreparedResult)>();
+        let job_receiver = Mutex::new(job_receiver);
+        std::thread::scope(|scope| {
+            for _ in 0..threads {
+                let (job_receiver, result_sender, current_dir) = (&job_receiver, result_sender.clone(), &current_dir);
+                scope.spawn(move || loop {
+                    let job = match job_receiver.lock() {
+                        Ok(receiver) => receiver.recv(),
+                        Err(_) => return
+                    };
+                    let (sequence, file_path) = match job {
+                        Ok(job) => job,
+                        Err(_) => return
+                    };
+                    // a file that cannot be prepared must still get a result, or the files after it would never be written
+                    let prepared = std::panic::catch_unwind(|| Self::prepare_file(&file_path, current_dir, None, features, chunk_size).and_then(|mut prepared| {
+                        let size = prepared.parser.logical_size();
+                        if size > READ_AHEAD_FILE_SIZE {
+                            return Ok((prepared, None));
+                        }
+                        let mut contents = Vec::with_capacity(size as usize);
+                        unwrap_or_return_error!(
+                            Read::by_ref(&mut prepared.file).take(size).read_to_end(&mut contents),
+                            "Error adding file: Could not read file"
+                        );
+                        Ok((prepared, Some(contents)))
+                    })).unwrap_or(Err("Error adding file: Could not prepare file"));
+                    if result_sender.send((sequence, prepared)).is_err() {
+                        return;
+                    }
+                });
+            }
+            // only the workers can send results, so receiving fails once they are all gone
+            drop(result_sender);
+
+            let mut file_paths = file_paths.into_iter().map(|path| path.as_ref().to_path_buf());
+            let mut job_sender = Some(job_sender);
+            let mut sent = 0;
+            let mut written = 0;
+            // paths of the files sent to the workers and not written yet
+            let mut pending: BTreeMap<usize, PathBuf> = BTreeMap::new();
+            let mut waiting: BTreeMap<usize, PreparedResult> = BTreeMap::new();
+            loop {
+                // the paths are taken here, so that they can come from a source that cannot be shared between threads
+                while sent - written < window && job_sender.is_some() {
+                    match file_paths.next() {
+                        Some(file_path) => {
+                            pending.insert(sent, file_path.clone());
+                            if job_sender.as_ref().unwrap().send((sent, file_path)).is_err() {
+                                job_sender = None;
+                            }
+                            sent += 1;
+                        },
+                        // closing the channel stops the workers once they are done
+                        None => job_sender = None
+                    }
+                }
+                if written == sent {
+                    break;
+                }
+                match result_receiver.recv() {
+                    Ok((sequence, prepared)) => {
+                        waiting.insert(sequence, prepared);
+                    },
+                    Err(_) => break
+                }
+                while let Some(prepared) = waiting.remove(&written) {
+                    let file_path = pending.remove(&written).unwrap();
+                    let added = match prepared {
+                        Ok((prepared, contents)) => {
+                            println!("Adding file: {}", prepared.parser.file_path.display());
+                            match contents {
+                                Some(contents) => self.add_entry(&mut Cursor::new(contents), prepared.parser, prepared.data_regions.as_ref()),
+                                None => self.add_entry(&mut BufReader::new(prepared.file), prepared.parser, prepared.data_regions.as_ref())
+                            }
+                        },
+                        Err(e) => Err(e)
+                    };
+                    if let Err(e) = added {
+                        println!("Error adding file: {}", e);
+                        failed_files.push(file_path);
+                    }
+                    written += 1;
+                }
+            }
+            // only left if the workers stopped early
+            for (_, file_path) in pending {
+                println!("Error adding file: {}", file_path.display());
+                failed_files.push(file_path);
+            }
+        });
+        self.headers_stale = true;
+        failed_files
+    }
+
+    // This function probably isn't needed
+    /// Extract a single entry, resolving an existing output file with the policy, and return the action taken
+    pub fn extract_file(&mut self, output_folder: PathBuf, index: u32, output_path: &PathBuf, overwrite: OverwritePolicy) -> Result<ExtractAction, &'static str>{
+        let options = ExtractOptions { overwrite, ..Default::default() };
+        match self.extract_file_with_options(output_folder, index, output_path, &options)?.action {
+            ExtractAction::Failed(e) => Err(e),
+            action => Ok(action)
+        }
+    }
+
+    /// Extract a single entry, resolving an existing output file with the given options
+    pub fn extract_file_with_options(&mut self, output_folder: PathBuf, index: u32, output_path: &Path, options: &ExtractOptions) -> Result<ExtractReport, &'static str>{
+        self.seek_to_start();
         if !output_folder.exists() {
             unwrap_or_return_error!(
                 std::fs::create_dir_all(&output_folder),
@@ -299,115 +1223,247 @@ impl FctArchive {
             );
         }
 
-        for _ in 0..index {
-            match self.seek_file(){
-                Some(_) => {},
-                None => return Err("Could not seek to file")
-            }
+        if self.headers_stale {
+            self.get_headers();
         }
-
-        let mut header = unwrap_or_return_error!(
-            FileParser::from_archive(&mut self.archive_file),
-            "Could not parse file header"
-        );
+        let header = match self.headers.get(index as usize) {
+            Some(h) => h.clone(),
+            None => return Err("Could not seek to file")
+        };
+        unwrap_or_return_error!(self.archive_file.seek(SeekFrom::Start(header.data_offset)), "Could not seek to file");
         println!("Extracting file: {}", header.file_path.display());
 
-        let file_path = output_path.join(&header.file_path);
-        if file_path.exists() {
-            println!("File already exists, skipping");
-            return Ok(());
+        let file_path = match Self::map_output_path(output_path, &header.file_path, options) {
+            Ok(Some(path)) => path,
+            Ok(None) => {
+                return Ok(ExtractReport { path: header.file_path.clone(), action: ExtractAction::Skipped });
+            },
+            Err(e) => return Err(e)
+        };
+        if let Some(parent) = file_path.parent() {
+            unwrap_or_return_error!(
+                std::fs::create_dir_all(parent),
+                "Error extracting file: Could not create output folder"
+            );
         }
+        Ok(self.extract_entry_data(&header, &file_path, options.overwrite))
+    }
 
-        let file = match OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open(file_path) {
-            Ok(f) => BufReaderWriter::new_writer(f),
-            Err(_) => {
-                return Err("Error extracting file: Could not create file");
-            }
+    // rewrite a stored path and place it inside the output folder. None means the rules removed the path entirely.
+    pub(crate) fn map_output_path(output_folder: &Path, stored_path: &Path, options: &ExtractOptions) -> Result<Option<PathBuf>, &'static str> {
+        let rewritten = match fs_operations::rewrite_path(stored_path, &options.path_rewrites) {
+            Some(path) => path,
+            None => return Ok(None)
         };
-        // prepend header file path 
-        header.file_path = output_folder.join(&header.file_path);
-        self.write_file_from_archive(&mut BufReaderWriter::new_writer(file), &header, false)  
+        fs_operations::join_inside(output_folder, &rewritten).map(Some)
+    }
+
+    // decide where an entry goes if its output path is already taken
+    pub(crate) fn resolve_output_path(header: &FileParser, file_path: &PathBuf, policy: OverwritePolicy) -> Result<(PathBuf, ExtractAction), &'static str> {
+        if !file_path.exists() {
+            return Ok((file_path.clone(), ExtractAction::Extracted));
+        }
+        match policy {
+            OverwritePolicy::Skip => Ok((file_path.clone(), ExtractAction::Skipped)),
+            OverwritePolicy::Overwrite => Ok((file_path.clone(), ExtractAction::Overwritten)),
+            OverwritePolicy::OverwriteIfNewer => {
+                // an entry without a modification time is never known to be newer, so the existing file is kept
+                if header.features & file_parser::FEATURE_MODIFIED_TIME == 0 {
+                    return Ok((file_path.clone(), ExtractAction::Skipped));
+                }
+                let entry_time = UNIX_EPOCH + Duration::from_secs(header.modified);
+                match std::fs::metadata(file_path).and_then(|m| m.modified()) {
+                    Ok(file_time) if entry_time > file_time => Ok((file_path.clone(), ExtractAction::Overwritten)),
+                    _ => Ok((file_path.clone(), ExtractAction::Skipped))
+                }
+            },
+            OverwritePolicy::Rename => {
+                let free_path = fs_operations::find_free_path(file_path);
+                Ok((free_path.clone(), ExtractAction::Renamed(free_path)))
+            },
+            OverwritePolicy::Fail => Err("File already exists")
+        }
+    }
+
+    // open the output file of an entry, resolving an existing file with the policy. A file is only returned
+    // if the entry is to be written to it, otherwise the report tells why not.
+    pub(crate) fn create_output_file(header: &FileParser, file_path: &PathBuf, policy: OverwritePolicy) -> (ExtractReport, Option<File>) {
+        let (target_path, action) = match Self::resolve_output_path(header, file_path, policy) {
+            Ok(resolved) => resolved,
+            Err(e) => return (ExtractReport { path: file_path.clone(), action: ExtractAction::Failed(e) }, None)
+        };
+        if action == ExtractAction::Skipped {
+            return (ExtractReport { path: target_path, action }, None);
+        }
+        // truncate, so that a shorter entry does not leave stale bytes of the previous file behind
+        let out_file = match OpenOptions::new().write(true).create(true).truncate(true).open(&target_path) {
+            Ok(file) => file,
+            Err(_) => return (ExtractReport { path: target_path, action: ExtractAction::Failed("Could not create file") }, None)
+        };
+        // size the file up front, so holes in the entry stay holes on disk
+        if header.features & file_parser::FEATURE_SPARSE != 0 && out_file.set_len(header.logical_size()).is_err() {
+            return (ExtractReport { path: target_path, action: ExtractAction::Failed("Could not create file") }, None);
+        }
+        (ExtractReport { path: target_path, action }, Some(out_file))
+    }
+
+    // complete the output file of an entry once its contents are written
+    pub(crate) fn finish_output_file(header: &FileParser, file: &File) -> Result<(), &'static str> {
+        if header.features & file_parser::FEATURE_MODIFIED_TIME != 0 && file.set_modified(UNIX_EPOCH + Duration::from_secs(header.modified)).is_err() {
+            return Err("Could not set modification time");
+        }
+        Ok(())
+    }
+
+    // extract a single entry to the given path with positional reads of the storage, so that the serial and the
+    // parallel extraction write the same files
+    pub(crate) fn extract_entry_from(storage: &ArchiveStorage, layout: &ArchiveLayout, header: &FileParser, file_path: &PathBuf, policy: OverwritePolicy) -> ExtractReport {
+        let (report, out_file) = Self::create_output_file(header, file_path, policy);
+        let mut out_file = match out_file {
+            Some(file) => file,
+            None => return report
+        };
+        if let Err(e) = Self::copy_extents(storage, layout, header, &mut out_file) {
+            return ExtractReport { path: report.path, action: ExtractAction::Failed(e) };
+        }
+        if let Err(e) = Self::finish_output_file(header, &out_file) {
+            return ExtractReport { path: report.path, action: ExtractAction::Failed(e) };
+        }
+        report
+    }
+
+    // copies the contents of an entry to a file positioned at its start.
+    // Holes are skipped over, so the file has to be presized to leave them sparse.
+    fn copy_extents(storage: &ArchiveStorage, layout: &ArchiveLayout, header: &FileParser, file: &mut File) -> Result<(), &'static str> {
+        let mut buffer = Vec::new();
+        for extent in header.extents(layout)? {
+            let offset = match extent.source {
+                ExtentSource::Archive(offset) => offset,
+                ExtentSource::Zero => {
+                    unwrap_or_return_error!(file.seek(SeekFrom::Current(extent.length as i64)), "Error extracting file: Could not seek in file");
+                    continue;
+                }
+            };
+            unwrap_or_return_error!(
+                storage.copy_to(offset, extent.length, file, &mut buffer),
+                "Error extracting file: Could not write file"
+            );
+        }
+        Ok(())
+    }
+
+    // write out the data of an entry whose header has just been read and leave the cursor behind the entry's data
+    fn extract_entry_data(&mut self, header: &FileParser, file_path: &PathBuf, policy: OverwritePolicy) -> ExtractReport {
+        // the data is read from the files directly, so nothing may be left in the buffer
+        if self.archive_file.flush().is_err() {
+            return ExtractReport { path: file_path.clone(), action: ExtractAction::Failed("Could not write to archive") };
+        }
+        let report = Self::extract_entry_from(self.archive_file.get_ref(), &self.layout, header, file_path, policy);
+        if self.archive_file.seek(SeekFrom::Start(header.data_offset + header.stored_size())).is_err() {
+            return ExtractReport { path: report.path, action: ExtractAction::Failed("Error seeking over file") };
+        }
+        report
     }
 
     // this is more sophisticated than adding files because of optimisations
     /// Extract a file from the archive to the output folder, creating subdirectories if necessary
     pub fn extract_files(&mut self, output_folder: &PathBuf ,indices: &mut Vec<u32>) -> Vec<PathBuf>{
-        self.seek_to_start();
+        let options = ExtractOptions { overwrite: OverwritePolicy::Overwrite, ..Default::default() };
+        self.extract_files_with_options(output_folder, indices, &options)
+            .into_iter()
+            .filter(|report| matches!(report.action, ExtractAction::Failed(_)))
+            .map(|report| report.path)
+            .collect()
+    }
+
+    /// Extract files from the archive to the output folder and report the action taken for every selected entry
+    pub fn extract_files_with_options(&mut self, output_folder: &PathBuf, indices: &mut Vec<u32>, options: &ExtractOptions) -> Vec<ExtractReport>{
         if self.headers_stale {
             self.get_headers();
         }
+        self.seek_to_start();
+        if indices.is_empty() {
+            for i in 0..self.headers.len() {
+                indices.push(i as u32);
+            }
+        }
         if !output_folder.exists() {
             match std::fs::create_dir_all(output_folder) {
                 Ok(_) => {},
                 Err(e) => {
                     println!("Error extracting files: Could not create output folder: {}", e);
-                    // fill vector with the paths of all indices
-                    let output_vector = indices.iter().map(|i| {
-                        self.headers[*i as usize].file_path.clone()
+                    // report all selected entries as failed
+                    return indices.iter().filter_map(|i| self.headers.get(*i as usize)).map(|header| {
+                        ExtractReport {
+                            path: output_folder.join(&header.file_path),
+                            action: ExtractAction::Failed("Could not create output folder")
+                        }
                     }).collect();
-                    return output_vector;
                 }
             }
         }
-        let mut failed_files: Vec<PathBuf> = Vec::new();
-        if indices.len() == 0 {
-            for i in 0..self.headers.len() {
-                indices.push(i as u32);
-            }
+        let mut reports: Vec<ExtractReport> = Vec::new();
+        if self.headers.is_empty() {
+            return reports;
         }
 
         indices.sort();
-        let mut prev_directory: PathBuf = self.headers[0].file_path.parent().unwrap().to_path_buf();
+        let mut prev_directory: PathBuf = output_folder.clone();
         for i in 0..self.headers.len() {
             if !indices.contains(&(i as u32)) {
                 self.seek_file();
+                continue;
             }
-            else{
-                let mut header = self.headers[i as usize].clone();
-                let orig_header_size: i64 = header.get_header_size() as i64;
-                header.file_path = output_folder.join(&header.file_path);
-                let cur_directory = header.file_path.parent().unwrap();
-                if cur_directory != prev_directory {
-                    match std::fs::create_dir_all(header.file_path.parent().unwrap()) {
-                        Ok(_) => {},
-                        Err(e) => {
-                            println!("Error extracting files: Could not create output folder: {}", e);
-                            failed_files.push(header.file_path.clone());
-                            continue;
-                        }
-                    }
-                    prev_directory = cur_directory.to_path_buf();
+            let header = self.headers[i].clone();
+            let file_path = match Self::map_output_path(output_folder, &header.file_path, options) {
+                Ok(Some(path)) => path,
+                Ok(None) => {
+                    self.seek_file();
+                    reports.push(ExtractReport { path: header.file_path.clone(), action: ExtractAction::Skipped });
+                    continue;
+                },
+                Err(e) => {
+                    println!("Error extracting file {}: {}", header.file_path.display(), e);
+                    self.seek_file();
+                    reports.push(ExtractReport { path: header.file_path.clone(), action: ExtractAction::Failed(e) });
+                    continue;
                 }
-
-                println!("Extracting file: {}", header.file_path.display());
-
-                // seek over header
-                self.archive_file.seek(SeekFrom::Current(orig_header_size)).expect("Could not seek to file");
-                // write out file
-                let mut out_file = match OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open(&header.file_path) {
-                    Ok(f) => BufWriter::new(f),
-                    Err(_) => {
-                        //println!("Error opening file for extracting: {}", e);
-                        failed_files.push(self.headers[i as usize].file_path.clone());
-                        continue;
-                    }
-                };
-                match self.write_file_from_archive(&mut out_file, &header, false) {
+            };
+            let cur_directory = file_path.parent().unwrap();
+            if cur_directory != prev_directory {
+                match std::fs::create_dir_all(cur_directory) {
                     Ok(_) => {},
-                    Err(_) => {
-                        //println!("Error extracting file data: {}", e);
-                        failed_files.push(PathBuf::from(&header.file_path));
+                    Err(e) => {
+                        println!("Error extracting files: Could not create output folder: {}", e);
+                        self.seek_file();
+                        reports.push(ExtractReport {
+                            path: file_path,
+                            action: ExtractAction::Failed("Could not create output folder")
+                        });
+                        continue;
                     }
                 }
+                prev_directory = cur_directory.to_path_buf();
             }
+
+            println!("Extracting file: {}", file_path.display());
+
+            // seek over header
+            self.archive_file.seek(SeekFrom::Start(header.data_offset)).expect("Could not seek to file");
+            reports.push(self.extract_entry_data(&header, &file_path, options.overwrite));
+        }
+        reports
+    }
+
+    /// Extract files like extract_files_with_options, with the given number of threads reading the entries and writing
+    /// the files at once. With 0 threads, one thread per processor is used.
+    pub fn extract_files_parallel(&mut self, output_folder: &PathBuf, indices: &mut Vec<u32>, options: &ExtractOptions, threads: usize) -> Vec<ExtractReport>{
+        match SharedArchive::from_archive(self) {
+            Ok(shared) => shared.extract_files_parallel(output_folder, indices, options, threads),
+            Err(e) => indices.iter().filter_map(|i| self.headers.get(*i as usize)).map(|header| {
+                ExtractReport { path: output_folder.join(&header.file_path), action: ExtractAction::Failed(e) }
+            }).collect()
         }
-        failed_files
     }
 
     pub fn list_files(&mut self) {
@@ -423,66 +1479,1654 @@ impl FctArchive {
                 "{}: {} {}", 
                 index + 1,
                 self.headers[index].file_path.display(),
-                self.headers[index].chunk_count as u64 
-                    * self.chunk_size as u64 
-                    + self.headers[index].last_chunk_size as u64
+                self.headers[index].logical_size()
+            );
+        }
+        if self.features & file_parser::FEATURE_DEDUP != 0 {
+            let stats = self.dedup_stats();
+            println!(
+                "Deduplication: {} of {} chunks stored, {} bytes saved",
+                stats.stored_chunks,
+                stats.referenced_chunks,
+                stats.saved_bytes
+            );
+        }
+    }
+
+    /// Count the chunks referenced by entries against the chunks actually stored
+    pub fn dedup_stats(&mut self) -> DedupStats {
+        if self.headers_stale {
+            self.get_headers();
+        }
+        let mut stats = DedupStats::default();
+        for header in &self.headers {
+            let chunk_size = header.chunk_size.max(1) as u64;
+            stats.referenced_chunks += header.data_chunk_count() as u64;
+            stats.stored_chunks += header.stored_size() / chunk_size;
+            stats.saved_bytes += header.data_chunk_count() as u64 * chunk_size - header.stored_size();
+        }
+        stats
+    }
+
+    /// Check that the records of the archive end exactly where the archive does and that every entry can be read
+    /// in full. Returns the problems found with the path of the affected entry, or of the archive itself.
+    pub fn verify(&mut self) -> Vec<(PathBuf, &'static str)> {
+        let mut problems = Vec::new();
+        self.headers_stale = true;
+        self.get_headers();
+        self.seek_to_start();
+        while self.seek_record().is_some() {}
+        // a missing or truncated volume leaves the last record reaching past the end
+        let records_end = self.archive_file.stream_position().unwrap_or(0);
+        match self.archive_file.seek(SeekFrom::End(0)) {
+            Ok(archive_end) if records_end > archive_end => problems.push((self.archive_path.clone(), "Archive is truncated")),
+            Ok(archive_end) if records_end < archive_end => problems.push((self.archive_path.clone(), "Archive has unreadable data at its end")),
+            Ok(_) => {},
+            Err(_) => problems.push((self.archive_path.clone(), "Could not get archive size"))
+        }
+
+        for index in 0..self.headers.len() {
+            let header = self.headers[index].clone();
+            let mut reader = match self.entry_reader(index as u32) {
+                Ok(reader) => reader,
+                Err(e) => {
+                    problems.push((header.file_path, e));
+                    continue;
+                }
+            };
+            match std::io::copy(&mut reader, &mut std::io::sink()) {
+                Ok(length) if length == header.logical_size() => {},
+                Ok(_) => problems.push((header.file_path, "Entry data is incomplete")),
+                Err(_) => problems.push((header.file_path, "Could not read entry data"))
+            }
+        }
+        problems
+    }
+
+    // SHA-256 of the contents of the entry at the given index
+    fn entry_hash(&mut self, index: u32) -> Result<[u8; 32], &'static str> {
+        let mut hasher = Sha256::new();
+        let mut reader = self.entry_reader(index)?;
+        unwrap_or_return_error!(std::io::copy(&mut reader, &mut hasher), "Could not read entry data");
+        Ok(hasher.finalize().into())
+    }
+
+    // the last entry of every path, which is the one an extraction leaves behind
+    fn entries_by_path(&mut self) -> BTreeMap<PathBuf, u32> {
+        if self.headers_stale {
+            self.get_headers();
+        }
+        self.headers.iter().enumerate().map(|(index, header)| (header.file_path.clone(), index as u32)).collect()
+    }
+
+    // how the entry at the given index differs from a file. Contents are only compared if asked to and the sizes match.
+    fn file_differences(&mut self, index: u32, file_path: &PathBuf, compare_content: bool) -> Result<Vec<Difference>, &'static str> {
+        let header = self.headers[index as usize].clone();
+        let metadata = unwrap_or_return_error!(std::fs::metadata(file_path), "Could not read file");
+        let mut differences = Vec::new();
+        if metadata.len() != header.logical_size() {
+            differences.push(Difference::Size);
+        }
+        if header.features & file_parser::FEATURE_MODIFIED_TIME != 0 && fs_operations::modified_seconds(&metadata) != header.modified {
+            differences.push(Difference::ModifiedTime);
+        }
+        if compare_content && metadata.len() == header.logical_size() {
+            let mut hasher = Sha256::new();
+            let mut file = unwrap_or_return_error!(File::open(file_path), "Could not open file");
+            unwrap_or_return_error!(std::io::copy(&mut file, &mut hasher), "Could not read file");
+            let file_hash: [u8; 32] = hasher.finalize().into();
+            if self.entry_hash(index)? != file_hash {
+                differences.push(Difference::Content);
+            }
+        }
+        Ok(differences)
+    }
+
+    /// Compare the entries with the files below a directory, as if the archive was extracted into it
+    /// with the path rewrites of the options. The expand options choose the files on disk that are compared, like sync
+    /// does it, so files they leave out are neither reported as only on disk nor as missing for their entries.
+    pub fn diff_directory(&mut self, directory: &PathBuf, options: &ExtractOptions, expand_options: &ExpandOptions) -> Result<DiffReport, &'static str> {
+        let mut entries: BTreeMap<PathBuf, u32> = BTreeMap::new();
+        for (stored_path, index) in self.entries_by_path() {
+            match Self::map_output_path(directory, &stored_path, options) {
+                Ok(Some(output_path)) => {
+                    entries.insert(output_path.strip_prefix(directory).unwrap_or(&output_path).to_path_buf(), index);
+                },
+                Ok(None) => {},
+                Err(e) => println!("Skipping {}: {}", stored_path.display(), e)
+            }
+        }
+        let mut files: BTreeMap<PathBuf, PathBuf> = BTreeMap::new();
+        if directory.is_dir() {
+            for file_path in fs_operations::expand_directory_with_options(directory, expand_options)? {
+                files.insert(file_path.strip_prefix(directory).unwrap_or(&file_path).to_path_buf(), file_path);
+            }
+        }
+
+        let mut report = DiffReport { target: DiffTarget::Directory, entries: Vec::new() };
+        for (path, index) in entries {
+            let file_path = match files.remove(&path) {
+                Some(file_path) => file_path,
+                None if directory.join(&path).is_file() => continue,
+                None => {
+                    report.entries.push(DiffEntry { path, kind: DiffKind::OnlyInArchive, differences: Vec::new() });
+                    continue;
+                }
+            };
+            let differences = self.file_differences(index, &file_path, true)?;
+            if !differences.is_empty() {
+                report.entries.push(DiffEntry { path, kind: DiffKind::Changed, differences });
+            }
+        }
+        for path in files.into_keys() {
+            report.entries.push(DiffEntry { path, kind: DiffKind::OnlyInOther, differences: Vec::new() });
+        }
+        report.entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(report)
+    }
+
+    /// Compare the entries with the entries of another archive by their stored paths
+    pub fn diff_archive(&mut self, other: &mut FctArchive) -> Result<DiffReport, &'static str> {
+        let mut other_entries = other.entries_by_path();
+        let mut report = DiffReport { target: DiffTarget::Archive, entries: Vec::new() };
+        for (path, index) in self.entries_by_path() {
+            let other_index = match other_entries.remove(&path) {
+                Some(other_index) => other_index,
+                None => {
+                    report.entries.push(DiffEntry { path, kind: DiffKind::OnlyInArchive, differences: Vec::new() });
+                    continue;
+                }
+            };
+            let header = self.headers[index as usize].clone();
+            let other_header = other.headers[other_index as usize].clone();
+            let mut differences = Vec::new();
+            if header.logical_size() != other_header.logical_size() {
+                differences.push(Difference::Size);
+            }
+            if header.features & other_header.features & file_parser::FEATURE_MODIFIED_TIME != 0 && header.modified != other_header.modified {
+                differences.push(Difference::ModifiedTime);
+            }
+            if header.logical_size() == other_header.logical_size() && self.entry_hash(index)? != other.entry_hash(other_index)? {
+                differences.push(Difference::Content);
+            }
+            if !differences.is_empty() {
+                report.entries.push(DiffEntry { path, kind: DiffKind::Changed, differences });
+            }
+        }
+        for path in other_entries.into_keys() {
+            report.entries.push(DiffEntry { path, kind: DiffKind::OnlyInOther, differences: Vec::new() });
+        }
+        report.entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(report)
+    }
+
+    /// Bring the entries of the files below a directory up to date: new files are added, changed files replaced and
+    /// entries of files that are gone removed, all in a single rewrite that only replaces the archive once it is complete.
+    /// Files count as changed if their size or modification time differ, or, if the archive has no modification times,
+    /// their contents. The options choose the files that are added or updated, while entries are only removed once their
+    /// file no longer exists, so files left out by the options keep their entries. Entries outside of the directory are
+    /// kept as they are, and so are the entries of files that cannot be opened, which are reported as failed.
+    /// A path stored more than once is compared by its last entry and loses all of its entries when it is replaced.
+    pub fn sync(&mut self, directory: &PathBuf, options: &ExpandOptions) -> Result<SyncReport, &'static str> {
+        self.check_writable()?;
+        let current_dir = unwrap_or_return_error!(std::env::current_dir(), "Could not get current directory");
+        // entries are stored relative to the current directory, the same way add_file does it
+        let directory = unwrap_or_return_error!(std::fs::canonicalize(directory), "Could not find directory");
+        let scope = fs_operations::format_path(&current_dir, &directory)?;
+        let files = fs_operations::expand_directory_with_options(&directory, options)?;
+        // a path can be stored more than once, the last entry being the one that is extracted
+        let mut entries: HashMap<PathBuf, Vec<u32>> = HashMap::new();
+        for (index, header) in self.get_headers().iter().enumerate() {
+            entries.entry(header.file_path.clone()).or_default().push(index as u32);
+        }
+        let compare_content = self.features & file_parser::FEATURE_MODIFIED_TIME == 0;
+
+        let mut report = SyncReport::default();
+        let mut removed_indices: Vec<u32> = Vec::new();
+        let mut added_files: Vec<PathBuf> = Vec::new();
+        let mut found: HashSet<PathBuf> = HashSet::new();
+        for file_path in files {
+            let stored_path = fs_operations::format_path(&current_dir, &file_path)?;
+            found.insert(stored_path.clone());
+            // files that cannot be read keep their entries as they are
+            if File::open(&file_path).is_err() {
+                report.failed.push(stored_path);
+                continue;
+            }
+            match entries.get(&stored_path) {
+                Some(indices) if self.file_differences(indices[indices.len() - 1], &file_path, compare_content)?.is_empty() => report.unchanged += 1,
+                Some(indices) => {
+                    removed_indices.extend(indices);
+                    added_files.push(file_path);
+                    report.replaced.push(stored_path);
+                },
+                None => {
+                    added_files.push(file_path);
+                    report.added.push(stored_path);
+                }
+            }
+        }
+        for (index, header) in self.headers.iter().enumerate() {
+            if header.file_path.starts_with(&scope) && !found.contains(&header.file_path) && !current_dir.join(&header.file_path).is_file() {
+                removed_indices.push(index as u32);
+                report.removed.push(header.file_path.clone());
+            }
+        }
+
+        if !removed_indices.is_empty() || !added_files.is_empty() {
+            self.rewrite(&removed_indices, &added_files)?;
+        }
+        Ok(report)
+    }
+
+    /// Report how the space of the archive is used by the entries
+    pub fn stats(&mut self) -> Result<ArchiveStats, &'static str> {
+        if self.headers_stale {
+            self.get_headers();
+        }
+        let archive_bytes = unwrap_or_return_error!(self.archive_file.seek(SeekFrom::End(0)), "Could not get archive size");
+        let mut entries: Vec<EntryStats> = Vec::with_capacity(self.headers.len());
+        let mut tail_blocks = vec![TailBlockStats {
+            header_bytes: FileParser::tail_block(self.features, self.chunk_size).get_header_size() as u64,
+            stored_bytes: self.chunk_size as u64,
+            used_bytes: 0
+        }; self.layout.tail_blocks.len()];
+        // index of the first chunk an entry adds to the chunk store
+        let mut next_stored_chunk: u64 = 0;
+        for header in &self.headers {
+            // the partial last chunk is padded where it is stored as a chunk
+            let last_chunk_stored = match header.data_chunk_count() > header.chunk_count {
+                false => false,
+                true if header.is_hole(header.chunk_count) => false,
+                true if header.features & file_parser::FEATURE_DEDUP != 0 => {
+                    header.chunk_refs[header.chunk_count as usize] as u64 >= next_stored_chunk
+                },
+                true => true
+            };
+            next_stored_chunk += header.stored_chunk_count as u64;
+            if header.has_packed_tail() {
+                if let Some(tail_block) = tail_blocks.get_mut(header.tail_block as usize) {
+                    tail_block.used_bytes += header.last_chunk_size as u64;
+                }
+            }
+            entries.push(EntryStats {
+                path: header.file_path.clone(),
+                logical_bytes: header.logical_size(),
+                stored_bytes: header.stored_size(),
+                header_bytes: header.get_header_size() as u64,
+                padding_bytes: if last_chunk_stored { (header.chunk_size - header.last_chunk_size) as u64 } else { 0 }
+            });
+        }
+        Ok(ArchiveStats::new(archive_bytes, self.chunk_size, self.data_start, entries, &tail_blocks))
+    }
+
+    /// Copy the entries of the source archives to the end of this archive, in order.
+    /// Entries are copied as they are stored if the archives are compatible, and cut into chunks anew otherwise.
+    /// Conflicts are resolved before the archive is changed. If entries are replaced, the archive is written anew into a
+    /// temporary archive that only replaces it once complete, so failing leaves it untouched. Otherwise the entries are
+    /// appended in place like added files are, and cut off again if the merge fails.
+    pub fn merge_from(&mut self, sources: &mut [FctArchive], policy: MergeConflictPolicy) -> Result<Vec<MergeReport>, &'static str> {
+        self.check_writable()?;
+        if self.headers_stale {
+            self.get_headers();
+        }
+        // the entry currently owning a name, either in this archive or planned to be copied
+        enum Owner {
+            Existing(u32),
+            Planned(usize)
+        }
+        let mut owners: HashMap<PathBuf, Owner> = HashMap::new();
+        for (index, header) in self.headers.iter().enumerate() {
+            owners.insert(header.file_path.clone(), Owner::Existing(index as u32));
+        }
+        // (source index, entry index, name in this archive, index of the report), None once replaced again
+        let mut plan: Vec<Option<(usize, u32, PathBuf, usize)>> = Vec::new();
+        let mut removed_indices: Vec<u32> = Vec::new();
+        let mut reports: Vec<MergeReport> = Vec::new();
+        for (source_index, source) in sources.iter_mut().enumerate() {
+            if source.headers_stale {
+                source.get_headers();
+            }
+            for (entry_index, header) in source.headers.iter().enumerate() {
+                let path = header.file_path.clone();
+                let (name, action) = match (owners.get(&path), policy) {
+                    (None, _) | (Some(_), MergeConflictPolicy::KeepBoth) => (path.clone(), MergeAction::Added),
+                    (Some(_), MergeConflictPolicy::Skip) => {
+                        reports.push(MergeReport { path, action: MergeAction::Skipped });
+                        continue;
+                    },
+                    (Some(_), MergeConflictPolicy::Fail) => {
+                        println!("Entry already exists: {}", path.display());
+                        return Err("Entry already exists in the target archive");
+                    },
+                    (Some(_), MergeConflictPolicy::Rename) => {
+                        let name = fs_operations::find_free_name(&path, |candidate| owners.contains_key(candidate));
+                        (name.clone(), MergeAction::Renamed(name))
+                    },
+                    (Some(Owner::Existing(index)), MergeConflictPolicy::Replace) => {
+                        removed_indices.push(*index);
+                        (path.clone(), MergeAction::Replaced)
+                    },
+                    (Some(Owner::Planned(plan_index)), MergeConflictPolicy::Replace) => {
+                        // an entry of an earlier source is replaced before it is even copied
+                        if let Some((_, _, _, report_index)) = plan[*plan_index].take() {
+                            reports[report_index].action = MergeAction::Skipped;
+                        }
+                        (path.clone(), MergeAction::Replaced)
+                    }
+                };
+                owners.insert(name.clone(), Owner::Planned(plan.len()));
+                plan.push(Some((source_index, entry_index as u32, name, reports.len())));
+                reports.push(MergeReport { path, action });
+            }
+        }
+
+        let copy_planned = |target: &mut FctArchive| -> Result<(), &'static str> {
+            for (source_index, entry_index, name, _) in plan.into_iter().flatten() {
+                println!("Merging file: {}", name.display());
+                target.copy_entry_from(&mut sources[source_index], entry_index, name)?;
+            }
+            Ok(())
+        };
+        match removed_indices.is_empty() {
+            true => {
+                let length = unwrap_or_return_error!(self.archive_file.seek(SeekFrom::End(0)), "Could not get archive size");
+                if let Err(e) = copy_planned(self) {
+                    self.truncate(length)?;
+                    return Err(e);
+                }
+            },
+            false => self.rewrite_with(&removed_indices, copy_planned)?
+        }
+        Ok(reports)
+    }
+
+    // cut off everything behind the given length, such as partially appended entries, and reread the headers
+    fn truncate(&mut self, length: u64) -> Result<(), &'static str> {
+        unwrap_or_return_error!(self.archive_file.flush(), "Could not write to archive");
+        unwrap_or_return_error!(self.archive_file.get_mut().truncate(length), "Could not truncate archive");
+        unwrap_or_return_error!(self.archive_file.seek(SeekFrom::Start(length)), "Could not seek in archive");
+        self.headers_stale = true;
+        self.dedup_index = None;
+        self.get_headers();
+        Ok(())
+    }
+
+    // append an entry of another archive under the given name, copying its stored data unchanged if possible
+    fn copy_entry_from(&mut self, source: &mut FctArchive, index: u32, name: PathBuf) -> Result<(), &'static str> {
+        let header = match source.headers.get(index as usize) {
+            Some(header) => header.clone(),
+            None => return Err("Could not find entry")
+        };
+        // entries of deduplicating and tail packing archives depend on data stored elsewhere,
+        // split archives have to check every chunk against the volume size
+        let raw_copy = source.features == self.features
+            && source.chunk_size == self.chunk_size
+            && self.features & (file_parser::FEATURE_DEDUP | file_parser::FEATURE_TAIL_PACK | file_parser::FEATURE_MULTI_VOLUME) == 0;
+        if raw_copy {
+            let entry = FileParser { file_path: name, ..header.clone() };
+            unwrap_or_return_error!(self.archive_file.seek(SeekFrom::End(0)), "Could not seek to end of archive");
+            unwrap_or_return_error!(self.archive_file.write_all(&entry.generate_header()?), "Could not write file header");
+            self.headers_stale = true;
+            return source.copy_data_to(header.data_offset, header.stored_size(), self);
+        }
+
+        let chunk_size = match self.features & source.features & file_parser::FEATURE_ENTRY_CHUNK_SIZE {
+            0 => self.chunk_size,
+            _ => header.chunk_size
+        };
+        let mut entry = FileParser { file_path: name, chunk_size, modified: header.modified, ..Default::default() };
+        entry.set_logical_size(header.logical_size())?;
+        let mut reader = source.entry_reader(index)?;
+        self.add_entry(&mut reader, entry, None)
+    }
+
+    /// Write a self-extracting archive: the extractor executable at the stub path, followed by the archive and
+    /// a footer locating it, so that both the extractor and FctArchive::open find the archive inside the executable
+    pub fn write_self_extracting(&mut self, stub_path: &Path, output_path: &Path) -> Result<(), &'static str> {
+        unwrap_or_return_error!(self.archive_file.flush(), "Could not write to archive");
+        let mut stub = unwrap_or_return_error!(File::open(stub_path), "Could not open extractor");
+        let mut output = BufWriter::new(unwrap_or_return_error!(File::create(output_path), "Could not create self-extracting archive"));
+        let offset = unwrap_or_return_error!(std::io::copy(&mut stub, &mut output), "Could not write extractor");
+        unwrap_or_return_error!(self.archive_file.seek(SeekFrom::Start(0)), "Could not seek to start of archive");
+        unwrap_or_return_error!(std::io::copy(&mut self.archive_file, &mut output), "Could not write archive");
+        unwrap_or_return_error!(storage::write_embedded_footer(&mut output, offset), "Could not write archive footer");
+        unwrap_or_return_error!(output.flush(), "Could not write self-extracting archive");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            unwrap_or_return_error!(
+                std::fs::set_permissions(output_path, std::fs::Permissions::from_mode(0o755)),
+                "Could not make self-extracting archive executable"
+            );
+        }
+        Ok(())
+    }
+
+    /// Write all entries into a new archive at the target path which uses the given chunk size, in the same order
+    /// and with the same format options. The entries are streamed, so nothing is extracted in between.
+    /// If the target is the archive itself, the new archive is written next to it and only replaces it once complete.
+    /// A target that could not be completed is removed again.
+    pub fn rechunk(&mut self, target_path: &Path, chunk_size: u16) -> Result<FctArchive, &'static str> {
+        if self.headers_stale {
+            self.get_headers();
+        }
+        let in_place = fs_operations::absolute_path(&storage::archive_base_path(target_path)) == fs_operations::absolute_path(&self.archive_path);
+        let write_path = match in_place {
+            true => storage::temporary_path(&self.archive_path),
+            false => target_path.to_path_buf()
+        };
+        let mut target = FctArchive::create_with_options(&write_path, chunk_size, &self.options())?;
+        if let Err(e) = self.rechunk_into(&mut target, chunk_size) {
+            drop(target);
+            let _ = storage::remove_archive(&write_path);
+            return Err(e);
+        }
+        if !in_place {
+            return Ok(target);
+        }
+        drop(target);
+        unwrap_or_return_error!(storage::replace_archive(&write_path, &self.archive_path), "Could not replace old archive");
+        // this archive's handle still reads the replaced archive, so it is reopened too
+        *self = FctArchive::open(&self.archive_path)?;
+        FctArchive::open(&self.archive_path)
+    }
+
+    // write the entries cut into chunks of the given size into the target archive
+    fn rechunk_into(&mut self, target: &mut FctArchive, chunk_size: u16) -> Result<(), &'static str> {
+        if self.features != 0 {
+            self.write_entries_to(target, &[], Some(chunk_size))?;
+            unwrap_or_return_error!(target.archive_file.flush(), "Could not write data to the new archive");
+            return Ok(());
+        }
+
+        // plain archives store the entries one after the other, so they are copied in a single sequential pass
+        self.seek_to_start();
+        while let Ok(header) = FileParser::from_archive_with_features(&mut self.archive_file, 0, self.chunk_size) {
+            println!("Rechunking file: {}", header.file_path.display());
+            let mut entry = FileParser { file_path: header.file_path.clone(), chunk_size, ..Default::default() };
+            entry.set_logical_size(header.logical_size())?;
+            unwrap_or_return_error!(
+                target.archive_file.write_all(&entry.generate_header()?),
+                "Could not write file header to the new archive"
+            );
+            // the contents are copied without padding, which is then added for the new chunk size
+            unwrap_or_return_error!(
+                self.write_file_from_archive(target, &header, false),
+                "Could not write data to the new archive"
+            );
+            if entry.last_chunk_size > 0 {
+                unwrap_or_return_error!(
+                    target.archive_file.write_all(&vec![0u8; (chunk_size - entry.last_chunk_size) as usize]),
+                    "Could not write data to the new archive"
+                );
+            }
+        }
+        unwrap_or_return_error!(target.archive_file.flush(), "Could not write data to the new archive");
+        target.headers_stale = true;
+        Ok(())
+    }
+
+    // entries of extended archives can refer to data of other entries, so their contents are written anew.
+    // If a chunk size is given, the entries are cut into chunks of that size instead of keeping theirs.
+    fn write_entries_to(&mut self, target: &mut FctArchive, skipped_indices: &[u32], chunk_size: Option<u16>) -> Result<(), &'static str> {
+        for index in 0..self.headers.len() {
+            let header = self.headers[index].clone();
+            if skipped_indices.contains(&(index as u32)) {
+                println!("Removing file: {}", header.file_path.display());
+                continue;
+            }
+            let mut entry = FileParser {
+                file_path: header.file_path.clone(),
+                chunk_count: header.chunk_count,
+                last_chunk_size: header.last_chunk_size,
+                chunk_size: header.chunk_size,
+                modified: header.modified,
+                ..Default::default()
+            };
+            if let Some(chunk_size) = chunk_size {
+                // entries with their own chunk size get the one fitting their size under the new default
+                entry.chunk_size = match target.features & file_parser::FEATURE_ENTRY_CHUNK_SIZE {
+                    0 => chunk_size,
+                    _ => Self::entry_chunk_size(target.features, chunk_size, header.logical_size())
+                };
+                entry.set_logical_size(header.logical_size())?;
+            }
+            let mut reader = self.entry_reader(index as u32)?;
+            unwrap_or_return_error!(
+                target.add_entry(&mut reader, entry, None),
+                "Could not write data to the temporary archive"
             );
         }
+        Ok(())
     }
 
     // remove files by moving non-matched items to a new archive. Returns the new archive
     /// Remove the files at the indices given from the archive and mark the file headers as stale
-    pub fn remove_files(&mut self, file_indices: &Vec<u32>) -> Result<(), &'static str>{
+    pub fn remove_files(&mut self, file_indices: &[u32]) -> Result<(), &'static str>{
+        if self.headers_stale {
+            self.get_headers();
+        }
         if self.headers.len() == 0 {
             return Err("No files in archive");
         }
+        self.rewrite(file_indices, &[])
+    }
 
+    // write the entries not at the given indices and then the given files into a temporary archive, which replaces
+    // the archive once it is complete. If a file cannot be added, the archive is left unchanged.
+    fn rewrite(&mut self, file_indices: &[u32], added_files: &[PathBuf]) -> Result<(), &'static str> {
+        self.rewrite_with(file_indices, |tmp_archive| {
+            let failed_files = tmp_archive.add_files(added_files);
+            for failed_file in &failed_files {
+                println!("Could not add file: {}", failed_file.display());
+            }
+            match failed_files.is_empty() {
+                true => Ok(()),
+                false => Err("Could not add files to the temporary archive")
+            }
+        })
+    }
+
+    // like rewrite, with the given function appending to the temporary archive after the kept entries
+    fn rewrite_with<F>(&mut self, file_indices: &[u32], append: F) -> Result<(), &'static str> where F: FnOnce(&mut FctArchive) -> Result<(), &'static str> {
+        self.check_writable()?;
+        if self.headers_stale {
+            self.get_headers();
+        }
         let mut tmp_archive = unwrap_or_return_error!(
-            FctArchive::create_new(&self.archive_path.with_extension("tmp"), self.chunk_size),
-            "Error removing files: Could not create temporary archive"
+            FctArchive::create_with_options(&storage::temporary_path(&self.archive_path), self.chunk_size, &self.options()),
+            "Could not create temporary archive"
         );
         self.seek_to_start();
         tmp_archive.seek_to_start();
 
-        let mut index = 0;
-        loop {
-            let header = match FileParser::from_archive(&mut self.archive_file) {
-                Ok(h) => h,
-                Err(_) => break
-            };
+        if self.features != 0 {
+            self.write_entries_to(&mut tmp_archive, file_indices, None)?;
+        }
+        else {
+            let mut index = 0;
+            while let Ok(header) = FileParser::from_archive_with_features(&mut self.archive_file, 0, self.chunk_size) {
 
-            if !file_indices.contains(&(index as u32)) {
-                // write header to tmp archive
-                unwrap_or_return_error!(
-                    tmp_archive.archive_file.write(&header.generate_header().unwrap()),
-                    "Could not write file header to the temporary archive"
-                );
-                // write file to tmp archive
-                unwrap_or_return_error!(
-                    self.write_file_from_archive(&mut tmp_archive.archive_file, &header, true),
-                    "Could not write data to the temporary archive"
-                );
-            }
-            else {
-                println!("Removing file: {}", header.file_path.display());
-                unwrap_or_return_error!(
-                    self.seek_data(&header),
-                    "Could not seek over file data in the original archive"
-                );
+                if !file_indices.contains(&(index as u32)) {
+                    // write header to tmp archive
+                    unwrap_or_return_error!(
+                        tmp_archive.archive_file.write(&header.generate_header().unwrap()),
+                        "Could not write file header to the temporary archive"
+                    );
+                    // write file to tmp archive
+                    unwrap_or_return_error!(
+                        self.write_file_from_archive(&mut tmp_archive, &header, true),
+                        "Could not write data to the temporary archive"
+                    );
+                }
+                else {
+                    println!("Removing file: {}", header.file_path.display());
+                    unwrap_or_return_error!(
+                        self.seek_data(&header),
+                        "Could not seek over file data in the original archive"
+                    );
+                }
+                index += 1;
             }
-            index += 1;
-        }
-        std::fs::remove_file(self.archive_path.as_path()).expect("Error removing files: Could not remove old archive");
-        std::fs::rename(
-            tmp_archive.archive_path.as_path(),
-            self.archive_path.as_path()
-        ).expect("Error removing files: Could not rename temporary archive");
-        
-        // replace old self
-        self.archive_path = tmp_archive.archive_path;
-        self.archive_file = tmp_archive.archive_file;
-        self.headers = tmp_archive.headers;
+        }
+        let tmp_path = tmp_archive.archive_path.clone();
+        if let Err(e) = append(&mut tmp_archive) {
+            drop(tmp_archive);
+            let _ = storage::remove_archive(&tmp_path);
+            return Err(e);
+        }
+        unwrap_or_return_error!(tmp_archive.archive_file.flush(), "Could not write to the temporary archive");
+        let layout = tmp_archive.layout;
+        let tail_block_used = tmp_archive.tail_block_used;
+        drop(tmp_archive.archive_file);
+        unwrap_or_return_error!(storage::replace_archive(&tmp_path, &self.archive_path), "Could not replace old archive");
+
+        // reopen the archive at the original path the temporary archive has been moved to, volumes may have been renamed
+        let storage = unwrap_or_return_error!(ArchiveStorage::open(&self.archive_path), "Could not reopen archive");
+        self.archive_file = BufReaderWriter::new_reader(storage);
+        self.headers = Vec::new();
         self.headers_stale = true;
+        self.dedup_index = None;
+        self.layout = layout;
+        self.tail_block_used = tail_block_used;
         Ok(())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // a fresh folder for the files of a test
+    pub(crate) fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fct4-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("input")).unwrap();
+        dir
+    }
+
+    // bytes that do not repeat within the length, different for every seed
+    pub(crate) fn noise(length: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(2654435761).wrapping_add(1);
+        (0..length).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect()
+    }
+
+    // write the files into the input folder of the test folder, add them to a new archive and open it again
+    pub(crate) fn create_archive(dir: &Path, chunk_size: u16, options: &ArchiveOptions, files: &[(&str, Vec<u8>)]) -> FctArchive {
+        let archive_path = dir.join("test.fct");
+        let mut archive = FctArchive::create_with_options(&archive_path, chunk_size, options).unwrap();
+        add_files(&mut archive, dir, files);
+        drop(archive);
+        FctArchive::open(&archive_path).unwrap()
+    }
+
+    pub(crate) fn add_files(archive: &mut FctArchive, dir: &Path, files: &[(&str, Vec<u8>)]) {
+        let input = dir.join("input");
+        for (name, contents) in files {
+            let file_path = input.join(name);
+            std::fs::write(&file_path, contents).unwrap();
+            archive.add_file_relative_to(&file_path, &input).unwrap();
+        }
+        archive.archive_file.flush().unwrap();
+    }
+
+    // read every entry back and compare it with the file it was added from
+    pub(crate) fn assert_contents(archive: &mut FctArchive, files: &[(&str, Vec<u8>)]) {
+        let headers = archive.get_headers().clone();
+        assert_eq!(headers.len(), files.len());
+        for (index, (name, contents)) in files.iter().enumerate() {
+            assert_eq!(headers[index].file_path, PathBuf::from(name));
+            let mut read = Vec::new();
+            archive.entry_reader(index as u32).unwrap().read_to_end(&mut read).unwrap();
+            assert!(read == *contents, "contents of {} differ", name);
+        }
+    }
+
+    #[test]
+    fn dedup_stores_identical_chunks_once() {
+        let dir = test_dir("dedup");
+        let first = noise(10000, 1);
+        let mut shifted = first[..5120].to_vec();
+        shifted.extend_from_slice(&noise(3000, 2));
+        let files = vec![("first", first.clone()), ("copy", first.clone()), ("shifted", shifted), ("empty", Vec::new())];
+        let options = ArchiveOptions { dedup: true, ..Default::default() };
+        let mut archive = create_archive(&dir, 512, &options, &files);
+        assert_contents(&mut archive, &files);
+        let headers = archive.get_headers().clone();
+        assert_eq!(headers[0].stored_chunk_count, headers[0].data_chunk_count());
+        // the copy only references the chunks of the first file, the shifted file shares its first ten chunks
+        assert_eq!(headers[1].stored_chunk_count, 0);
+        assert_eq!(headers[1].chunk_refs, headers[0].chunk_refs);
+        assert_eq!(headers[2].chunk_refs[..10], headers[0].chunk_refs[..10]);
+        assert_eq!(headers[2].stored_chunk_count, headers[2].data_chunk_count() - 10);
+
+        // chunks stored before the archive was opened again are found as well
+        add_files(&mut archive, &dir, &[("again", first)]);
+        let mut archive = FctArchive::open(&dir.join("test.fct")).unwrap();
+        assert_eq!(archive.get_headers()[4].stored_chunk_count, 0);
+        let mut files = files;
+        files.push(("again", files[0].1.clone()));
+        assert_contents(&mut archive, &files);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dedup_savings_are_reported_and_kept_through_removal() {
+        let dir = test_dir("dedup-savings");
+        let input = dir.join("input");
+        let files = vec![("first", noise(10000, 1)), ("copy", noise(10000, 1)), ("other", noise(700, 2))];
+        for (name, contents) in &files {
+            std::fs::write(input.join(name), contents).unwrap();
+        }
+        let archive_path = dir.join("test.fct");
+        let mut archive = FctArchive::create_with_options(&archive_path, 512, &ArchiveOptions { dedup: true, ..Default::default() }).unwrap();
+        // the chunks are looked up while adding, so a batch finds the duplicates within itself
+        let paths: Vec<PathBuf> = files.iter().map(|(name, _)| input.join(name)).collect();
+        assert!(archive.add_files(&paths).is_empty());
+        let stats = archive.dedup_stats();
+        assert_eq!((stats.referenced_chunks, stats.stored_chunks, stats.saved_bytes), (42, 22, 20 * 512));
+
+        // removing the entry that stored the chunks moves them to the copy referencing them
+        archive.remove_files(&[0]).unwrap();
+        let stats = archive.dedup_stats();
+        assert_eq!((stats.referenced_chunks, stats.stored_chunks, stats.saved_bytes), (22, 22, 0));
+        assert_eq!(archive.get_headers()[0].stored_chunk_count, 20);
+        let mut read = Vec::new();
+        archive.entry_reader(0).unwrap().read_to_end(&mut read).unwrap();
+        assert!(read == files[1].1);
+        assert!(archive.verify().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sparse_leaves_out_zero_chunks() {
+        let dir = test_dir("sparse");
+        let mut middle = noise(1000, 3);
+        middle.resize(7000, 0);
+        middle.extend_from_slice(&noise(1000, 4));
+        let files = vec![("middle", middle), ("zeros", vec![0u8; 5000]), ("data", noise(3000, 5))];
+        let options = ArchiveOptions { sparse: true, ..Default::default() };
+        let mut archive = create_archive(&dir, 512, &options, &files);
+        assert_contents(&mut archive, &files);
+        let headers = archive.get_headers().clone();
+        // the zeros from byte 1024 to byte 6656 fill eleven chunks
+        assert_eq!(headers[0].hole_count(), 11);
+        assert_eq!(headers[1].hole_count(), headers[1].data_chunk_count());
+        assert_eq!(headers[2].hole_count(), 0);
+
+        // extracted files hold the zeros again
+        let output = dir.join("output");
+        assert!(archive.extract_files(&output, &mut Vec::new()).is_empty());
+        for (name, contents) in &files {
+            assert!(std::fs::read(output.join(name)).unwrap() == *contents, "extracted {} differs", name);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sparse_files_are_read_around_their_holes() {
+        let dir = test_dir("sparse-holes");
+        // a file with holes on disk, whose data regions tell which chunks need to be read
+        let file_path = dir.join("input").join("holes");
+        let mut file = File::create(&file_path).unwrap();
+        file.set_len(1 << 20).unwrap();
+        for offset in [4096u64, 503800, (1 << 20) - 100] {
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.write_all(&noise(100, offset as u32)).unwrap();
+        }
+        drop(file);
+        let contents = std::fs::read(&file_path).unwrap();
+        let options = ArchiveOptions { sparse: true, dedup: true, ..Default::default() };
+        let archive_path = dir.join("test.fct");
+        let mut archive = FctArchive::create_with_options(&archive_path, 4096, &options).unwrap();
+        archive.add_file_relative_to(&file_path, &dir.join("input")).unwrap();
+        drop(archive);
+        let mut archive = FctArchive::open(&archive_path).unwrap();
+        assert_contents(&mut archive, &[("holes", contents)]);
+        let header = &archive.get_headers()[0];
+        // the data at 503800 crosses into a second chunk
+        assert_eq!(header.data_chunk_count() - header.hole_count(), 4);
+        assert_eq!(header.stored_chunk_count, 4);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn extracted_sparse_files_keep_their_holes() {
+        use std::os::unix::fs::{FileExt, MetadataExt};
+        let dir = test_dir("sparse-extract");
+        // data at the start and in the middle, and a hole up to the end that no chunk is written for
+        let file_path = dir.join("input").join("holes");
+        let file = File::create(&file_path).unwrap();
+        file.set_len(4 << 20).unwrap();
+        file.write_all_at(&noise(5000, 1), 0).unwrap();
+        file.write_all_at(&noise(100, 2), 2 << 20).unwrap();
+        let regions = fs_operations::data_regions(&file).unwrap();
+        assert!(regions.iter().map(|(_, length)| length).sum::<u64>() < 1 << 20);
+        assert!(regions.iter().any(|(offset, length)| *offset <= 2 << 20 && offset + length >= (2 << 20) + 100));
+        drop(file);
+
+        let contents = std::fs::read(&file_path).unwrap();
+        let mut archive = FctArchive::create_with_options(&dir.join("test.fct"), 4096, &ArchiveOptions { sparse: true, ..Default::default() }).unwrap();
+        archive.add_file_relative_to(&file_path, &dir.join("input")).unwrap();
+        let mut archive = FctArchive::open(&dir.join("test.fct")).unwrap();
+        assert_eq!(archive.get_headers()[0].data_chunk_count() - archive.get_headers()[0].hole_count(), 3);
+
+        // the holes are left unwritten, which also holds when a longer file is extracted over
+        let output = dir.join("output");
+        std::fs::create_dir_all(&output).unwrap();
+        std::fs::write(output.join("holes"), vec![1u8; 5 << 20]).unwrap();
+        assert!(archive.extract_files(&output, &mut Vec::new()).is_empty());
+        let metadata = std::fs::metadata(output.join("holes")).unwrap();
+        assert_eq!(metadata.len(), 4 << 20);
+        assert!(metadata.blocks() * 512 < 1 << 20);
+        assert!(std::fs::read(output.join("holes")).unwrap() == contents);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tail_pack_shares_tail_blocks() {
+        let dir = test_dir("tail");
+        let files = vec![
+            ("first", noise(1100, 6)),
+            ("second", noise(300, 7)),
+            ("third", noise(200, 8)),
+            ("whole", noise(1024, 9)),
+            ("empty", Vec::new())
+        ];
+        let options = ArchiveOptions { tail_pack: true, ..Default::default() };
+        let mut archive = create_archive(&dir, 512, &options, &files);
+        assert_contents(&mut archive, &files);
+        let headers = archive.get_headers().clone();
+        // the third tail does not fit behind the first two anymore and starts a new block
+        let places: Vec<_> = headers[..3].iter().map(|header| (header.has_packed_tail(), header.tail_block, header.tail_offset)).collect();
+        assert_eq!(places, vec![(true, 0, 0), (true, 0, 76), (true, 1, 0)]);
+        assert!(!headers[3].has_packed_tail() && !headers[4].has_packed_tail());
+        assert_eq!(archive.layout.tail_blocks.len(), 2);
+
+        // tails added after opening the archive again continue the last block
+        add_files(&mut archive, &dir, &[("fourth", noise(100, 10))]);
+        let mut archive = FctArchive::open(&dir.join("test.fct")).unwrap();
+        let fourth = archive.get_headers()[5].clone();
+        assert_eq!((fourth.tail_block, fourth.tail_offset), (1, 200));
+        let mut files = files;
+        files.push(("fourth", noise(100, 10)));
+        assert_contents(&mut archive, &files);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn packed_tails_are_read_at_any_position_and_repacked_on_removal() {
+        let dir = test_dir("tail-access");
+        let files = vec![
+            ("first", noise(1100, 1)),
+            ("second", noise(300, 2)),
+            ("third", noise(400, 3)),
+            ("fourth", noise(1124, 4))
+        ];
+        let mut archive = create_archive(&dir, 512, &ArchiveOptions { tail_pack: true, ..Default::default() }, &files);
+        let places: Vec<_> = archive.get_headers().iter().map(|header| (header.tail_block, header.tail_offset)).collect();
+        assert_eq!(places, vec![(0, 0), (0, 76), (1, 0), (1, 400)]);
+
+        // reads starting in the last whole chunk run on into the packed tail
+        for (index, (_, contents)) in files.iter().enumerate() {
+            let mut reader = archive.entry_reader(index as u32).unwrap();
+            for start in [0, contents.len() / 2, contents.len().saturating_sub(100), contents.len() - 1] {
+                reader.seek(SeekFrom::Start(start as u64)).unwrap();
+                let mut read = Vec::new();
+                reader.read_to_end(&mut read).unwrap();
+                assert!(read == contents[start..], "{} read from {} differs", files[index].0, start);
+            }
+        }
+        let output = dir.join("output");
+        assert!(matches!(archive.extract_file(output.clone(), 1, &output, OverwritePolicy::Fail), Ok(ExtractAction::Extracted)));
+        assert!(std::fs::read(output.join("second")).unwrap() == files[1].1);
+
+        // removing entries packs the remaining tails anew, without the gaps left behind
+        archive.remove_files(&[1, 2]).unwrap();
+        let mut archive = FctArchive::open(&dir.join("test.fct")).unwrap();
+        let places: Vec<_> = archive.get_headers().iter().map(|header| (header.tail_block, header.tail_offset)).collect();
+        assert_eq!(places, vec![(0, 0), (0, 76)]);
+        assert_eq!(archive.layout.tail_blocks.len(), 1);
+        assert!(archive.verify().is_empty());
+        assert_contents(&mut archive, &[files[0].clone(), files[3].clone()]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tail_pack_combines_with_all_features() {
+        let dir = test_dir("tail-all");
+        let mut sparse = vec![0u8; 20000];
+        sparse.extend_from_slice(&noise(333, 11));
+        let files = vec![
+            ("small", noise(700, 12)),
+            ("copy", noise(700, 12)),
+            ("sparse", sparse),
+            ("large", noise(300000, 13)),
+            ("exact", noise(4096, 14))
+        ];
+        let options = ArchiveOptions { dedup: true, sparse: true, tail_pack: true, entry_chunk_size: true, modified_time: true, ..Default::default() };
+        let mut archive = create_archive(&dir, 1024, &options, &files);
+        assert_contents(&mut archive, &files);
+        let output = dir.join("output");
+        assert!(archive.extract_files(&output, &mut Vec::new()).is_empty());
+        for (name, contents) in &files {
+            assert!(std::fs::read(output.join(name)).unwrap() == *contents, "extracted {} differs", name);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tails_bigger_than_the_archive_chunks_stay_with_their_entry() {
+        // the hole bitmap and chunk references of such entries cover the tail, and the next header follows them
+        let dir = test_dir("tail-entry-chunk-size");
+        let files = vec![("big", noise(70001, 1)), ("small", noise(10, 2)), ("after", noise(3000, 3))];
+        let options = ArchiveOptions { sparse: true, tail_pack: true, entry_chunk_size: true, ..Default::default() };
+        let mut archive = create_archive(&dir, 1024, &options, &files);
+        assert!(archive.get_headers()[0].last_chunk_size > 1024);
+        assert!(!archive.get_headers()[0].has_packed_tail());
+        assert_contents(&mut archive, &files);
+
+        let options = ArchiveOptions { dedup: true, sparse: true, tail_pack: true, entry_chunk_size: true, ..Default::default() };
+        let input = dir.join("input");
+        let archive_path = dir.join("dedup.fct");
+        let mut archive = FctArchive::create_with_options(&archive_path, 1024, &options).unwrap();
+        archive.add_file_with_chunk_size(&input.join("big"), Some(4000)).unwrap();
+        archive.add_file_with_chunk_size(&input.join("small"), None).unwrap();
+        archive.archive_file.flush().unwrap();
+        let mut archive = FctArchive::open(&archive_path).unwrap();
+        assert_eq!(archive.get_headers().len(), 2);
+        assert_eq!(archive.get_headers()[0].chunk_size, 4000);
+        for (index, (_, contents)) in files[..2].iter().enumerate() {
+            let mut read = Vec::new();
+            archive.entry_reader(index as u32).unwrap().read_to_end(&mut read).unwrap();
+            assert!(read == *contents);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // sizes of the volumes of a split archive
+    fn volume_sizes(archive_path: &Path) -> Vec<u64> {
+        storage::archive_files(archive_path).iter().map(|path| std::fs::metadata(path).unwrap().len()).collect()
+    }
+
+    #[test]
+    fn volumes_split_archives_between_chunks() {
+        let dir = test_dir("volumes");
+        let files = vec![
+            ("first", noise(20000, 15)),
+            ("second", noise(10000, 16)),
+            ("small", noise(100, 17)),
+            ("zeros", vec![0u8; 3000])
+        ];
+        for options in [
+            ArchiveOptions { volume_size: Some(4096), ..Default::default() },
+            ArchiveOptions { volume_size: Some(4096), dedup: true, sparse: true, tail_pack: true, ..Default::default() }
+        ] {
+            let archive_path = dir.join("test.fct");
+            let mut archive = create_archive(&dir, 512, &options, &files);
+            assert_contents(&mut archive, &files);
+            let sizes = volume_sizes(&archive_path);
+            assert!(sizes.len() > 1);
+            assert!(sizes.iter().all(|size| *size <= 4096));
+            assert_eq!(sizes.len(), archive.archive_file.get_ref().volume_count());
+
+            // removing entries leaves no volumes of the bigger archive behind
+            archive.remove_files(&[0]).unwrap();
+            let mut archive = FctArchive::open(&archive_path).unwrap();
+            assert_contents(&mut archive, &files[1..]);
+            let remaining = volume_sizes(&archive_path);
+            assert!(remaining.len() < sizes.len());
+            assert!(!storage::volume_path(&archive_path, remaining.len()).exists());
+            drop(archive);
+            storage::remove_archive(&archive_path).unwrap();
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn volume_sets_are_opened_verified_and_extracted_whole() {
+        let dir = test_dir("volume-set");
+        let files = vec![("first", noise(20000, 1)), ("second", noise(9000, 2)), ("small", noise(100, 3))];
+        let archive_path = dir.join("test.fct");
+        let archive = create_archive(&dir, 512, &ArchiveOptions { volume_size: Some(4096), ..Default::default() }, &files);
+        let volumes = storage::archive_files(&archive_path);
+        assert!(volumes.len() > 1);
+        assert!(!archive_path.exists());
+        for (index, volume) in volumes.iter().enumerate() {
+            assert_eq!(*volume, dir.join(format!("test.fct.{:03}", index + 1)));
+        }
+
+        // entries run on into the next volume, but every chunk lies within one of them
+        let storage = archive.archive_file.get_ref();
+        let mut spanning = false;
+        for header in archive.headers.iter() {
+            for extent in header.extents(&archive.layout).unwrap() {
+                let offset = match extent.source {
+                    ExtentSource::Archive(offset) => offset,
+                    ExtentSource::Zero => continue
+                };
+                spanning |= storage.file_at(offset).unwrap().2 < extent.length;
+                for chunk_start in (offset..offset + extent.length).step_by(512) {
+                    assert!(storage.file_at(chunk_start).unwrap().2 >= std::cmp::min(512, offset + extent.length - chunk_start));
+                }
+            }
+        }
+        assert!(spanning);
+
+        // the set is found through any of its names, and reads like a single archive
+        let mut archive = FctArchive::open(&volumes[0]).unwrap();
+        assert_eq!(archive.archive_path, archive_path);
+        assert!(archive.verify().is_empty());
+        let output = dir.join("output");
+        assert!(archive.extract_files(&output, &mut Vec::new()).is_empty());
+        for (name, contents) in &files {
+            assert!(std::fs::read(output.join(name)).unwrap() == *contents, "extracted {} differs", name);
+        }
+        drop(archive);
+
+        // a missing volume shows up in the verification
+        std::fs::remove_file(volumes.last().unwrap()).unwrap();
+        let mut archive = FctArchive::open(&archive_path).unwrap();
+        assert_eq!(archive.verify().first().map(|problem| problem.1), Some("Archive is truncated"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // archive the files, extract the first entry over an existing file with the policy and report what happened
+    fn extract_over(name: &str, options: &ArchiveOptions, existing: &[u8], policy: OverwritePolicy) -> (PathBuf, ExtractReport) {
+        let dir = test_dir(name);
+        let mut archive = create_archive(&dir, 512, options, &[("entry", noise(100, 31))]);
+        let output = dir.join("output");
+        std::fs::create_dir_all(&output).unwrap();
+        std::fs::write(output.join("entry"), existing).unwrap();
+        let extract_options = ExtractOptions { overwrite: policy, ..Default::default() };
+        let report = archive.extract_files_with_options(&output, &mut vec![0], &extract_options).remove(0);
+        (dir, report)
+    }
+
+    #[test]
+    fn overwrite_truncates_longer_files() {
+        for options in [ArchiveOptions::default(), ArchiveOptions { sparse: true, tail_pack: true, ..Default::default() }] {
+            let (dir, report) = extract_over("overwrite", &options, &noise(5000, 32), OverwritePolicy::Overwrite);
+            assert_eq!(report.action, ExtractAction::Overwritten);
+            assert_eq!(std::fs::read(dir.join("output").join("entry")).unwrap(), noise(100, 31));
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn overwrite_policies_report_their_action() {
+        let existing = noise(50, 33);
+        let (dir, report) = extract_over("policy-skip", &ArchiveOptions::default(), &existing, OverwritePolicy::Skip);
+        assert_eq!(report.action, ExtractAction::Skipped);
+        assert_eq!(std::fs::read(dir.join("output").join("entry")).unwrap(), existing);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let (dir, report) = extract_over("policy-fail", &ArchiveOptions::default(), &existing, OverwritePolicy::Fail);
+        assert_eq!(report.action, ExtractAction::Failed("File already exists"));
+        assert_eq!(std::fs::read(dir.join("output").join("entry")).unwrap(), existing);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // renamed entries take the first free suffix next to the existing file
+        let (dir, report) = extract_over("policy-rename", &ArchiveOptions::default(), &existing, OverwritePolicy::Rename);
+        let renamed = dir.join("output").join("entry.1");
+        assert_eq!(report.action, ExtractAction::Renamed(renamed.clone()));
+        assert_eq!(std::fs::read(&renamed).unwrap(), noise(100, 31));
+        assert_eq!(std::fs::read(dir.join("output").join("entry")).unwrap(), existing);
+        let mut archive = FctArchive::open(&dir.join("test.fct")).unwrap();
+        let action = archive.extract_file(dir.join("output"), 0, &dir.join("output"), OverwritePolicy::Rename).unwrap();
+        assert_eq!(action, ExtractAction::Renamed(dir.join("output").join("entry.2")));
+        assert_eq!(archive.extract_file(dir.join("output"), 0, &dir.join("output"), OverwritePolicy::Skip).unwrap(), ExtractAction::Skipped);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // without modification times in the archive, the entry is never newer
+        let (dir, report) = extract_over("policy-newer-unknown", &ArchiveOptions::default(), &existing, OverwritePolicy::OverwriteIfNewer);
+        assert_eq!(report.action, ExtractAction::Skipped);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn overwrite_if_newer_compares_modification_times() {
+        let options = ArchiveOptions { modified_time: true, ..Default::default() };
+        let dir = test_dir("policy-newer");
+        let mut archive = create_archive(&dir, 512, &options, &[("entry", noise(100, 31))]);
+        let output = dir.join("output");
+        std::fs::create_dir_all(&output).unwrap();
+        let extract_options = ExtractOptions { overwrite: OverwritePolicy::OverwriteIfNewer, ..Default::default() };
+        let entry_time = UNIX_EPOCH + Duration::from_secs(archive.get_headers()[0].modified);
+        for (file_time, action) in [
+            (entry_time + Duration::from_secs(60), ExtractAction::Skipped),
+            (entry_time - Duration::from_secs(60), ExtractAction::Overwritten)
+        ] {
+            let file = File::create(output.join("entry")).unwrap();
+            file.set_modified(file_time).unwrap();
+            drop(file);
+            let report = archive.extract_files_with_options(&output, &mut vec![0], &extract_options).remove(0);
+            assert_eq!(report.action, action);
+            assert_eq!(std::fs::read(output.join("entry")).unwrap().len(), if action == ExtractAction::Skipped {0} else {100});
+        }
+        // the overwritten file takes the entry's time
+        assert_eq!(std::fs::metadata(output.join("entry")).unwrap().modified().unwrap(), entry_time);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_archive_headers_are_errors() {
+        let dir = test_dir("truncated-header");
+        let path = dir.join("test.fct");
+        let volumes = (file_parser::FEATURE_MULTI_VOLUME | file_parser::FEATURE_ENTRY_CHUNK_SIZE).to_le_bytes();
+        let extended = [EXTENDED_ARCHIVE_HEADER_MAGIC.as_bytes(), &1024u16.to_le_bytes(), &volumes, &[1, 2, 3]].concat();
+        for (contents, error) in [
+            (b"".to_vec(), "Could not read archive header"),
+            (b"FC".to_vec(), "Could not read archive header"),
+            (b"FCT\x00".to_vec(), "Could not read archive header"),
+            (b"ABC\x00\x04".to_vec(), "Invalid archive header"),
+            (extended[..6].to_vec(), "Invalid archive header"),
+            (extended.clone(), "Invalid archive header")
+        ] {
+            std::fs::write(&path, &contents).unwrap();
+            assert_eq!(FctArchive::open(&path).err(), Some(error), "{:?}", contents);
+        }
+    }
+
+    #[test]
+    fn unreadable_entry_headers_are_errors() {
+        // a reader failing with something other than an interruption
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("damaged"))
+            }
+        }
+        assert_eq!(FileParser::from_archive_with_features(&mut Failing, file_parser::FEATURE_DEDUP, 1024).err(), Some("Failed to read file header data"));
+        let mut short: &[u8] = &[1, 0, 0, 0];
+        assert_eq!(FileParser::from_archive_with_features(&mut short, 0, 1024).err(), Some("File header is incomplete"));
+        let mut empty: &[u8] = &[];
+        assert_eq!(FileParser::from_archive(&mut empty).err(), Some("File is empty or EOF reached"));
+    }
+
+    // headers holding only the stored path, and the sizes of the files, as recommend_chunk_size measures them
+    fn measured(files: &[(&str, Vec<u8>)]) -> Vec<(FileParser, u64)> {
+        files.iter().map(|(name, contents)| (FileParser { file_path: PathBuf::from(name), ..Default::default() }, contents.len() as u64)).collect()
+    }
+
+    #[test]
+    fn chunk_size_recommendation_counts_padding_headers_and_tails() {
+        let files: Vec<(String, Vec<u8>)> = (0..10).map(|i| (format!("f{}", i), noise(1000, i))).collect();
+        let files: Vec<(&str, Vec<u8>)> = files.iter().map(|(name, contents)| (name.as_str(), contents.clone())).collect();
+        // padded to full chunks, 1024 bytes waste the least and win the tie with 512
+        let plain = FctArchive::recommend_chunk_size_for(&mut measured(&files), 0).unwrap();
+        assert_eq!(plain.chunk_size, 1024);
+        assert_eq!(plain.projected_size, 5 + 10 * (8 + 2 + 1024));
+        // packed, the ten tails fit into a single tail block of 10240 bytes
+        let tail_pack = ArchiveOptions { tail_pack: true, ..Default::default() }.to_features();
+        let packed = FctArchive::recommend_chunk_size_for(&mut measured(&files), tail_pack).unwrap();
+        assert_eq!(packed.chunk_size, 10240);
+        assert_eq!(packed.projected_size, 7 + 10 * (8 + 2 + 6) + (8 + 10240));
+        assert_eq!(FctArchive::recommend_chunk_size_for(&mut [], 0), Err("No files to choose a chunk size for"));
+    }
+
+    #[test]
+    fn projected_size_matches_the_written_archive() {
+        let files = [("a", noise(100, 1)), ("b", noise(5000, 2)), ("c", Vec::new()), ("d", noise(70000, 3)), ("e", noise(1300, 4))];
+        for options in [
+            ArchiveOptions::default(),
+            ArchiveOptions { tail_pack: true, ..Default::default() },
+            ArchiveOptions { entry_chunk_size: true, ..Default::default() },
+            ArchiveOptions { tail_pack: true, entry_chunk_size: true, modified_time: true, ..Default::default() },
+            // nothing repeats and nothing is zero, so no chunk is saved
+            ArchiveOptions { dedup: true, sparse: true, tail_pack: true, ..Default::default() }
+        ] {
+            let dir = test_dir("projected-size");
+            let recommendation = FctArchive::recommend_chunk_size_for(&mut measured(&files), options.to_features()).unwrap();
+            let mut archive = create_archive(&dir, recommendation.chunk_size, &options, &files);
+            let size = std::fs::metadata(dir.join("test.fct")).unwrap().len();
+            assert_eq!(recommendation.projected_size, size, "{:?}", options);
+            assert_eq!(archive.recommend_rechunk_size().unwrap(), recommendation);
+        }
+    }
+
+    #[test]
+    fn chunk_size_recommendation_measures_read_only_files() {
+        let dir = test_dir("recommend-readonly");
+        let read_only = dir.join("input/read-only");
+        std::fs::write(&read_only, noise(3000, 1)).unwrap();
+        let mut permissions = std::fs::metadata(&read_only).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&read_only, permissions).unwrap();
+        let missing = dir.join("input/missing");
+        let recommendation = FctArchive::recommend_chunk_size(&[read_only, missing.clone(), dir.join("input")], &ArchiveOptions::default()).unwrap();
+        assert_eq!(recommendation.chunk_size, 3072);
+        assert_eq!(recommendation.skipped_files, [missing, dir.join("input")]);
+    }
+
+    // the logical, stored, header and padding bytes of every entry
+    fn entry_sizes(stats: &ArchiveStats) -> Vec<(u64, u64, u64, u64)> {
+        stats.entries.iter().map(|entry| (entry.logical_bytes, entry.stored_bytes, entry.header_bytes, entry.padding_bytes)).collect()
+    }
+
+    #[test]
+    fn stats_count_padding_and_size_buckets() {
+        let dir = test_dir("stats-plain");
+        let files = [("a.txt", Vec::new()), ("b.txt", noise(100, 1)), ("c.bin", noise(2048, 2)), ("d", noise(3000, 3)), ("e.bin", noise(70000, 4))];
+        let stats = create_archive(&dir, 1024, &ArchiveOptions::default(), &files).stats().unwrap();
+        // every partial last chunk is padded to a full chunk
+        assert_eq!(entry_sizes(&stats), [(0, 0, 13, 0), (100, 1024, 13, 924), (2048, 2048, 13, 0), (3000, 3072, 9, 72), (70000, 70656, 13, 656)]);
+        assert_eq!((stats.entry_count, stats.logical_bytes, stats.stored_bytes, stats.header_bytes, stats.padding_bytes), (5, 75148, 76800, 5 + 61, 1652));
+        assert_eq!(stats.archive_bytes, std::fs::metadata(dir.join("test.fct")).unwrap().len());
+        assert_eq!(stats.archive_bytes, stats.header_bytes + stats.stored_bytes);
+        assert_eq!(stats.tail_block_count, 0);
+
+        let buckets: Vec<(Option<u64>, u64, u64, u64)> = stats.histogram.iter().map(|b| (b.below, b.entry_count, b.logical_bytes, b.padding_bytes)).collect();
+        assert_eq!(buckets, [
+            (Some(1), 1, 0, 0),
+            (Some(1 << 10), 1, 100, 924),
+            (Some(16 << 10), 2, 5048, 72),
+            (Some(64 << 10), 0, 0, 0),
+            (Some(1 << 20), 1, 70000, 656),
+            (Some(16 << 20), 0, 0, 0),
+            (Some(1 << 30), 0, 0, 0),
+            (None, 0, 0, 0)
+        ]);
+        let extensions: Vec<(&str, u64, u64, u64)> = stats.extensions.iter().map(|e| (e.extension.as_str(), e.entry_count, e.logical_bytes, e.padding_bytes)).collect();
+        assert_eq!(extensions, [("bin", 2, 72048, 656), ("", 1, 3000, 72), ("txt", 2, 100, 924)]);
+        let largest: Vec<&Path> = stats.largest.iter().map(|entry| entry.path.as_path()).collect();
+        assert_eq!(largest, ["e.bin", "d", "c.bin", "b.txt", "a.txt"].map(Path::new));
+    }
+
+    #[test]
+    fn stats_count_tail_blocks_holes_and_shared_chunks() {
+        let dir = test_dir("stats-tails");
+        let files = [("b.txt", noise(100, 1)), ("d", noise(3000, 3)), ("e.bin", noise(70000, 4))];
+        let stats = create_archive(&dir, 1024, &ArchiveOptions { tail_pack: true, ..Default::default() }, &files).stats().unwrap();
+        // the tails of b.txt and d do not fit into one block, the one of e.bin does not fit next to d's either
+        assert_eq!(entry_sizes(&stats), [(100, 0, 19, 0), (3000, 2048, 15, 0), (70000, 69632, 19, 0)]);
+        assert_eq!(stats.tail_block_count, 3);
+        assert_eq!(stats.padding_bytes, 3 * 1024 - (100 + 952 + 368));
+        assert_eq!(stats.stored_bytes, 2048 + 69632 + 3 * 1024);
+        assert_eq!(stats.header_bytes, 7 + 19 + 15 + 19 + 3 * 8);
+        assert_eq!(stats.archive_bytes, stats.header_bytes + stats.stored_bytes);
+
+        // a repeated file stores nothing, and a zero chunk is a hole, also where it is the partial last one
+        let dir = test_dir("stats-dedup");
+        let files = [("c", noise(2048, 2)), ("c2", noise(2048, 2)), ("z", vec![0; 1500]), ("d", noise(1500, 3))];
+        let stats = create_archive(&dir, 1024, &ArchiveOptions { dedup: true, sparse: true, ..Default::default() }, &files).stats().unwrap();
+        assert_eq!(entry_sizes(&stats), [(2048, 2048, 22, 0), (2048, 0, 23, 0), (1500, 0, 22, 0), (1500, 2048, 22, 548)]);
+        assert_eq!(stats.archive_bytes, stats.header_bytes + stats.stored_bytes);
+    }
+
+    // names of the files left in the test directory, besides the input folder
+    fn leftover_files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name != "input")
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn rechunk_keeps_contents_and_modification_times() {
+        let files = [("empty", Vec::new()), ("small", noise(100, 1)), ("a", noise(5000, 2)), ("zeros", vec![0; 9000]), ("a2", noise(5000, 2)), ("big", noise(70000, 3))];
+        for (from, to) in [(1024, 4096), (4096, 1000)] {
+            for options in [
+                ArchiveOptions::default(),
+                ArchiveOptions { modified_time: true, ..Default::default() },
+                ArchiveOptions { sparse: true, tail_pack: true, entry_chunk_size: true, modified_time: true, ..Default::default() },
+                ArchiveOptions { dedup: true, sparse: true, tail_pack: true, entry_chunk_size: true, modified_time: true, ..Default::default() }
+            ] {
+                let dir = test_dir("rechunk");
+                let mut archive = create_archive(&dir, from, &options, &files);
+                let modified: Vec<u64> = archive.get_headers().iter().map(|header| header.modified).collect();
+                assert_eq!(modified.iter().all(|m| *m != 0), options.modified_time);
+                let target_path = dir.join("rechunked.fct");
+                drop(archive.rechunk(&target_path, to).unwrap());
+
+                let mut rechunked = FctArchive::open(&target_path).unwrap();
+                assert_eq!((rechunked.chunk_size, rechunked.options()), (to, options));
+                assert_contents(&mut rechunked, &files);
+                for (header, (_, contents)) in rechunked.get_headers().iter().zip(&files) {
+                    // per-entry chunk sizes are chosen again for the new default
+                    let expected = match options.entry_chunk_size {
+                        true => FctArchive::entry_chunk_size(options.to_features(), to, contents.len() as u64),
+                        false => to
+                    };
+                    assert_eq!(header.chunk_size, expected, "{:?}", options);
+                }
+                assert_eq!(rechunked.get_headers().iter().map(|header| header.modified).collect::<Vec<u64>>(), modified);
+                drop(rechunked);
+
+                // rechunking into the archive itself replaces it
+                let rechunked = archive.rechunk(&dir.join("test.fct"), to).unwrap();
+                assert_eq!((archive.chunk_size, rechunked.chunk_size), (to, to));
+                assert_contents(&mut archive, &files);
+                assert_eq!(archive.get_headers().iter().map(|header| header.modified).collect::<Vec<u64>>(), modified);
+                assert_eq!(leftover_files(&dir), ["rechunked.fct", "test.fct"]);
+            }
+        }
+    }
+
+    #[test]
+    fn failed_rechunk_leaves_no_partial_archive() {
+        let files = [("a", noise(5000, 1)), ("b", noise(5000, 2))];
+        for options in [ArchiveOptions::default(), ArchiveOptions { tail_pack: true, ..Default::default() }] {
+            let dir = test_dir("rechunk-failed");
+            let mut archive = create_archive(&dir, 1024, &options, &files);
+            archive.get_headers();
+            // cut off the data of the last entry after its header has been read
+            let archive_path = dir.join("test.fct");
+            let size = std::fs::metadata(&archive_path).unwrap().len();
+            File::options().write(true).open(&archive_path).unwrap().set_len(size - 2000).unwrap();
+
+            assert!(archive.rechunk(&archive_path, 4096).is_err());
+            assert_eq!(std::fs::metadata(&archive_path).unwrap().len(), size - 2000);
+            assert!(archive.rechunk(&dir.join("rechunked.fct"), 4096).is_err());
+            assert_eq!(leftover_files(&dir), ["test.fct"]);
+        }
+    }
+
+    // an archive in its own folder of the test directory, so that several can be created side by side
+    fn create_archive_in(dir: &Path, name: &str, chunk_size: u16, options: &ArchiveOptions, files: &[(&str, Vec<u8>)]) -> FctArchive {
+        let archive_dir = dir.join(name);
+        std::fs::create_dir_all(archive_dir.join("input")).unwrap();
+        create_archive(&archive_dir, chunk_size, options, files)
+    }
+
+    fn merge_actions(reports: &[MergeReport]) -> Vec<(&Path, MergeAction)> {
+        reports.iter().map(|report| (report.path.as_path(), report.action.clone())).collect()
+    }
+
+    #[test]
+    fn merge_policies_resolve_conflicts() {
+        let (a, b1, b2, c2, c3) = (noise(3000, 1), noise(1500, 2), noise(2500, 3), noise(100, 4), noise(5000, 5));
+        let options = ArchiveOptions::default();
+        let merge = |policy: MergeConflictPolicy| {
+            let dir = test_dir("merge-policies");
+            let mut target = create_archive_in(&dir, "target", 1024, &options, &[("a", a.clone()), ("b.txt", b1.clone())]);
+            let mut sources = [
+                create_archive_in(&dir, "first", 1024, &options, &[("b.txt", b2.clone()), ("c", c2.clone())]),
+                create_archive_in(&dir, "second", 1024, &options, &[("c", c3.clone())])
+            ];
+            let before = std::fs::read(dir.join("target/test.fct")).unwrap();
+            let result = target.merge_from(&mut sources, policy).map(|reports| merge_actions(&reports).into_iter().map(|(path, action)| (path.to_path_buf(), action)).collect::<Vec<_>>());
+            (dir, before, target, result)
+        };
+
+        let (dir, before, _, result) = merge(MergeConflictPolicy::Fail);
+        assert_eq!(result.err(), Some("Entry already exists in the target archive"));
+        assert_eq!(std::fs::read(dir.join("target/test.fct")).unwrap(), before);
+
+        let (_, _, mut target, result) = merge(MergeConflictPolicy::Skip);
+        assert_eq!(result.unwrap(), [(PathBuf::from("b.txt"), MergeAction::Skipped), (PathBuf::from("c"), MergeAction::Added), (PathBuf::from("c"), MergeAction::Skipped)]);
+        assert_contents(&mut target, &[("a", a.clone()), ("b.txt", b1.clone()), ("c", c2.clone())]);
+
+        let (_, _, mut target, result) = merge(MergeConflictPolicy::Rename);
+        assert_eq!(result.unwrap(), [
+            (PathBuf::from("b.txt"), MergeAction::Renamed(PathBuf::from("b.1.txt"))),
+            (PathBuf::from("c"), MergeAction::Added),
+            (PathBuf::from("c"), MergeAction::Renamed(PathBuf::from("c.1")))
+        ]);
+        assert_contents(&mut target, &[("a", a.clone()), ("b.txt", b1.clone()), ("b.1.txt", b2.clone()), ("c", c2.clone()), ("c.1", c3.clone())]);
+
+        // the entry of the first source is replaced by the one of the second before it is copied
+        let (_, _, mut target, result) = merge(MergeConflictPolicy::Replace);
+        assert_eq!(result.unwrap(), [(PathBuf::from("b.txt"), MergeAction::Replaced), (PathBuf::from("c"), MergeAction::Skipped), (PathBuf::from("c"), MergeAction::Replaced)]);
+        assert_contents(&mut target, &[("a", a.clone()), ("b.txt", b2.clone()), ("c", c3.clone())]);
+
+        let (_, _, mut target, result) = merge(MergeConflictPolicy::KeepBoth);
+        assert_eq!(result.unwrap().iter().filter(|(_, action)| *action == MergeAction::Added).count(), 3);
+        assert_contents(&mut target, &[("a", a), ("b.txt", b1), ("b.txt", b2), ("c", c2), ("c", c3)]);
+    }
+
+    #[test]
+    fn merge_copies_stored_data_or_cuts_it_anew() {
+        let files = [("a", noise(3000, 1)), ("zeros", vec![0; 5000]), ("a2", noise(3000, 1)), ("b", noise(70000, 2))];
+        let plain = ArchiveOptions::default();
+        let extended = ArchiveOptions { dedup: true, sparse: true, tail_pack: true, modified_time: true, ..Default::default() };
+        // (target chunk size and options, source chunk size and options), the first pair copies the stored data unchanged
+        for ((target_chunk_size, target_options), (source_chunk_size, source_options)) in [
+            ((1024, plain), (1024, plain)),
+            ((1024, ArchiveOptions { sparse: true, ..Default::default() }), (1024, ArchiveOptions { sparse: true, ..Default::default() })),
+            ((1024, plain), (4096, plain)),
+            ((1000, plain), (1024, extended)),
+            ((4096, extended), (1024, extended)),
+            ((1024, extended), (1024, extended)),
+            ((1024, ArchiveOptions { volume_size: Some(20000), ..Default::default() }), (1024, extended))
+        ] {
+            let dir = test_dir("merge-copy");
+            let existing = [("existing", noise(1500, 3))];
+            let mut target = create_archive_in(&dir, "target", target_chunk_size, &target_options, &existing);
+            let mut sources = [create_archive_in(&dir, "source", source_chunk_size, &source_options, &files)];
+            let modified: Vec<u64> = sources[0].get_headers().iter().map(|header| header.modified).collect();
+            target.merge_from(&mut sources, MergeConflictPolicy::Fail).unwrap();
+            drop(target);
+
+            let mut merged = FctArchive::open(&storage::archive_base_path(&dir.join("target/test.fct"))).unwrap();
+            assert_contents(&mut merged, &[existing[0].clone(), files[0].clone(), files[1].clone(), files[2].clone(), files[3].clone()]);
+            // modification times survive if both archives record them
+            let merged_modified: Vec<u64> = merged.get_headers()[1..].iter().map(|header| header.modified).collect();
+            match target_options.modified_time && source_options.modified_time {
+                true => assert_eq!(merged_modified, modified),
+                false => assert!(merged_modified.iter().all(|m| *m == 0))
+            }
+            if target_options.dedup {
+                assert_eq!(merged.get_headers()[3].stored_chunk_count, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn failed_merge_leaves_the_archive_unchanged() {
+        let files = [("a", noise(3000, 1)), ("b", noise(5000, 2))];
+        for (options, chunk_size) in [
+            (ArchiveOptions::default(), 1024),
+            (ArchiveOptions::default(), 4096),
+            (ArchiveOptions { dedup: true, tail_pack: true, ..Default::default() }, 1024),
+            (ArchiveOptions { volume_size: Some(4000), ..Default::default() }, 1024)
+        ] {
+            let dir = test_dir("merge-failed");
+            let mut target = create_archive_in(&dir, "target", chunk_size, &options, &[("existing", noise(1500, 3))]);
+            let mut sources = [
+                create_archive_in(&dir, "first", 1024, &ArchiveOptions::default(), &[("c", noise(9000, 4))]),
+                create_archive_in(&dir, "second", 1024, &ArchiveOptions::default(), &files)
+            ];
+            // cut off the data of the last entry of the second source after its header has been read
+            sources[1].get_headers();
+            let source_path = dir.join("second/test.fct");
+            let size = std::fs::metadata(&source_path).unwrap().len();
+            File::options().write(true).open(&source_path).unwrap().set_len(size - 2000).unwrap();
+
+            let target_path = dir.join("target/test.fct");
+            let volume_count = storage::archive_files(&target_path).len();
+            let before: Vec<Vec<u8>> = storage::archive_files(&target_path).iter().map(|path| std::fs::read(path).unwrap()).collect();
+            assert!(target.merge_from(&mut sources, MergeConflictPolicy::Fail).is_err());
+            let after: Vec<Vec<u8>> = storage::archive_files(&target_path).iter().map(|path| std::fs::read(path).unwrap()).collect();
+            assert_eq!(after.len(), volume_count);
+            assert!(after == before, "{:?}", options);
+            // the archive can still be added to
+            assert_eq!(target.get_headers().len(), 1);
+            add_files(&mut target, &dir.join("target"), &[("new", noise(2000, 5))]);
+            drop(target);
+            let mut reopened = FctArchive::open(&target_path).unwrap();
+            assert_contents(&mut reopened, &[("existing", noise(1500, 3)), ("new", noise(2000, 5))]);
+        }
+    }
+
+    #[test]
+    fn sync_adds_replaces_and_removes_entries() {
+        for options in [ArchiveOptions::default(), ArchiveOptions { modified_time: true, tail_pack: true, ..Default::default() }] {
+            let dir = test_dir("sync");
+            let input = dir.join("input");
+            let current_dir = std::env::current_dir().unwrap();
+            // entries are stored relative to the current directory
+            let stored = |name: &str| fs_operations::format_path(&current_dir, &input.join(name)).unwrap();
+            for (name, contents) in [("same", noise(3000, 1)), ("changed", noise(3000, 2)), ("gone", noise(100, 3)), ("twice", noise(500, 4))] {
+                std::fs::write(input.join(name), contents).unwrap();
+            }
+            let mut archive = FctArchive::create_with_options(&dir.join("test.fct"), 1024, &options).unwrap();
+            let report = archive.sync(&input, &ExpandOptions::default()).unwrap();
+            assert_eq!(report.added, ["changed", "gone", "same", "twice"].map(stored));
+            // a second copy of the entry, which is replaced together with the first one
+            archive.add_file(&input.join("twice")).unwrap();
+
+            // the same size, so without modification times the contents tell the change apart
+            std::fs::write(input.join("changed"), noise(3000, 20)).unwrap();
+            std::fs::remove_file(input.join("gone")).unwrap();
+            std::fs::write(input.join("new"), noise(5000, 5)).unwrap();
+            std::fs::write(input.join("twice"), noise(600, 4)).unwrap();
+            // read-only files are read like any other
+            let mut permissions = std::fs::metadata(input.join("new")).unwrap().permissions();
+            permissions.set_readonly(true);
+            std::fs::set_permissions(input.join("new"), permissions).unwrap();
+            if options.modified_time {
+                let file = File::options().write(true).open(input.join("changed")).unwrap();
+                file.set_modified(std::time::SystemTime::now() + Duration::from_secs(10)).unwrap();
+            }
+            let report = archive.sync(&input, &ExpandOptions::default()).unwrap();
+            assert_eq!(report.added, [stored("new")]);
+            assert_eq!(report.replaced, ["changed", "twice"].map(stored));
+            assert_eq!(report.removed, [stored("gone")]);
+            assert_eq!((report.unchanged, report.failed.len()), (1, 0));
+
+            let mut synced = FctArchive::open(&dir.join("test.fct")).unwrap();
+            let names: Vec<PathBuf> = synced.get_headers().iter().map(|header| header.file_path.clone()).collect();
+            // kept entries come first, then the added files in the order they were found
+            assert_eq!(names, ["same", "changed", "new", "twice"].map(stored));
+            for (index, name) in ["same", "changed", "new", "twice"].iter().enumerate() {
+                let mut read = Vec::new();
+                synced.entry_reader(index as u32).unwrap().read_to_end(&mut read).unwrap();
+                assert!(read == std::fs::read(input.join(name)).unwrap(), "contents of {} differ", name);
+            }
+
+            // nothing changed, so the archive is not rewritten
+            let before = std::fs::read(dir.join("test.fct")).unwrap();
+            let report = archive.sync(&input, &ExpandOptions::default()).unwrap();
+            assert_eq!((report.added.len(), report.replaced.len(), report.removed.len(), report.unchanged), (0, 0, 0, 4));
+            assert!(std::fs::read(dir.join("test.fct")).unwrap() == before);
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn sync_keeps_entries_of_unreadable_files() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = test_dir("sync-unreadable");
+        let input = dir.join("input");
+        let current_dir = std::env::current_dir().unwrap();
+        std::fs::write(input.join("locked"), noise(100, 1)).unwrap();
+        let mut archive = FctArchive::create_with_options(&dir.join("test.fct"), 1024, &ArchiveOptions::default()).unwrap();
+        archive.sync(&input, &ExpandOptions::default()).unwrap();
+        std::fs::write(input.join("locked"), noise(200, 1)).unwrap();
+        std::fs::set_permissions(input.join("locked"), std::fs::Permissions::from_mode(0o000)).unwrap();
+        // privileged users can read the file anyway
+        if File::open(input.join("locked")).is_err() {
+            let report = archive.sync(&input, &ExpandOptions::default()).unwrap();
+            assert_eq!(report.failed, [fs_operations::format_path(&current_dir, &input.join("locked")).unwrap()]);
+            assert!(report.replaced.is_empty() && report.removed.is_empty());
+            assert_eq!(archive.get_headers()[0].logical_size(), 100);
+        }
+        std::fs::set_permissions(input.join("locked"), std::fs::Permissions::from_mode(0o644)).unwrap();
+    }
+
+    // every file of all archives with the given base path, so that split archives are compared whole
+    fn archive_bytes(archive_path: &Path) -> Vec<Vec<u8>> {
+        storage::archive_files(archive_path).iter().map(|path| std::fs::read(path).unwrap()).collect()
+    }
+
+    #[test]
+    fn parallel_adding_writes_the_same_archive() {
+        let dir = test_dir("add-parallel");
+        let input = dir.join("input");
+        let mut holes = noise(5000, 1);
+        holes.extend(vec![0; 9000]);
+        holes.extend(noise(300, 2));
+        let files = vec![
+            ("holes", holes),
+            ("first", noise(7000, 3)),
+            ("copy", noise(7000, 3)),
+            ("small", noise(10, 4)),
+            ("empty", Vec::new()),
+            // bigger than what is read ahead, so it is written straight from the file
+            ("big", noise(READ_AHEAD_FILE_SIZE as usize + 5000, 5)),
+            ("last", noise(2500, 6))
+        ];
+        for (name, contents) in &files {
+            std::fs::write(input.join(name), contents).unwrap();
+        }
+        std::fs::create_dir(input.join("directory")).unwrap();
+        // a missing file and a directory in the middle fail, the files after them are still added
+        let mut paths: Vec<PathBuf> = files.iter().map(|(name, _)| input.join(name)).collect();
+        paths.insert(3, input.join("missing"));
+        paths.insert(5, input.join("directory"));
+
+        let all_options = [
+            ArchiveOptions::default(),
+            ArchiveOptions { dedup: true, sparse: true, tail_pack: true, entry_chunk_size: true, modified_time: true, ..Default::default() },
+            ArchiveOptions { volume_size: Some(1 << 20), dedup: true, sparse: true, tail_pack: true, ..Default::default() }
+        ];
+        for (index, options) in all_options.iter().enumerate() {
+            let sequential_path = dir.join(format!("sequential-{}.fct", index));
+            let mut archive = FctArchive::create_with_options(&sequential_path, 1024, options).unwrap();
+            let sequential_failed = archive.add_files(&paths);
+            archive.archive_file.flush().unwrap();
+            drop(archive);
+            assert_eq!(sequential_failed, vec![input.join("missing"), input.join("directory")]);
+            let sequential = archive_bytes(&sequential_path);
+            for threads in [1, 2, 8] {
+                let parallel_path = dir.join(format!("parallel-{}-{}.fct", index, threads));
+                let mut archive = FctArchive::create_with_options(&parallel_path, 1024, options).unwrap();
+                let failed = archive.add_files_parallel(&paths, threads);
+                archive.archive_file.flush().unwrap();
+                drop(archive);
+                assert_eq!(failed, sequential_failed);
+                assert_eq!(archive_bytes(&parallel_path), sequential, "{} threads with options {:?} wrote another archive", threads, options);
+            }
+        }
+    }
+
+    // add the files create_archive wrote to the input folder to another archive, but from memory, which can only be
+    // copied through the buffer. The files are not written again, so that their modification times stay the same.
+    fn create_archive_from_memory(dir: &Path, name: &str, chunk_size: u16, options: &ArchiveOptions, files: &[(&str, Vec<u8>)]) -> FctArchive {
+        let (input, archive_path) = (dir.join("input"), dir.join(name));
+        let mut archive = FctArchive::create_with_options(&archive_path, chunk_size, options).unwrap();
+        for (name, contents) in files {
+            let prepared = FctArchive::prepare_file(&input.join(name), &input, None, archive.features, chunk_size).unwrap();
+            archive.add_entry(&mut Cursor::new(contents.clone()), prepared.parser, prepared.data_regions.as_ref()).unwrap();
+        }
+        archive.archive_file.flush().unwrap();
+        drop(archive);
+        FctArchive::open(&archive_path).unwrap()
+    }
+
+    #[test]
+    fn kernel_and_buffered_copies_write_the_same_archive() {
+        // the kernel copies between any offsets and stops at the end of the source
+        let dir = test_dir("copy-paths");
+        let source_contents = noise(10000, 1);
+        std::fs::write(dir.join("source"), &source_contents).unwrap();
+        std::fs::write(dir.join("target"), vec![1; 20]).unwrap();
+        let source = File::open(dir.join("source")).unwrap();
+        let target = OpenOptions::new().write(true).open(dir.join("target")).unwrap();
+        assert_eq!(fs_operations::copy_file_range(&source, 5, &target, 3, 1000).unwrap(), 1000);
+        assert_eq!(fs_operations::copy_file_range(&source, 9000, &target, 1003, 5000).unwrap(), 1000);
+        let mut expected = vec![1; 3];
+        expected.extend_from_slice(&source_contents[5..1005]);
+        expected.extend_from_slice(&source_contents[9000..]);
+        assert_eq!(std::fs::read(dir.join("target")).unwrap(), expected);
+
+        // files bigger than the copy buffer take several rounds of it
+        let files = vec![
+            ("big", noise(storage::COPY_BUFFER_SIZE + 4099, 2)),
+            ("small", noise(3000, 3)),
+            ("empty", Vec::new()),
+            ("odd", noise(storage::COPY_BUFFER_SIZE * 2 + 1, 4))
+        ];
+        // single archives are written by the kernel from files, split ones always through the buffer
+        let all_options = [
+            ArchiveOptions::default(),
+            ArchiveOptions { entry_chunk_size: true, modified_time: true, ..Default::default() },
+            ArchiveOptions { volume_size: Some(3 << 20), ..Default::default() }
+        ];
+        for (index, options) in all_options.iter().enumerate() {
+            let dir = test_dir(&format!("copy-paths-{}", index));
+            let mut from_files = create_archive(&dir, 1024, options, &files);
+            assert_contents(&mut from_files, &files);
+            let mut from_memory = create_archive_from_memory(&dir, "memory.fct", 1024, options, &files);
+            assert_contents(&mut from_memory, &files);
+            assert_eq!(archive_bytes(&dir.join("memory.fct")), archive_bytes(&dir.join("test.fct")));
+        }
+    }
+
+    #[test]
+    fn tiny_chunks_are_copied_in_whole_batches() {
+        // a run of zeros across the end of the first batch, and a file ending just after a batch
+        let mut first = noise(storage::COPY_BUFFER_SIZE - 100, 1);
+        first.extend(vec![0; 300]);
+        first.extend(noise(5000, 2));
+        let files = vec![
+            ("first", first),
+            ("batch", noise(storage::COPY_BUFFER_SIZE + 1, 3)),
+            ("tail", noise(10, 4))
+        ];
+        let cases = [
+            (1, ArchiveOptions::default()),
+            (1, ArchiveOptions { sparse: true, ..Default::default() }),
+            (7, ArchiveOptions::default()),
+            (7, ArchiveOptions { sparse: true, tail_pack: true, ..Default::default() }),
+            (7, ArchiveOptions { volume_size: Some(5 << 20), sparse: true, ..Default::default() })
+        ];
+        for (index, (chunk_size, options)) in cases.iter().enumerate() {
+            let dir = test_dir(&format!("copy-batches-{}", index));
+            let mut archive = create_archive(&dir, *chunk_size, options, &files);
+            assert_contents(&mut archive, &files);
+            let mut archive = create_archive_from_memory(&dir, "memory.fct", *chunk_size, options, &files);
+            assert_contents(&mut archive, &files);
+            assert_eq!(archive_bytes(&dir.join("memory.fct")), archive_bytes(&dir.join("test.fct")));
+        }
+    }
+}
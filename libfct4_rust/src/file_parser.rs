@@ -3,51 +3,220 @@ use std::path::PathBuf;
 use std::io::Read;
 use crate::fs_operations;
 
+/// Entries store their data as references into a chunk store of unique chunks
+pub const FEATURE_DEDUP: u16 = 0x0001;
+/// Chunks consisting of zeros only are recorded as holes instead of being stored
+pub const FEATURE_SPARSE: u16 = 0x0002;
+/// Partial last chunks are packed together into shared tail blocks
+pub const FEATURE_TAIL_PACK: u16 = 0x0004;
+/// Every entry carries its own chunk size, the archive's chunk size is only the default
+pub const FEATURE_ENTRY_CHUNK_SIZE: u16 = 0x0008;
+/// The archive is split into volumes, its header carries the maximum volume size
+pub const FEATURE_MULTI_VOLUME: u16 = 0x0010;
+/// Every entry carries the modification time of its file
+pub const FEATURE_MODIFIED_TIME: u16 = 0x0020;
+/// All archive features known to this implementation
+pub const KNOWN_FEATURES: u16 = FEATURE_DEDUP | FEATURE_SPARSE | FEATURE_TAIL_PACK | FEATURE_ENTRY_CHUNK_SIZE | FEATURE_MULTI_VOLUME | FEATURE_MODIFIED_TIME;
+
+/// Chunk reference of a hole in deduplicating sparse archives
+pub const ZERO_CHUNK: u32 = u32::MAX;
+/// Tail block index of entries whose partial last chunk is stored with the entry in tail packing archives
+pub const NO_TAIL_BLOCK: u32 = u32::MAX;
+
 #[derive(Default, Debug, Clone)]
 pub struct FileParser {
     pub file_path: PathBuf,
     pub chunk_count: u32,
-    pub last_chunk_size: u16
+    pub last_chunk_size: u16,
+    /// Size of the chunks the entry's contents are cut into
+    pub chunk_size: u16,
+    /// Feature flags of the archive the entry belongs to, deciding which extension fields the header carries
+    pub features: u16,
+    /// Number of chunks stored directly after the header (deduplicating archives only)
+    pub stored_chunk_count: u32,
+    /// Index into the chunk store for every chunk of the entry (deduplicating archives only)
+    pub chunk_refs: Vec<u32>,
+    /// One bit per chunk of the entry, set for holes (sparse archives only)
+    pub hole_bitmap: Vec<u8>,
+    /// Index of the tail block holding the partial last chunk (tail packing archives only)
+    pub tail_block: u32,
+    /// Position of the partial last chunk in its tail block (tail packing archives only)
+    pub tail_offset: u16,
+    /// Modification time of the file in seconds since the Unix epoch (archives with modification times only)
+    pub modified: u64,
+    /// Position of the entry data in the archive, filled in when the header is read from an archive
+    pub data_offset: u64
+}
+
+/// Where a run of an entry's bytes comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtentSource {
+    /// Stored in the archive at the given absolute offset
+    Archive(u64),
+    /// Not stored, consists of zeros
+    Zero
+}
+
+/// A run of an entry's bytes, in order of the entry's contents
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub length: u64,
+    pub source: ExtentSource
+}
+
+/// Archive wide information needed to locate the data of an entry
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveLayout {
+    pub chunk_size: u16,
+    pub features: u16,
+    /// Offsets of the chunks in the chunk store by chunk index (deduplicating archives only)
+    pub chunk_offsets: Vec<u64>,
+    /// Offsets of the tail blocks' data by tail block index (tail packing archives only)
+    pub tail_blocks: Vec<u64>
+}
+
+impl ArchiveLayout {
+    /// Record where the data of an entry or tail block read from the archive is stored
+    pub fn add_record(&mut self, header: &FileParser) {
+        if header.is_tail_block() {
+            self.tail_blocks.push(header.data_offset);
+            return;
+        }
+        // the chunks stored in an entry are appended to the chunk store in order
+        for chunk_index in 0..header.stored_chunk_count as u64 {
+            self.chunk_offsets.push(header.data_offset + chunk_index * header.chunk_size as u64);
+        }
+    }
+
+    /// Bytes of the last tail block taken by the given entries, as only the last one can take further tails
+    pub fn tail_block_used(&self, headers: &[FileParser]) -> u16 {
+        let last_tail_block = self.tail_blocks.len() as u32;
+        headers.iter()
+            .filter(|header| header.has_packed_tail() && header.tail_block + 1 == last_tail_block)
+            .map(|header| header.tail_offset + header.last_chunk_size)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Chunk size that cuts contents of the given size into as few chunks as possible while padding the last one the least.
+/// Empty contents keep the default.
+pub fn fitting_chunk_size(len: u64, default: u16) -> u16 {
+    if len == 0 {
+        return default;
+    }
+    let chunk_count = len.div_ceil(u16::MAX as u64);
+    len.div_ceil(chunk_count) as u16
+}
+
+// like read_exact, but reaching the end of the input before the first byte is not an error
+fn read_full<R: Read>(file: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut total = 0;
+    while total < buffer.len() {
+        match file.read(&mut buffer[total..]) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(total)
+}
+
+fn read_u32<R: Read>(file: &mut R) -> Result<u32, &'static str> {
+    let mut buffer = [0u8; 4];
+    match file.read_exact(&mut buffer) {
+        Ok(_) => Ok(u32::from_le_bytes(buffer)),
+        Err(_) => Err("File header is incomplete")
+    }
 }
 
 impl FileParser {
     pub fn from_file(file_path: &PathBuf, root_dir: &PathBuf, chunk_size: u16) -> Result<Self, &'static str> {
         match fs::metadata(&file_path){
             Ok(file_info) => {
-                let mut parser = FileParser::default();
-                if file_info.permissions().readonly() {
-                    return Err("File is readonly");
+                if chunk_size == 0 {
+                    return Err("Chunk size must not be 0");
                 }
+                // files are only read, so read-only files are archived like any other
+                let mut parser = FileParser { chunk_size, ..Default::default() };
                 parser.file_path = match fs_operations::format_path(root_dir, file_path) {
                     Ok(path) => path,
                     Err(_) => return Err("Could not format path")
                 };
-                // calculate chunk count and last chunk size
-                let chunk_count_result = u32::try_from(file_info.len() / chunk_size as u64);
-
-                let last_chunk_size_result =  u16::try_from(file_info.len() % chunk_size as u64);
-
-                match chunk_count_result {
-                    Ok(chunk_count) => parser.chunk_count = chunk_count,
-                    Err(_) => return Err("File size is too big")
-                }
-
-                match last_chunk_size_result {
-                    Ok(value) => parser.last_chunk_size = value,
-                    Err(_) => return Err("Final Chunk is too big")
-                }
+                parser.set_logical_size(file_info.len())?;
+                parser.modified = fs_operations::modified_seconds(&file_info);
                 return Ok(parser);
             }
             Err(_) => return Err("File not found")
         }
     }
 
+    /// Calculate chunk count and last chunk size of contents of the given size from the chunk size
+    pub fn set_logical_size(&mut self, len: u64) -> Result<(), &'static str> {
+        if self.chunk_size == 0 {
+            return Err("Chunk size must not be 0");
+        }
+        let chunk_count_result = u32::try_from(len / self.chunk_size as u64);
+
+        let last_chunk_size_result =  u16::try_from(len % self.chunk_size as u64);
+
+        match chunk_count_result {
+            Ok(chunk_count) => self.chunk_count = chunk_count,
+            Err(_) => return Err("File size is too big")
+        }
+
+        match last_chunk_size_result {
+            Ok(value) => self.last_chunk_size = value,
+            Err(_) => return Err("Final Chunk is too big")
+        }
+        Ok(())
+    }
+
+    /// Prepare the header of a new entry for an archive with the given features and chunk size. The chunk references
+    /// and holes are left empty, to be recorded while the data is written.
+    pub fn set_format(&mut self, features: u16, archive_chunk_size: u16) {
+        self.features = features;
+        if self.chunk_size == 0 {
+            self.chunk_size = archive_chunk_size;
+        }
+        // partial last chunks only fit into a tail block if they are not bigger than the archive's chunks
+        if features & FEATURE_TAIL_PACK != 0 && self.last_chunk_size > archive_chunk_size {
+            self.tail_block = NO_TAIL_BLOCK;
+        }
+        if features & FEATURE_DEDUP != 0 {
+            self.chunk_refs = vec![0; self.data_chunk_count() as usize];
+            self.stored_chunk_count = 0;
+        }
+        if features & FEATURE_SPARSE != 0 {
+            self.hole_bitmap = vec![0; self.hole_bitmap_len()];
+        }
+        // the modification time is only kept by archives recording it
+        if features & FEATURE_MODIFIED_TIME == 0 {
+            self.modified = 0;
+        }
+    }
+
+    /// Header of a tail block, which is stored like an entry with an empty name and a single chunk
+    pub fn tail_block(features: u16, chunk_size: u16) -> Self {
+        FileParser { chunk_count: 1, chunk_size, features, ..Default::default() }
+    }
+
     pub fn from_archive<R: Read>(file: &mut R) -> Result<Self, &'static str> {
+        Self::from_archive_with_features(file, 0, 0)
+    }
+
+    /// Read an entry header of an archive with the given feature flags and chunk size
+    pub fn from_archive_with_features<R: Read>(file: &mut R, features: u16, chunk_size: u16) -> Result<Self, &'static str> {
         const PROPERTY_FIELD_LEN: usize = 8;
-        let mut parser = FileParser::default();
+        let mut parser = FileParser { features, chunk_size, ..Default::default() };
         let mut buffer = [0u8; PROPERTY_FIELD_LEN];
 
-        let bytes_read = file.read(&mut buffer).expect("Failed to read file header data");
+        // a single read may stop at the end of the reader's buffer, so keep reading until the field is complete
+        let bytes_read = match read_full(file, &mut buffer) {
+            Ok(n) => n,
+            Err(_) => return Err("Failed to read file header data")
+        };
         if bytes_read == 0 {
             return Err("File is empty or EOF reached");
         }
@@ -61,8 +230,61 @@ impl FileParser {
         
         // read file_path_len amount of bytes
         let mut file_path_buffer = vec![0u8; file_path_len];
-        file.read_exact(&mut file_path_buffer).expect("Failed to read file path");
-        parser.file_path = PathBuf::from(String::from_utf8(file_path_buffer).expect("Failed to convert file path to string"));
+        // damaged or missing data, like a missing volume of a split archive, shows up as an unreadable path
+        if file.read_exact(&mut file_path_buffer).is_err() {
+            return Err("File header is incomplete");
+        }
+        parser.file_path = match String::from_utf8(file_path_buffer) {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => return Err("File path is not valid UTF-8")
+        };
+        if parser.is_tail_block() {
+            return Ok(parser);
+        }
+        // the chunk references and holes leave out a packed tail, which set_format rules out for tails bigger than the
+        // archive's chunks, so their number is known before the tail block field is read
+        if features & FEATURE_TAIL_PACK != 0 && parser.last_chunk_size > chunk_size {
+            parser.tail_block = NO_TAIL_BLOCK;
+        }
+
+        if features & FEATURE_DEDUP != 0 {
+            parser.stored_chunk_count = read_u32(file)?;
+            // the references are not reserved up front, as a damaged chunk count would reserve memory the header does not hold
+            let ref_count = parser.data_chunk_count();
+            parser.chunk_refs = Vec::new();
+            for _ in 0..ref_count {
+                parser.chunk_refs.push(read_u32(file)?);
+            }
+        }
+        if features & FEATURE_SPARSE != 0 {
+            let bitmap_len = parser.hole_bitmap_len();
+            match Read::by_ref(file).take(bitmap_len as u64).read_to_end(&mut parser.hole_bitmap) {
+                Ok(read) if read == bitmap_len => {},
+                _ => return Err("File header is incomplete")
+            }
+        }
+        if features & FEATURE_TAIL_PACK != 0 {
+            parser.tail_block = read_u32(file)?;
+            let mut buffer = [0u8; 2];
+            if file.read_exact(&mut buffer).is_err() {
+                return Err("File header is incomplete");
+            }
+            parser.tail_offset = u16::from_le_bytes(buffer);
+        }
+        if features & FEATURE_ENTRY_CHUNK_SIZE != 0 {
+            let mut buffer = [0u8; 2];
+            if file.read_exact(&mut buffer).is_err() {
+                return Err("File header is incomplete");
+            }
+            parser.chunk_size = u16::from_le_bytes(buffer);
+        }
+        if features & FEATURE_MODIFIED_TIME != 0 {
+            let mut buffer = [0u8; 8];
+            if file.read_exact(&mut buffer).is_err() {
+                return Err("File header is incomplete");
+            }
+            parser.modified = u64::from_le_bytes(buffer);
+        }
 
         //println!("{:?}", parser);
 
@@ -80,12 +302,179 @@ impl FileParser {
             Err(_) => return Err("File path is too big")
         }
         header.extend_from_slice(&file_path_bytes);
+        if self.is_tail_block() {
+            return Ok(header);
+        }
+
+        if self.features & FEATURE_DEDUP != 0 {
+            if self.chunk_refs.len() != self.data_chunk_count() as usize {
+                return Err("Chunk reference count does not match chunk count");
+            }
+            header.extend_from_slice(&self.stored_chunk_count.to_le_bytes());
+            for chunk_ref in &self.chunk_refs {
+                header.extend_from_slice(&chunk_ref.to_le_bytes());
+            }
+        }
+        if self.features & FEATURE_SPARSE != 0 {
+            if self.hole_bitmap.len() != self.hole_bitmap_len() {
+                return Err("Hole bitmap does not match chunk count");
+            }
+            header.extend_from_slice(&self.hole_bitmap);
+        }
+        if self.features & FEATURE_TAIL_PACK != 0 {
+            header.extend_from_slice(&self.tail_block.to_le_bytes());
+            header.extend_from_slice(&self.tail_offset.to_le_bytes());
+        }
+        if self.features & FEATURE_ENTRY_CHUNK_SIZE != 0 {
+            header.extend_from_slice(&self.chunk_size.to_le_bytes());
+        }
+        if self.features & FEATURE_MODIFIED_TIME != 0 {
+            header.extend_from_slice(&self.modified.to_le_bytes());
+        }
         return Ok(header);
     }
 
     pub fn get_header_size(&self) -> usize {
-        return 8 + self.file_path.to_str().unwrap().as_bytes().len();
+        let mut size = 8 + self.file_path.to_str().unwrap().len();
+        if self.is_tail_block() {
+            return size;
+        }
+        if self.features & FEATURE_DEDUP != 0 {
+            size += 4 + 4 * self.data_chunk_count() as usize;
+        }
+        if self.features & FEATURE_SPARSE != 0 {
+            size += self.hole_bitmap_len();
+        }
+        if self.features & FEATURE_TAIL_PACK != 0 {
+            size += 6;
+        }
+        if self.features & FEATURE_ENTRY_CHUNK_SIZE != 0 {
+            size += 2;
+        }
+        if self.features & FEATURE_MODIFIED_TIME != 0 {
+            size += 8;
+        }
+        size
+    }
+
+    pub fn hole_bitmap_len(&self) -> usize {
+        (self.data_chunk_count() as usize).div_ceil(8)
+    }
+
+    /// Whether the chunk at the given index is a hole
+    pub fn is_hole(&self, chunk_index: u32) -> bool {
+        match self.hole_bitmap.get(chunk_index as usize / 8) {
+            Some(byte) => byte & (1 << (chunk_index % 8)) != 0,
+            None => false
+        }
+    }
+
+    /// Record the chunk at the given index as a hole
+    pub fn set_hole(&mut self, chunk_index: u32) {
+        if self.hole_bitmap.len() < self.hole_bitmap_len() {
+            self.hole_bitmap.resize(self.hole_bitmap_len(), 0);
+        }
+        self.hole_bitmap[chunk_index as usize / 8] |= 1 << (chunk_index % 8);
+    }
+
+    /// Number of chunks recorded as holes
+    pub fn hole_count(&self) -> u32 {
+        let chunk_count = self.data_chunk_count() as usize;
+        self.hole_bitmap.iter().enumerate().map(|(index, byte)| {
+            // bits past the last chunk, which only a damaged header sets, are no holes
+            let chunk_bits = chunk_count.saturating_sub(index * 8).min(8);
+            (*byte as u16 & ((1u16 << chunk_bits) - 1)).count_ones()
+        }).sum()
+    }
+
+    /// Whether the header describes a tail block instead of a file
+    pub fn is_tail_block(&self) -> bool {
+        self.features & FEATURE_TAIL_PACK != 0 && self.file_path.as_os_str().is_empty()
+    }
+
+    /// Whether the partial last chunk is stored in a tail block instead of with the entry
+    pub fn has_packed_tail(&self) -> bool {
+        self.features & FEATURE_TAIL_PACK != 0 && self.last_chunk_size > 0 && self.tail_block != NO_TAIL_BLOCK
+    }
+
+    /// Number of chunks the entry's contents are cut into, including a partial last chunk unless it is packed
+    pub fn data_chunk_count(&self) -> u32 {
+        self.chunk_count + if self.last_chunk_size > 0 && !self.has_packed_tail() {1} else {0}
+    }
+
+    /// Size of the entry's contents
+    pub fn logical_size(&self) -> u64 {
+        self.chunk_count as u64 * self.chunk_size as u64 + self.last_chunk_size as u64
+    }
+
+    /// Number of bytes following the header in the archive
+    pub fn stored_size(&self) -> u64 {
+        let chunk_size = self.chunk_size as u64;
+        if self.is_tail_block() {
+            return chunk_size;
+        }
+        if self.features & FEATURE_DEDUP != 0 {
+            return self.stored_chunk_count as u64 * chunk_size;
+        }
+        (self.data_chunk_count() - self.hole_count()) as u64 * chunk_size
+    }
+
+    /// Map the entry's contents to the places they are stored at, merging adjacent runs
+    pub fn extents(&self, layout: &ArchiveLayout) -> Result<Vec<Extent>, &'static str> {
+        let chunk_size = self.chunk_size as u64;
+        let logical_size = self.logical_size();
+        if self.features & (FEATURE_DEDUP | FEATURE_SPARSE | FEATURE_TAIL_PACK) == 0 {
+            if logical_size == 0 {
+                return Ok(Vec::new());
+            }
+            return Ok(vec![Extent { length: logical_size, source: ExtentSource::Archive(self.data_offset) }]);
+        }
+
+        let mut extents: Vec<Extent> = Vec::new();
+        let mut stored_index: u64 = 0;
+        for index in 0..self.data_chunk_count() {
+            let length = if index == self.chunk_count { self.last_chunk_size as u64 } else { chunk_size };
+            let source = if self.is_hole(index) {
+                ExtentSource::Zero
+            }
+            else if self.features & FEATURE_DEDUP != 0 {
+                match layout.chunk_offsets.get(self.chunk_refs[index as usize] as usize) {
+                    Some(offset) => ExtentSource::Archive(*offset),
+                    None => return Err("Chunk reference is out of range")
+                }
+            }
+            else {
+                // the chunks that are not holes are stored in order
+                stored_index += 1;
+                ExtentSource::Archive(self.data_offset + (stored_index - 1) * chunk_size)
+            };
+            push_extent(&mut extents, Extent { length, source });
+        }
+        if self.has_packed_tail() {
+            let source = match layout.tail_blocks.get(self.tail_block as usize) {
+                Some(offset) => ExtentSource::Archive(*offset + self.tail_offset as u64),
+                None => return Err("Tail block reference is out of range")
+            };
+            push_extent(&mut extents, Extent { length: self.last_chunk_size as u64, source });
+        }
+        Ok(extents)
+    }
+}
+
+// append a run, merging it into the previous one if it continues it
+fn push_extent(extents: &mut Vec<Extent>, extent: Extent) {
+    if let Some(last) = extents.last_mut() {
+        let continues = match (last.source, extent.source) {
+            (ExtentSource::Zero, ExtentSource::Zero) => true,
+            (ExtentSource::Archive(a), ExtentSource::Archive(b)) => a + last.length == b,
+            _ => false
+        };
+        if continues {
+            last.length += extent.length;
+            return;
+        }
     }
+    extents.push(extent);
 }
 
 impl std::fmt::Display for FileParser {
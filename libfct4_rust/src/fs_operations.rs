@@ -1,8 +1,125 @@
 //use relative_path::RelativePath;
 use pathdiff;
-use std::path::{Path,PathBuf};
-use std::fs::{self};
-use walkdir::WalkDir;
+use std::path::{Component,Path,PathBuf};
+use std::fs::{self, Metadata};
+use std::collections::HashSet;
+use std::io::BufRead;
+use std::sync::Arc;
+use std::time::SystemTime;
+use ignore::WalkBuilder;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use regex::Regex;
+
+/// Name of the FCT specific ignore file, read next to .gitignore and .ignore files
+pub const IGNORE_FILE_NAME: &str = ".fctignore";
+
+/// A rule for rewriting the stored path of an entry on extraction
+#[derive(Debug, Clone)]
+pub enum PathRewrite {
+    /// Remove the given number of leading path components
+    StripComponents(usize),
+    /// Keep only the file name, placing every entry directly in the output folder
+    Flatten,
+    /// Replace the first (or, if global, every) match of the pattern in the path
+    Regex { pattern: Regex, replacement: String, global: bool }
+}
+
+impl PathRewrite {
+    /// Parse a sed-style substitution such as "s/^build\/(.*)/$1/" or "s|a|b|g"
+    pub fn from_substitution(expression: &str) -> Result<Self, &'static str> {
+        let mut chars = expression.chars();
+        if chars.next() != Some('s') {
+            return Err("Substitution must start with 's'");
+        }
+        let delimiter = match chars.next() {
+            Some(d) => d,
+            None => return Err("Substitution is missing a delimiter")
+        };
+        // split on unescaped delimiters
+        let mut parts: Vec<String> = vec![String::new()];
+        let mut escaped = false;
+        for c in chars {
+            if escaped {
+                if c != delimiter {
+                    parts.last_mut().unwrap().push('\\');
+                }
+                parts.last_mut().unwrap().push(c);
+                escaped = false;
+            }
+            else if c == '\\' {
+                escaped = true;
+            }
+            else if c == delimiter {
+                parts.push(String::new());
+            }
+            else {
+                parts.last_mut().unwrap().push(c);
+            }
+        }
+        if parts.len() != 3 {
+            return Err("Substitution must have the form s/pattern/replacement/flags");
+        }
+        let global = match parts[2].as_str() {
+            "" => false,
+            "g" => true,
+            _ => return Err("Unknown substitution flag")
+        };
+        let pattern = match Regex::new(&parts[0]) {
+            Ok(r) => r,
+            Err(_) => return Err("Invalid regular expression")
+        };
+        Ok(PathRewrite::Regex { pattern, replacement: parts[1].clone(), global })
+    }
+}
+
+/// Apply the rewrite rules in order. Returns None if the rules leave nothing of the path.
+pub fn rewrite_path(path: &Path, rules: &[PathRewrite]) -> Option<PathBuf> {
+    let mut path = path.to_path_buf();
+    for rule in rules {
+        path = match rule {
+            PathRewrite::StripComponents(count) => path.components().skip(*count).collect(),
+            PathRewrite::Flatten => match path.file_name() {
+                Some(name) => PathBuf::from(name),
+                None => PathBuf::new()
+            },
+            PathRewrite::Regex { pattern, replacement, global } => {
+                let path_str = path.to_string_lossy();
+                let replaced = if *global {
+                    pattern.replace_all(&path_str, replacement.as_str())
+                }
+                else {
+                    pattern.replace(&path_str, replacement.as_str())
+                };
+                PathBuf::from(replaced.into_owned())
+            }
+        };
+        if path.as_os_str().is_empty() {
+            return None;
+        }
+    }
+    Some(path)
+}
+
+/// Join a relative entry path onto the output folder, refusing paths that would end up outside of it
+pub fn join_inside(output_folder: &Path, path: &Path) -> Result<PathBuf, &'static str> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {},
+            Component::ParentDir => {
+                if !relative.pop() {
+                    return Err("Path leaves the output folder");
+                }
+            },
+            Component::RootDir | Component::Prefix(_) => return Err("Path is absolute")
+        }
+    }
+    if relative.as_os_str().is_empty() {
+        return Err("Path is empty");
+    }
+    Ok(output_folder.join(relative))
+}
 
 // create folders for a list of path buffers
 #[allow(dead_code)]
@@ -40,18 +157,462 @@ pub fn create_directories(file_indexes: &Vec<PathBuf>){
     });
 }
 
+/// Options for collecting the files below the paths given to an archive
+#[derive(Debug, Clone, Default)]
+pub struct ExpandOptions {
+    /// If not empty, only files matching one of these globs are collected
+    pub include: Vec<String>,
+    /// Files and directories matching one of these globs are skipped
+    pub exclude: Vec<String>,
+    /// Honor .gitignore, .ignore and .fctignore files found while walking
+    pub respect_ignore_files: bool,
+    /// Maximum directory depth to descend into, the given directory itself being depth 0
+    pub max_depth: Option<usize>,
+    /// Files that are never collected, such as the archive that is being written
+    pub exclude_paths: Vec<PathBuf>,
+    /// Only files passing all of these filters are collected
+    pub filters: Vec<FileFilter>,
+    /// Do not descend into directories on a different file system than the walked directory
+    pub one_file_system: bool
+}
+
+/// A user supplied predicate for [`FileFilter::Custom`]
+pub type FilterPredicate = dyn Fn(&Path, &Metadata) -> bool + Send + Sync;
+
+/// A predicate on the metadata of a file that decides whether it is collected
+#[derive(Clone)]
+pub enum FileFilter {
+    /// Modified after the given time
+    NewerThan(SystemTime),
+    /// At least the given number of bytes
+    MinSize(u64),
+    /// At most the given number of bytes
+    MaxSize(u64),
+    /// Stored on the device with the given id. Always passes on platforms without device ids.
+    SameDevice(u64),
+    /// Any other predicate
+    Custom(Arc<FilterPredicate>)
+}
+
+impl FileFilter {
+    /// A filter for files on the same device as the given path
+    pub fn same_device_as(path: &Path) -> Result<Self, &'static str> {
+        match fs::metadata(path) {
+            Ok(metadata) => Ok(FileFilter::SameDevice(device_id(&metadata))),
+            Err(_) => Err("Could not read metadata of reference path")
+        }
+    }
+
+    /// A filter for files modified after the given reference file
+    pub fn newer_than_file(path: &Path) -> Result<Self, &'static str> {
+        match fs::metadata(path).and_then(|m| m.modified()) {
+            Ok(time) => Ok(FileFilter::NewerThan(time)),
+            Err(_) => Err("Could not read modification time of reference file")
+        }
+    }
+
+    pub fn matches(&self, path: &Path, metadata: &Metadata) -> bool {
+        match self {
+            FileFilter::NewerThan(time) => match metadata.modified() {
+                Ok(modified) => modified > *time,
+                Err(_) => false
+            },
+            FileFilter::MinSize(size) => metadata.len() >= *size,
+            FileFilter::MaxSize(size) => metadata.len() <= *size,
+            FileFilter::SameDevice(device) => device_id(metadata) == *device,
+            FileFilter::Custom(predicate) => predicate(path, metadata)
+        }
+    }
+}
+
+impl std::fmt::Debug for FileFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileFilter::NewerThan(time) => write!(f, "NewerThan({:?})", time),
+            FileFilter::MinSize(size) => write!(f, "MinSize({})", size),
+            FileFilter::MaxSize(size) => write!(f, "MaxSize({})", size),
+            FileFilter::SameDevice(device) => write!(f, "SameDevice({})", device),
+            FileFilter::Custom(_) => write!(f, "Custom")
+        }
+    }
+}
+
+#[cfg(unix)]
+fn device_id(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.dev()
+}
+
+#[cfg(not(unix))]
+fn device_id(_metadata: &Metadata) -> u64 {
+    0
+}
+
+/// Get the (offset, length) pairs of a file's regions that contain data, as reported by the file system.
+/// Returns None if the file system cannot tell data from holes. The file's cursor is reset to the start.
+#[cfg(target_os = "linux")]
+pub fn data_regions(file: &fs::File) -> Option<Vec<(u64, u64)>> {
+    use std::io::{Seek, SeekFrom};
+    use std::os::unix::io::AsRawFd;
+    let fd = file.as_raw_fd();
+    let mut regions = Vec::new();
+    let mut position: libc::off_t = 0;
+    loop {
+        let data_start = unsafe { libc::lseek(fd, position, libc::SEEK_DATA) };
+        if data_start < 0 {
+            // ENXIO means that there is no more data behind the position
+            if std::io::Error::last_os_error().raw_os_error() != Some(libc::ENXIO) {
+                return None;
+            }
+            break;
+        }
+        let data_end = unsafe { libc::lseek(fd, data_start, libc::SEEK_HOLE) };
+        if data_end < 0 {
+            return None;
+        }
+        regions.push((data_start as u64, (data_end - data_start) as u64));
+        position = data_end;
+    }
+    let mut file = file;
+    match file.seek(SeekFrom::Start(0)) {
+        Ok(_) => Some(regions),
+        Err(_) => None
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn data_regions(_file: &fs::File) -> Option<Vec<(u64, u64)>> {
+    None
+}
+
+/// Copy bytes from one file to another inside the kernel, between the given offsets and without moving the files' cursors.
+/// Returns how many bytes were copied, which is less than the length at the end of the source and 0 if the
+/// file systems cannot copy between the files, leaving the rest to the caller.
+#[cfg(target_os = "linux")]
+pub fn copy_file_range(source: &fs::File, source_offset: u64, target: &fs::File, target_offset: u64, length: u64) -> std::io::Result<u64> {
+    use std::os::unix::io::AsRawFd;
+    // bigger copies are split up, as a single call copies at most about 2 GiB anyway
+    const MAX_RANGE: u64 = 1 << 30;
+    let mut copied: u64 = 0;
+    while copied < length {
+        let mut offset_in = (source_offset + copied) as libc::loff_t;
+        let mut offset_out = (target_offset + copied) as libc::loff_t;
+        let wanted = std::cmp::min(length - copied, MAX_RANGE) as usize;
+        let result = unsafe { libc::copy_file_range(source.as_raw_fd(), &mut offset_in, target.as_raw_fd(), &mut offset_out, wanted, 0) };
+        if result < 0 {
+            let error = std::io::Error::last_os_error();
+            match error.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                // old kernels and some file systems cannot copy, or not between different file systems
+                Some(libc::ENOSYS) | Some(libc::EXDEV) | Some(libc::EINVAL) | Some(libc::EOPNOTSUPP) => return Ok(copied),
+                _ => return Err(error)
+            }
+        }
+        if result == 0 {
+            break;
+        }
+        copied += result as u64;
+    }
+    Ok(copied)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn copy_file_range(_source: &fs::File, _source_offset: u64, _target: &fs::File, _target_offset: u64, _length: u64) -> std::io::Result<u64> {
+    Ok(0)
+}
+
+fn passes_filters(filters: &[FileFilter], path: &Path, metadata: &Metadata) -> bool {
+    filters.iter().all(|filter| filter.matches(path, metadata))
+}
+
+// make a path absolute without requiring it to exist, so that archives which are yet to be created can be excluded
+pub(crate) fn absolute_path(path: &Path) -> PathBuf {
+    if let Ok(canonical) = fs::canonicalize(path) {
+        return canonical;
+    }
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => PathBuf::from(".")
+    };
+    match (fs::canonicalize(&parent), path.file_name()) {
+        (Ok(parent), Some(name)) => parent.join(name),
+        _ => path.to_path_buf()
+    }
+}
+
+fn build_globset(globs: &Vec<String>) -> Result<GlobSet, &'static str> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        match Glob::new(glob) {
+            Ok(g) => { builder.add(g); },
+            Err(_) => return Err("Invalid glob pattern")
+        }
+    }
+    match builder.build() {
+        Ok(set) => Ok(set),
+        Err(_) => Err("Invalid glob pattern")
+    }
+}
+
+// globs are tried against the path relative to the walked directory and against the bare file name
+fn glob_matches(set: &GlobSet, root: &Path, path: &Path) -> bool {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    set.is_match(relative) || path.file_name().is_some_and(|name| set.is_match(name))
+}
+
 #[allow(dead_code)]
 pub fn expand_directory(path: &PathBuf) -> Vec<PathBuf> {
     // get all files in directory recursively
+    expand_directory_with_options(path, &ExpandOptions::default()).unwrap_or_default()
+}
+
+/// Collect the files below a directory, sorted by name, applying the filters of the options.
+/// Fails if part of the directory could not be read, so that no file is silently left out.
+pub fn expand_directory_with_options(path: &PathBuf, options: &ExpandOptions) -> Result<Vec<PathBuf>, &'static str> {
+    let include = build_globset(&options.include)?;
+    let exclude = build_globset(&options.exclude)?;
+    let excluded_paths: Vec<PathBuf> = options.exclude_paths.iter().map(|p| absolute_path(p)).collect();
+    let mut failed: Vec<(PathBuf, &'static str)> = Vec::new();
+    let files = walk_directory(path, options, &include, &exclude, &excluded_paths, &mut failed);
+    match failed.is_empty() {
+        true => Ok(files),
+        false => Err("Could not read directory")
+    }
+}
+
+// walks a directory, recording the paths that could not be read instead of stopping at them
+fn walk_directory(path: &PathBuf, options: &ExpandOptions, include: &GlobSet, exclude: &GlobSet, excluded_paths: &[PathBuf], failed: &mut Vec<(PathBuf, &'static str)>) -> Vec<PathBuf> {
+    let mut walker = WalkBuilder::new(path);
+    walker
+        .standard_filters(false)
+        .max_depth(options.max_depth)
+        .same_file_system(options.one_file_system)
+        .sort_by_file_name(|a, b| a.cmp(b));
+    if options.respect_ignore_files {
+        walker
+            .git_ignore(true)
+            .git_exclude(true)
+            .ignore(true)
+            .parents(true)
+            .require_git(false)
+            .add_custom_ignore_filename(IGNORE_FILE_NAME);
+    }
+    let root = path.clone();
+    let filter_exclude = exclude.clone();
+    walker.filter_entry(move |entry| {
+        // the root itself is never excluded, only what is found below it
+        entry.depth() == 0 || !glob_matches(&filter_exclude, &root, entry.path())
+    });
+
     let mut files: Vec<PathBuf> = Vec::new();
-    for entry in WalkDir::new(&path) {
-        let entry = entry.unwrap();
-        let path = entry.path();
-        if path.is_file() {
-            files.push(path.to_path_buf());
+    for entry in walker.build() {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(e) => {
+                failed.push((error_path(&e).unwrap_or(path).to_path_buf(), "Could not read directory entry"));
+                continue;
+            }
+        };
+        let file_path = entry.path();
+        // follows symlinks, like the plain walk did
+        let metadata = match fs::metadata(file_path) {
+            Ok(m) => m,
+            Err(_) => continue
+        };
+        if !metadata.is_file() || !passes_filters(&options.filters, file_path, &metadata) {
+            continue;
+        }
+        if !options.include.is_empty() && !glob_matches(include, path, file_path) {
+            continue;
+        }
+        if !excluded_paths.is_empty() && excluded_paths.contains(&absolute_path(file_path)) {
+            continue;
+        }
+        files.push(file_path.to_path_buf());
+    }
+    files
+}
+
+// the path a walk error is about, if it names one
+fn error_path(error: &ignore::Error) -> Option<&Path> {
+    match error {
+        ignore::Error::WithPath { path, .. } => Some(path),
+        ignore::Error::WithDepth { err, .. } | ignore::Error::WithLineNumber { err, .. } => error_path(err),
+        ignore::Error::Loop { child, .. } => Some(child),
+        _ => None
+    }
+}
+
+/// Expand a list of files and directories into the files to archive.
+/// Directories are walked with the given options, and files reached through overlapping inputs are only returned once.
+/// Files given directly go through the same globs and filters, the globs matching their path relative to the current
+/// directory or their file name. Fails if part of a directory could not be read.
+pub fn expand_paths(paths: &[PathBuf], options: &ExpandOptions) -> Result<Vec<PathBuf>, &'static str> {
+    let mut expanded = expand_paths_iter(paths.iter().cloned(), options)?;
+    let files: Vec<PathBuf> = expanded.by_ref().collect();
+    match expanded.take_failed().is_empty() {
+        true => Ok(files),
+        false => Err("Could not read directory")
+    }
+}
+
+/// Lazily expand a sequence of files and directories, see expand_paths.
+/// Only one directory listing is held in memory at a time. Paths that could not be read are skipped and can be
+/// collected with take_failed.
+pub fn expand_paths_iter<I: IntoIterator<Item = PathBuf>>(paths: I, options: &ExpandOptions) -> Result<ExpandedPaths<I::IntoIter>, &'static str> {
+    // build the globs once up front, so that the iterator itself cannot fail
+    Ok(ExpandedPaths {
+        paths: paths.into_iter(),
+        include: build_globset(&options.include)?,
+        exclude: build_globset(&options.exclude)?,
+        options: options.clone(),
+        excluded_paths: options.exclude_paths.iter().map(|p| absolute_path(p)).collect(),
+        pending: Vec::new().into_iter(),
+        seen: HashSet::new(),
+        failed: Vec::new()
+    })
+}
+
+/// Iterator returned by expand_paths_iter
+pub struct ExpandedPaths<I: Iterator<Item = PathBuf>> {
+    paths: I,
+    include: GlobSet,
+    exclude: GlobSet,
+    options: ExpandOptions,
+    excluded_paths: Vec<PathBuf>,
+    pending: std::vec::IntoIter<PathBuf>,
+    seen: HashSet<PathBuf>,
+    failed: Vec<(PathBuf, &'static str)>
+}
+
+impl<I: Iterator<Item = PathBuf>> ExpandedPaths<I> {
+    /// Take the paths that could not be read since the last call, such as unreadable directories
+    pub fn take_failed(&mut self) -> Vec<(PathBuf, &'static str)> {
+        std::mem::take(&mut self.failed)
+    }
+
+    fn expand(&mut self, path: PathBuf) -> Vec<PathBuf> {
+        if path.is_dir() {
+            return walk_directory(&path, &self.options, &self.include, &self.exclude, &self.excluded_paths, &mut self.failed);
+        }
+        if self.excluded_paths.contains(&absolute_path(&path)) {
+            return Vec::new();
+        }
+        // there is no walked directory, so the globs see the path relative to the current directory
+        let root = std::env::current_dir().unwrap_or_default();
+        if glob_matches(&self.exclude, &root, &path) || (!self.options.include.is_empty() && !glob_matches(&self.include, &root, &path)) {
+            return Vec::new();
+        }
+        if !self.options.filters.is_empty() {
+            return match fs::metadata(&path) {
+                Ok(metadata) if passes_filters(&self.options.filters, &path, &metadata) => vec![path],
+                _ => Vec::new()
+            };
+        }
+        vec![path]
+    }
+}
+
+impl<I: Iterator<Item = PathBuf>> Iterator for ExpandedPaths<I> {
+    type Item = PathBuf;
+
+    fn next(&mut self) -> Option<PathBuf> {
+        loop {
+            match self.pending.next() {
+                Some(file) => {
+                    // the same file can be reached under different paths, such as through a symlink or with ..
+                    if self.seen.insert(absolute_path(&file)) {
+                        return Some(file);
+                    }
+                },
+                None => {
+                    let path = self.paths.next()?;
+                    self.pending = self.expand(path).into_iter();
+                }
+            }
         }
     }
-    return files;
+}
+
+/// Read a list of paths, one per line or separated by NUL bytes, such as the output of find -print0
+pub fn read_path_list<R: BufRead>(reader: R, null_delimited: bool) -> PathList<R> {
+    PathList {
+        reader,
+        delimiter: if null_delimited { 0 } else { b'\n' }
+    }
+}
+
+/// Iterator returned by read_path_list. Empty entries are skipped.
+pub struct PathList<R: BufRead> {
+    reader: R,
+    delimiter: u8
+}
+
+impl<R: BufRead> Iterator for PathList<R> {
+    type Item = std::io::Result<PathBuf>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut buffer: Vec<u8> = Vec::new();
+        loop {
+            buffer.clear();
+            match self.reader.read_until(self.delimiter, &mut buffer) {
+                Ok(0) => return None,
+                Ok(_) => {},
+                Err(e) => return Some(Err(e))
+            }
+            if buffer.last() == Some(&self.delimiter) {
+                buffer.pop();
+            }
+            if self.delimiter == b'\n' && buffer.last() == Some(&b'\r') {
+                buffer.pop();
+            }
+            if !buffer.is_empty() {
+                return Some(Ok(path_from_bytes(buffer)));
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Modification time of a file in whole seconds since the Unix epoch, 0 if it is not available
+pub fn modified_seconds(metadata: &Metadata) -> u64 {
+    match metadata.modified().map(|time| time.duration_since(SystemTime::UNIX_EPOCH)) {
+        Ok(Ok(duration)) => duration.as_secs(),
+        _ => 0
+    }
+}
+
+/// Find a path next to the given one that does not exist yet by appending a numeric suffix to the file stem
+pub fn find_free_path(path: &Path) -> PathBuf {
+    find_free_name(path, |candidate| candidate.exists())
+}
+
+/// Like find_free_path, but with the given function deciding whether a path is taken
+pub fn find_free_name<F: Fn(&Path) -> bool>(path: &Path, taken: F) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+    let extension = path.extension().map(|e| e.to_string_lossy().into_owned());
+    let mut counter: u32 = 1;
+    loop {
+        let file_name = match &extension {
+            Some(ext) => format!("{}.{}.{}", stem, counter, ext),
+            None => format!("{}.{}", stem, counter)
+        };
+        let candidate = path.with_file_name(file_name);
+        if !taken(&candidate) {
+            return candidate;
+        }
+        counter += 1;
+    }
 }
 
 // default function for all OSes
@@ -75,4 +636,188 @@ pub fn format_path<'a, P: ?Sized>(root_dir: &'a P, file_path: &'a P) -> Result<P
         Some(path) => Ok(path),
         None => Err("Could not get relative path")
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_inside_refuses_paths_leaving_the_output_folder() {
+        let output = Path::new("out");
+        assert_eq!(join_inside(output, Path::new("a/../x")).unwrap(), PathBuf::from("out/x"));
+        assert_eq!(join_inside(output, Path::new("./a/b")).unwrap(), PathBuf::from("out/a/b"));
+        assert_eq!(join_inside(output, Path::new("../x")), Err("Path leaves the output folder"));
+        assert_eq!(join_inside(output, Path::new("a/../../x")), Err("Path leaves the output folder"));
+        assert_eq!(join_inside(output, Path::new("/etc/passwd")), Err("Path is absolute"));
+        assert_eq!(join_inside(output, Path::new("a/..")), Err("Path is empty"));
+        assert_eq!(join_inside(output, Path::new("")), Err("Path is empty"));
+    }
+
+    #[test]
+    fn rewrite_rules_apply_in_order() {
+        let path = Path::new("build/x86/lib/libfoo.so");
+        assert_eq!(rewrite_path(path, &[PathRewrite::StripComponents(2)]), Some(PathBuf::from("lib/libfoo.so")));
+        assert_eq!(rewrite_path(path, &[PathRewrite::Flatten]), Some(PathBuf::from("libfoo.so")));
+        // stripping every component leaves nothing to extract
+        assert_eq!(rewrite_path(path, &[PathRewrite::StripComponents(4)]), None);
+        assert_eq!(rewrite_path(path, &[PathRewrite::StripComponents(9)]), None);
+        let rules = [PathRewrite::from_substitution("s/o/0/g").unwrap(), PathRewrite::StripComponents(1)];
+        assert_eq!(rewrite_path(path, &rules), Some(PathBuf::from("x86/lib/libf00.s0")));
+        let first_only = PathRewrite::from_substitution("s|o|0|").unwrap();
+        assert_eq!(rewrite_path(Path::new("foo"), &[first_only]), Some(PathBuf::from("f0o")));
+        // a rule may also produce a path that join_inside has to refuse
+        let escape = PathRewrite::from_substitution("s/^build/../").unwrap();
+        let rewritten = rewrite_path(path, &[escape]).unwrap();
+        assert!(join_inside(Path::new("out"), &rewritten).is_err());
+    }
+
+    #[test]
+    fn substitutions_are_parsed_like_sed() {
+        // an escaped delimiter belongs to the pattern, other escapes are kept for the regular expression
+        match PathRewrite::from_substitution(r"s/^build\/(.*)\.o/obj\/$1/").unwrap() {
+            PathRewrite::Regex { pattern, replacement, global } => {
+                assert_eq!(pattern.as_str(), r"^build/(.*)\.o");
+                assert_eq!(replacement, "obj/$1");
+                assert!(!global);
+            },
+            rule => panic!("parsed as {:?}", rule)
+        }
+        let rewrite = PathRewrite::from_substitution(r"s/^build\/(.*)/$1/").unwrap();
+        assert_eq!(rewrite_path(Path::new("build/a/b"), &[rewrite]), Some(PathBuf::from("a/b")));
+        assert_eq!(PathRewrite::from_substitution("x/a/b/").err(), Some("Substitution must start with 's'"));
+        assert_eq!(PathRewrite::from_substitution("s").err(), Some("Substitution is missing a delimiter"));
+        assert_eq!(PathRewrite::from_substitution("s/a/b").err(), Some("Substitution must have the form s/pattern/replacement/flags"));
+        assert_eq!(PathRewrite::from_substitution("s/a/b/c/").err(), Some("Substitution must have the form s/pattern/replacement/flags"));
+        assert_eq!(PathRewrite::from_substitution("s/a/b/x").err(), Some("Unknown substitution flag"));
+        assert_eq!(PathRewrite::from_substitution("s/(a/b/").err(), Some("Invalid regular expression"));
+    }
+
+    // writes the files below the directory, creating their folders
+    fn write_tree(dir: &Path, files: &[(&str, usize)]) {
+        for (name, len) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, vec![b'x'; *len]).unwrap();
+        }
+    }
+
+    // the expanded files relative to the directory
+    fn relative_names(dir: &Path, files: &[PathBuf]) -> Vec<String> {
+        files.iter().map(|f| f.strip_prefix(dir).unwrap().to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn expansion_applies_globs_depth_and_ignore_files() {
+        let dir = crate::fct_archive::tests::test_dir("expand-options").join("input");
+        write_tree(&dir, &[("a.txt", 1), ("b.log", 1), ("sub/c.txt", 1), ("sub/deep/d.txt", 1), ("target/e.txt", 1)]);
+        fs::write(dir.join(".gitignore"), "target/\n").unwrap();
+        fs::write(dir.join(IGNORE_FILE_NAME), "*.log\n").unwrap();
+        let expand = |options: ExpandOptions| relative_names(&dir, &expand_directory_with_options(&dir, &options).unwrap());
+
+        assert_eq!(expand(ExpandOptions::default()), [".fctignore", ".gitignore", "a.txt", "b.log", "sub/c.txt", "sub/deep/d.txt", "target/e.txt"]);
+        // include globs match the relative path or the file name, exclude globs also prune directories
+        assert_eq!(expand(ExpandOptions { include: vec!["*.txt".to_string()], ..Default::default() }), ["a.txt", "sub/c.txt", "sub/deep/d.txt", "target/e.txt"]);
+        // * is not limited to one path component
+        assert_eq!(expand(ExpandOptions { include: vec!["sub/*.txt".to_string()], ..Default::default() }), ["sub/c.txt", "sub/deep/d.txt"]);
+        assert_eq!(expand(ExpandOptions { exclude: vec!["sub".to_string(), ".*".to_string()], ..Default::default() }), ["a.txt", "b.log", "target/e.txt"]);
+        // the ignore files themselves are still collected, only what they name is left out
+        assert_eq!(expand(ExpandOptions { respect_ignore_files: true, ..Default::default() }), [".fctignore", ".gitignore", "a.txt", "sub/c.txt", "sub/deep/d.txt"]);
+        assert_eq!(expand(ExpandOptions { max_depth: Some(2), exclude: vec![".*".to_string()], ..Default::default() }), ["a.txt", "b.log", "sub/c.txt", "target/e.txt"]);
+        assert_eq!(expand(ExpandOptions { max_depth: Some(1), exclude: vec![".*".to_string()], ..Default::default() }), ["a.txt", "b.log"]);
+        assert_eq!(expand(ExpandOptions { exclude_paths: vec![dir.join("sub/../a.txt")], exclude: vec![".*".to_string()], max_depth: Some(1), ..Default::default() }), ["b.log"]);
+        assert_eq!(expand_directory_with_options(&dir, &ExpandOptions { include: vec!["[".to_string()], ..Default::default() }), Err("Invalid glob pattern"));
+    }
+
+    #[test]
+    fn expansion_returns_each_file_once() {
+        let dir = crate::fct_archive::tests::test_dir("expand-dedup").join("input");
+        write_tree(&dir, &[("a", 1), ("src/f", 1), ("src/g", 1)]);
+        let paths = [dir.join("src"), dir.join("src/f"), dir.join("./src/f"), dir.join("src/../src/f"), dir.clone()];
+        let files = expand_paths(&paths, &ExpandOptions::default()).unwrap();
+        assert_eq!(relative_names(&dir, &files), ["src/f", "src/g", "a"]);
+        #[cfg(unix)]
+        {
+            // a symlink to a directory that is also given directly adds nothing new
+            std::os::unix::fs::symlink(dir.join("src"), dir.join("link")).unwrap();
+            let files = expand_paths(&[dir.join("src"), dir.join("link/f"), dir.join("link")], &ExpandOptions::default()).unwrap();
+            assert_eq!(relative_names(&dir, &files), ["src/f", "src/g"]);
+        }
+    }
+
+    #[test]
+    fn filters_select_files_by_size_time_and_device() {
+        let dir = crate::fct_archive::tests::test_dir("expand-filters").join("input");
+        write_tree(&dir, &[("empty", 0), ("small", 10), ("large", 1000), ("sub/medium", 100)]);
+        let now = SystemTime::now();
+        for (name, age) in [("empty", 0), ("small", 3600), ("large", 7200), ("sub/medium", 60)] {
+            let file = fs::File::options().write(true).open(dir.join(name)).unwrap();
+            file.set_modified(now - std::time::Duration::from_secs(age)).unwrap();
+        }
+        let filtered = |filters: Vec<FileFilter>| {
+            let options = ExpandOptions { filters, ..Default::default() };
+            let mut names = relative_names(&dir, &expand_directory_with_options(&dir, &options).unwrap());
+            // files given directly go through the same filters
+            let direct: Vec<PathBuf> = ["empty", "large", "small", "sub/medium"].iter().map(|n| dir.join(n)).collect();
+            assert_eq!(relative_names(&dir, &expand_paths(&direct, &options).unwrap()), names);
+            names.sort();
+            names
+        };
+
+        assert_eq!(filtered(vec![FileFilter::MinSize(10)]), ["large", "small", "sub/medium"]);
+        assert_eq!(filtered(vec![FileFilter::MaxSize(100)]), ["empty", "small", "sub/medium"]);
+        assert_eq!(filtered(vec![FileFilter::MinSize(10), FileFilter::MaxSize(100)]), ["small", "sub/medium"]);
+        assert_eq!(filtered(vec![FileFilter::NewerThan(now - std::time::Duration::from_secs(3000))]), ["empty", "sub/medium"]);
+        // only strictly newer files pass
+        assert_eq!(filtered(vec![FileFilter::newer_than_file(&dir.join("small")).unwrap()]), ["empty", "sub/medium"]);
+        assert_eq!(filtered(vec![FileFilter::same_device_as(&dir).unwrap()]), ["empty", "large", "small", "sub/medium"]);
+        #[cfg(unix)]
+        assert_eq!(filtered(vec![FileFilter::SameDevice(u64::MAX)]), Vec::<String>::new());
+        let custom = FileFilter::Custom(Arc::new(|path: &Path, _: &Metadata| path.ends_with("large")));
+        assert_eq!(filtered(vec![custom]), ["large"]);
+        assert!(FileFilter::same_device_as(&dir.join("missing")).is_err());
+        assert!(FileFilter::newer_than_file(&dir.join("missing")).is_err());
+    }
+
+    #[test]
+    fn unreadable_directories_are_reported() {
+        let dir = crate::fct_archive::tests::test_dir("expand-errors").join("input");
+        write_tree(&dir, &[("a", 1), ("locked/b", 1)]);
+        assert_eq!(expand_directory_with_options(&dir.join("missing"), &ExpandOptions::default()), Err("Could not read directory"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let locked = dir.join("locked");
+            fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+            // privileged users can read the directory anyway
+            if fs::read_dir(&locked).is_err() {
+                assert_eq!(expand_directory_with_options(&dir, &ExpandOptions::default()), Err("Could not read directory"));
+                assert_eq!(expand_paths(std::slice::from_ref(&dir), &ExpandOptions::default()), Err("Could not read directory"));
+                let mut expanded = expand_paths_iter(vec![dir.clone()], &ExpandOptions::default()).unwrap();
+                assert_eq!(expanded.by_ref().collect::<Vec<PathBuf>>(), [dir.join("a")]);
+                assert_eq!(expanded.take_failed(), [(locked.clone(), "Could not read directory entry")]);
+                assert!(expanded.take_failed().is_empty());
+            }
+            fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+    }
+
+    #[test]
+    fn path_lists_are_split_on_lines_or_nul_bytes() {
+        let read = |input: &[u8], null_delimited: bool| -> Vec<PathBuf> {
+            read_path_list(input, null_delimited).map(|p| p.unwrap()).collect()
+        };
+        assert_eq!(read(b"a\nb c\r\n\n\nd/e", false), [PathBuf::from("a"), PathBuf::from("b c"), PathBuf::from("d/e")]);
+        // NUL separated lists keep newlines and carriage returns in names
+        assert_eq!(read(b"a\nb\0c\r\0\0d\0", true), [PathBuf::from("a\nb"), PathBuf::from("c\r"), PathBuf::from("d")]);
+        assert_eq!(read(b"", false), Vec::<PathBuf>::new());
+        assert_eq!(read(b"\n\n", false), Vec::<PathBuf>::new());
+        #[cfg(unix)]
+        {
+            // names do not have to be valid UTF-8
+            use std::os::unix::ffi::OsStrExt;
+            let list = read(b"\xff\xfe\0ok", true);
+            assert_eq!(list[0].as_os_str().as_bytes(), b"\xff\xfe");
+            assert_eq!(list[1], PathBuf::from("ok"));
+        }
+    }
+}
@@ -2,3 +2,12 @@ pub mod fct_archive;
 pub mod fs_operations;
 pub mod file_parser;
 pub mod error;
+pub mod stats;
+pub mod storage;
+pub mod diff;
+pub mod watch;
+pub mod embed;
+pub mod view;
+pub mod shared;
+#[cfg(feature = "tokio")]
+pub mod async_archive;
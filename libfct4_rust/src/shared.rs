@@ -0,0 +1,435 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::fct_archive::{ExtractAction, ExtractOptions, ExtractReport, FctArchive};
+use crate::file_parser::{ArchiveLayout, Extent, ExtentSource, FileParser};
+use crate::storage::ArchiveStorage;
+use crate::error::*;
+
+/// Read-only handle of an archive that threads can read from at the same time.
+/// The entries are read with positional reads instead of a shared cursor, and clones share the open files.
+#[derive(Clone)]
+pub struct SharedArchive {
+    inner: Arc<SharedState>
+}
+
+// handing the archive to other threads is what it is for, so losing Send or Sync must not go unnoticed
+fn _assert<T: Send + Sync + Clone>() {}
+const _: fn() = _assert::<SharedArchive>;
+
+struct SharedState {
+    storage: ArchiveStorage,
+    archive_path: PathBuf,
+    headers: Vec<FileParser>,
+    layout: ArchiveLayout
+}
+
+/// Reads the contents of an entry of a shared archive, independently of other readers
+pub struct SharedEntryReader {
+    archive: SharedArchive,
+    extents: Vec<Extent>,
+    extent_index: usize,
+    extent_position: u64
+}
+
+impl SharedArchive {
+    /// Open an archive for reading, like FctArchive::open. The entries are read once, changes made to the
+    /// archive afterwards are not seen by the handle.
+    pub fn open(archive_path: &PathBuf) -> Result<Self, &'static str> {
+        let mut archive = FctArchive::open(archive_path)?;
+        Self::from_archive(&mut archive)
+    }
+
+    /// Open a handle of an archive that is already open, taking over the entries it has read
+    pub fn from_archive(archive: &mut FctArchive) -> Result<Self, &'static str> {
+        // entries added through the archive are only seen by positional reads once they are written out
+        unwrap_or_return_error!(archive.archive_file.flush(), "Could not write to archive");
+        let headers = archive.get_headers().clone();
+        let storage = unwrap_or_return_error!(ArchiveStorage::open(&archive.archive_path), "Error opening archive file");
+        Ok(SharedArchive {
+            inner: Arc::new(SharedState {
+                storage,
+                archive_path: archive.archive_path.clone(),
+                headers,
+                layout: archive.layout.clone()
+            })
+        })
+    }
+
+    pub fn archive_path(&self) -> &Path {
+        &self.inner.archive_path
+    }
+
+    /// The file headers of the entries in the archive
+    pub fn headers(&self) -> &Vec<FileParser> {
+        &self.inner.headers
+    }
+
+    /// Index of the last entry with the given stored path, which is the one extraction leaves behind
+    pub fn find<P: AsRef<Path>>(&self, path: P) -> Option<u32> {
+        self.inner.headers.iter().rposition(|header| header.file_path == path.as_ref()).map(|index| index as u32)
+    }
+
+    /// Open a reader over the contents of the entry at the given index
+    pub fn entry_reader(&self, index: u32) -> Result<SharedEntryReader, &'static str> {
+        let extents = match self.inner.headers.get(index as usize) {
+            Some(header) => header.extents(&self.inner.layout)?,
+            None => return Err("Could not find entry")
+        };
+        Ok(SharedEntryReader {
+            archive: self.clone(),
+            extents,
+            extent_index: 0,
+            extent_position: 0
+        })
+    }
+
+    /// Read the contents of the entry at the given index
+    pub fn read_entry(&self, index: u32) -> Result<Vec<u8>, &'static str> {
+        let mut reader = self.entry_reader(index)?;
+        let mut contents = Vec::with_capacity(reader.len() as usize);
+        unwrap_or_return_error!(reader.read_to_end(&mut contents), "Could not read file");
+        Ok(contents)
+    }
+
+    /// Extract files like FctArchive::extract_files_with_options, with the given number of threads reading the entries
+    /// and writing the files at once. With 0 threads, one thread per processor is used.
+    /// Entries extracted to the same path are handled one after the other in archive order, so the overwrite policy
+    /// decides like it does when extracting in order. The reports are in archive order.
+    pub fn extract_files_parallel(&self, output_folder: &PathBuf, indices: &mut Vec<u32>, options: &ExtractOptions, threads: usize) -> Vec<ExtractReport> {
+        let headers = &self.inner.headers;
+        if indices.is_empty() {
+            indices.extend(0..headers.len() as u32);
+        }
+        indices.sort();
+        indices.dedup();
+        let selected: Vec<&FileParser> = indices.iter().filter_map(|i| headers.get(*i as usize)).collect();
+        if !output_folder.exists() {
+            if let Err(e) = std::fs::create_dir_all(output_folder) {
+                println!("Error extracting files: Could not create output folder: {}", e);
+                return selected.iter().map(|header| ExtractReport {
+                    path: output_folder.join(&header.file_path),
+                    action: ExtractAction::Failed("Could not create output folder")
+                }).collect();
+            }
+        }
+
+        // one report per selected entry, filled in by whoever handles the entry
+        let mut reports: Vec<Option<ExtractReport>> = vec![None; selected.len()];
+        // entries extracted to the same path form one job, so that they are written in order
+        let mut jobs: Vec<Vec<(usize, PathBuf)>> = Vec::new();
+        let mut path_jobs: HashMap<PathBuf, usize> = HashMap::new();
+        let mut directories: BTreeSet<PathBuf> = BTreeSet::new();
+        for (slot, header) in selected.iter().enumerate() {
+            match FctArchive::map_output_path(output_folder, &header.file_path, options) {
+                Ok(Some(path)) => {
+                    if let Some(parent) = path.parent() {
+                        directories.insert(parent.to_path_buf());
+                    }
+                    match path_jobs.get(&path) {
+                        Some(job) => jobs[*job].push((slot, path)),
+                        None => {
+                            path_jobs.insert(path.clone(), jobs.len());
+                            jobs.push(vec![(slot, path)]);
+                        }
+                    }
+                },
+                Ok(None) => reports[slot] = Some(ExtractReport { path: header.file_path.clone(), action: ExtractAction::Skipped }),
+                Err(e) => {
+                    println!("Error extracting file {}: {}", header.file_path.display(), e);
+                    reports[slot] = Some(ExtractReport { path: header.file_path.clone(), action: ExtractAction::Failed(e) });
+                }
+            }
+        }
+
+        // directories are created before the files, in sorted order, which puts parents before their children
+        let mut failed_directories: HashSet<PathBuf> = HashSet::new();
+        for directory in &directories {
+            if let Err(e) = std::fs::create_dir_all(directory) {
+                println!("Error extracting files: Could not create output folder: {}", e);
+                failed_directories.insert(directory.clone());
+            }
+        }
+
+        let threads = match threads {
+            0 => std::thread::available_parallelism().map(|count| count.get()).unwrap_or(1),
+            count => count
+        };
+        let next_job = AtomicUsize::new(0);
+        let finished: Vec<(usize, ExtractReport)> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads.min(jobs.len())).map(|_| scope.spawn(|| {
+                let mut finished: Vec<(usize, ExtractReport)> = Vec::new();
+                loop {
+                    let job = match jobs.get(next_job.fetch_add(1, Ordering::Relaxed)) {
+                        Some(job) => job,
+                        None => return finished
+                    };
+                    for (slot, file_path) in job {
+                        let report = match file_path.parent() {
+                            Some(parent) if failed_directories.contains(parent) => ExtractReport {
+                                path: file_path.clone(),
+                                action: ExtractAction::Failed("Could not create output folder")
+                            },
+                            _ => {
+                                println!("Extracting file: {}", file_path.display());
+                                FctArchive::extract_entry_from(&self.inner.storage, &self.inner.layout, selected[*slot], file_path, options.overwrite)
+                            }
+                        };
+                        finished.push((*slot, report));
+                    }
+                }
+            })).collect();
+            workers.into_iter().flat_map(|worker| worker.join().unwrap_or_default()).collect()
+        });
+        for (slot, report) in finished {
+            reports[slot] = Some(report);
+        }
+        // entries of a thread that panicked have no report
+        reports.into_iter().enumerate().map(|(slot, report)| match report {
+            Some(report) => report,
+            None => ExtractReport { path: selected[slot].file_path.clone(), action: ExtractAction::Failed("Extraction thread failed") }
+        }).collect()
+    }
+}
+
+impl SharedEntryReader {
+    /// Size of the entry's contents
+    pub fn len(&self) -> u64 {
+        self.extents.iter().map(|extent| extent.length).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Read for SharedEntryReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.extent_index < self.extents.len() && self.extent_position == self.extents[self.extent_index].length {
+            self.extent_index += 1;
+            self.extent_position = 0;
+        }
+        if self.extent_index == self.extents.len() || buf.is_empty() {
+            return Ok(0);
+        }
+        let extent = self.extents[self.extent_index];
+        let wanted = std::cmp::min(buf.len() as u64, extent.length - self.extent_position) as usize;
+        let read = match extent.source {
+            ExtentSource::Archive(offset) => self.archive.inner.storage.read_at(&mut buf[..wanted], offset + self.extent_position)?,
+            ExtentSource::Zero => {
+                buf[..wanted].fill(0);
+                wanted
+            }
+        };
+        if read == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Entry data is truncated"));
+        }
+        self.extent_position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for SharedEntryReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let current: u64 = self.extents[..self.extent_index].iter().map(|extent| extent.length).sum::<u64>() + self.extent_position;
+        let target = match pos {
+            SeekFrom::Start(offset) => offset as i128,
+            SeekFrom::Current(offset) => current as i128 + offset as i128,
+            SeekFrom::End(offset) => self.len() as i128 + offset as i128
+        };
+        if target < 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seek before the start of the entry"));
+        }
+        // find the extent containing the target, positions past the end stay at the end
+        let mut remaining = target as u64;
+        self.extent_index = 0;
+        while self.extent_index < self.extents.len() && remaining >= self.extents[self.extent_index].length {
+            remaining -= self.extents[self.extent_index].length;
+            self.extent_index += 1;
+        }
+        self.extent_position = if self.extent_index < self.extents.len() { remaining } else { 0 };
+        Ok(target as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fct_archive::{ArchiveOptions, OverwritePolicy};
+    use crate::fct_archive::tests::{create_archive, noise, test_dir};
+
+    #[test]
+    fn readers_on_clones_read_and_seek_concurrently() {
+        let mut holes = noise(3000, 1);
+        holes.extend(vec![0; 5000]);
+        holes.extend(noise(2500, 2));
+        let files = vec![
+            ("holes", holes),
+            ("first", noise(6000, 3)),
+            ("copy", noise(6000, 3)),
+            ("small", noise(300, 4)),
+            ("empty", Vec::new()),
+            ("big", noise(50000, 5))
+        ];
+        let all_options = [
+            ArchiveOptions { dedup: true, sparse: true, tail_pack: true, entry_chunk_size: true, modified_time: true, ..Default::default() },
+            ArchiveOptions { volume_size: Some(4096), dedup: true, sparse: true, tail_pack: true, ..Default::default() }
+        ];
+        for (index, options) in all_options.iter().enumerate() {
+            let dir = test_dir(&format!("shared-readers-{}", index));
+            let mut archive = create_archive(&dir, 1024, options, &files);
+            // the plain archive reads the entries through its own cursor
+            let expected: Vec<Vec<u8>> = (0..files.len() as u32).map(|entry| {
+                let mut contents = Vec::new();
+                archive.entry_reader(entry).unwrap().read_to_end(&mut contents).unwrap();
+                contents
+            }).collect();
+            for ((_, contents), read) in files.iter().zip(&expected) {
+                assert_eq!(contents, read);
+            }
+
+            let shared = SharedArchive::from_archive(&mut archive).unwrap();
+            let barrier = std::sync::Barrier::new(4);
+            std::thread::scope(|scope| {
+                for thread in 0..4usize {
+                    let shared = shared.clone();
+                    let (barrier, expected) = (&barrier, &expected);
+                    scope.spawn(move || {
+                        barrier.wait();
+                        // every thread starts at another entry, so that different entries are read at the same time
+                        for step in 0..expected.len() {
+                            let entry = (step + thread) % expected.len();
+                            let contents = &expected[entry];
+                            let mut reader = shared.entry_reader(entry as u32).unwrap();
+                            assert_eq!(reader.len(), contents.len() as u64);
+                            let mut read = Vec::new();
+                            reader.read_to_end(&mut read).unwrap();
+                            assert_eq!(&read, contents);
+                            for round in 0..20 {
+                                let start = (round * 7919 + thread * 131) % (contents.len() + 1);
+                                let position = match round % 3 {
+                                    0 => reader.seek(SeekFrom::Start(start as u64)).unwrap(),
+                                    1 => reader.seek(SeekFrom::End(start as i64 - contents.len() as i64)).unwrap(),
+                                    _ => {
+                                        reader.seek(SeekFrom::Start(contents.len() as u64 / 2)).unwrap();
+                                        reader.seek(SeekFrom::Current(start as i64 - contents.len() as i64 / 2)).unwrap()
+                                    }
+                                };
+                                assert_eq!(position, start as u64);
+                                let mut buffer = vec![0; (contents.len() - start).min(1500)];
+                                reader.read_exact(&mut buffer).unwrap();
+                                assert_eq!(buffer, contents[start..start + buffer.len()]);
+                            }
+                        }
+                    });
+                }
+            });
+            assert_eq!(shared.read_entry(shared.find("big").unwrap()).unwrap(), files[5].1);
+        }
+    }
+
+    // archive files under stored paths with directories, where a path can be stored more than once
+    fn archive_of(dir: &Path, files: &[(&str, Vec<u8>)]) -> FctArchive {
+        let input = dir.join("input");
+        let mut archive = FctArchive::create_with_options(&dir.join("test.fct"), 512, &ArchiveOptions::default()).unwrap();
+        for (name, contents) in files {
+            std::fs::create_dir_all(input.join(name).parent().unwrap()).unwrap();
+            std::fs::write(input.join(name), contents).unwrap();
+            archive.add_file_relative_to(&input.join(name), &input).unwrap();
+        }
+        archive
+    }
+
+    // extracted files and reports, with paths relative to the output folder so that runs can be compared
+    type Extracted = (Vec<(PathBuf, Vec<u8>)>, Vec<(PathBuf, ExtractAction)>);
+
+    fn extracted(output: &Path, reports: Vec<ExtractReport>) -> Extracted {
+        let files = crate::fs_operations::expand_directory(&output.to_path_buf()).into_iter()
+            .map(|path| (path.strip_prefix(output).unwrap().to_path_buf(), std::fs::read(&path).unwrap()))
+            .collect();
+        let relative = |path: PathBuf| path.strip_prefix(output).map(Path::to_path_buf).unwrap_or(path);
+        let reports = reports.into_iter().map(|report| (relative(report.path), match report.action {
+            ExtractAction::Renamed(path) => ExtractAction::Renamed(relative(path)),
+            action => action
+        })).collect();
+        (files, reports)
+    }
+
+    #[test]
+    fn parallel_extraction_matches_serial_extraction() {
+        let dir = test_dir("shared-extract");
+        let files = vec![
+            ("top", noise(700, 1)),
+            ("dir/a", noise(100, 2)),
+            ("dir/sub/deeper/c", noise(2000, 3)),
+            ("dup", noise(50, 4)),
+            ("dir/sub/b", noise(600, 5)),
+            ("other/x", Vec::new()),
+            ("dup", noise(80, 6)),
+            ("dir/a", noise(1500, 7)),
+            ("dup", noise(90, 8))
+        ];
+        let mut archive = archive_of(&dir, &files);
+        let shared = SharedArchive::from_archive(&mut archive).unwrap();
+        for overwrite in [OverwritePolicy::Overwrite, OverwritePolicy::Rename] {
+            let options = ExtractOptions { overwrite, ..Default::default() };
+            let serial_output = dir.join(format!("serial-{:?}", overwrite));
+            let serial = extracted(&serial_output, archive.extract_files_with_options(&serial_output, &mut Vec::new(), &options));
+            // the last entry of a path wins, or every entry gets a name of its own
+            assert_eq!(serial.0.iter().find(|(path, _)| path == Path::new("dup")).unwrap().1, match overwrite {
+                OverwritePolicy::Overwrite => noise(90, 8),
+                _ => noise(50, 4)
+            });
+            for threads in [1, 3, 8] {
+                let output = dir.join(format!("parallel-{:?}-{}", overwrite, threads));
+                let parallel = extracted(&output, shared.extract_files_parallel(&output, &mut Vec::new(), &options, threads));
+                assert_eq!(parallel, serial);
+            }
+            // the reports follow the archive, with the names the entries were written to
+            let report_paths: Vec<&str> = serial.1.iter().map(|(path, _)| path.to_str().unwrap()).collect();
+            assert_eq!(report_paths, match overwrite {
+                OverwritePolicy::Overwrite => files.iter().map(|(name, _)| *name).collect(),
+                _ => vec!["top", "dir/a", "dir/sub/deeper/c", "dup", "dir/sub/b", "other/x", "dup.1", "dir/a.1", "dup.2"]
+            });
+        }
+    }
+
+    #[test]
+    fn failed_directories_fail_only_their_entries() {
+        let dir = test_dir("shared-extract-blocked");
+        let files = vec![
+            ("blocked/a", noise(100, 1)),
+            ("open/b", noise(200, 2)),
+            ("blocked/deep/c", noise(300, 3)),
+            ("top", noise(400, 4)),
+            ("open/deep/d", noise(500, 5))
+        ];
+        let mut archive = archive_of(&dir, &files);
+        let shared = SharedArchive::from_archive(&mut archive).unwrap();
+        let mut runs = Vec::new();
+        for threads in [0, 1, 4] {
+            // a file is in the way of the directory the first and third entry need
+            let output = dir.join(format!("output-{}", threads));
+            std::fs::create_dir_all(&output).unwrap();
+            std::fs::write(output.join("blocked"), b"file").unwrap();
+            let reports = shared.extract_files_parallel(&output, &mut Vec::new(), &ExtractOptions::default(), threads);
+            let actions: Vec<ExtractAction> = reports.iter().map(|report| report.action.clone()).collect();
+            assert_eq!(actions, vec![
+                ExtractAction::Failed("Could not create output folder"),
+                ExtractAction::Extracted,
+                ExtractAction::Failed("Could not create output folder"),
+                ExtractAction::Extracted,
+                ExtractAction::Extracted
+            ]);
+            runs.push(extracted(&output, reports));
+        }
+        let output = dir.join("serial");
+        std::fs::create_dir_all(&output).unwrap();
+        std::fs::write(output.join("blocked"), b"file").unwrap();
+        let serial = extracted(&output, archive.extract_files_with_options(&output, &mut Vec::new(), &ExtractOptions::default()));
+        assert!(runs.iter().all(|run| *run == serial));
+        assert_eq!(serial.0.len(), 4);
+    }
+}
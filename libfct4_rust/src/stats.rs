@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use serde::Serialize;
+
+// number of entries listed as the largest ones
+const LARGEST_ENTRY_COUNT: usize = 10;
+// upper bounds of the size histogram's buckets, the last bucket takes everything bigger
+const HISTOGRAM_BOUNDS: [u64; 7] = [1, 1 << 10, 16 << 10, 64 << 10, 1 << 20, 16 << 20, 1 << 30];
+
+/// How much space a single entry takes up in the archive
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct EntryStats {
+    pub path: PathBuf,
+    /// Size of the entry's contents
+    pub logical_bytes: u64,
+    /// Bytes of chunk data stored for the entry
+    pub stored_bytes: u64,
+    /// Size of the entry's header
+    pub header_bytes: u64,
+    /// Bytes the entry's stored chunks are padded with
+    pub padding_bytes: u64
+}
+
+/// Entries with a size below an upper bound
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SizeBucket {
+    /// Exclusive upper bound of the entries' size, None for the last bucket
+    pub below: Option<u64>,
+    pub entry_count: u64,
+    pub logical_bytes: u64,
+    pub padding_bytes: u64
+}
+
+/// Totals of the entries sharing a file extension
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ExtensionStats {
+    /// The extension without the dot, empty for files without one
+    pub extension: String,
+    pub entry_count: u64,
+    pub logical_bytes: u64,
+    pub stored_bytes: u64,
+    pub padding_bytes: u64
+}
+
+/// Storage efficiency and layout report of an archive
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ArchiveStats {
+    /// Size of the archive file
+    pub archive_bytes: u64,
+    pub chunk_size: u16,
+    pub entry_count: u64,
+    pub logical_bytes: u64,
+    /// Chunk data stored in the archive, including tail blocks
+    pub stored_bytes: u64,
+    /// Archive header, entry headers and tail block headers
+    pub header_bytes: u64,
+    /// Padding of stored chunks, including the unused space of tail blocks
+    pub padding_bytes: u64,
+    pub tail_block_count: u64,
+    pub entries: Vec<EntryStats>,
+    pub histogram: Vec<SizeBucket>,
+    /// Sorted by logical size, biggest first
+    pub extensions: Vec<ExtensionStats>,
+    /// The biggest entries by logical size, biggest first
+    pub largest: Vec<EntryStats>
+}
+
+impl ArchiveStats {
+    /// Sum up the entries and tail blocks of an archive.
+    /// The tail blocks add their headers, their chunks and their space not taken by tails.
+    pub fn new(archive_bytes: u64, chunk_size: u16, archive_header_bytes: u64, entries: Vec<EntryStats>, tail_blocks: &[TailBlockStats]) -> Self {
+        let mut stats = ArchiveStats {
+            archive_bytes,
+            chunk_size,
+            entry_count: entries.len() as u64,
+            header_bytes: archive_header_bytes,
+            tail_block_count: tail_blocks.len() as u64,
+            ..Default::default()
+        };
+        for tail_block in tail_blocks {
+            stats.header_bytes += tail_block.header_bytes;
+            stats.stored_bytes += tail_block.stored_bytes;
+            stats.padding_bytes += tail_block.stored_bytes - tail_block.used_bytes;
+        }
+
+        stats.histogram = HISTOGRAM_BOUNDS.iter().map(|bound| Some(*bound)).chain(std::iter::once(None))
+            .map(|bound| SizeBucket { below: bound, ..Default::default() })
+            .collect();
+        let mut extensions: HashMap<String, ExtensionStats> = HashMap::new();
+        for entry in &entries {
+            stats.logical_bytes += entry.logical_bytes;
+            stats.stored_bytes += entry.stored_bytes;
+            stats.header_bytes += entry.header_bytes;
+            stats.padding_bytes += entry.padding_bytes;
+
+            let bucket_index = HISTOGRAM_BOUNDS.iter().position(|bound| entry.logical_bytes < *bound).unwrap_or(HISTOGRAM_BOUNDS.len());
+            let bucket = &mut stats.histogram[bucket_index];
+            bucket.entry_count += 1;
+            bucket.logical_bytes += entry.logical_bytes;
+            bucket.padding_bytes += entry.padding_bytes;
+
+            let extension = match entry.path.extension() {
+                Some(extension) => extension.to_string_lossy().to_string(),
+                None => String::new()
+            };
+            let totals = extensions.entry(extension.clone()).or_insert_with(|| ExtensionStats { extension, ..Default::default() });
+            totals.entry_count += 1;
+            totals.logical_bytes += entry.logical_bytes;
+            totals.stored_bytes += entry.stored_bytes;
+            totals.padding_bytes += entry.padding_bytes;
+        }
+        stats.extensions = extensions.into_values().collect();
+        stats.extensions.sort_by(|a, b| b.logical_bytes.cmp(&a.logical_bytes).then(a.extension.cmp(&b.extension)));
+
+        let mut largest = entries.clone();
+        // stable, so entries of the same size keep the archive's order
+        largest.sort_by_key(|entry| std::cmp::Reverse(entry.logical_bytes));
+        largest.truncate(LARGEST_ENTRY_COUNT);
+        stats.largest = largest;
+        stats.entries = entries;
+        stats
+    }
+
+    /// Share of the archive taken up by padding, between 0 and 1
+    pub fn padding_ratio(&self) -> f64 {
+        if self.archive_bytes == 0 {
+            return 0.0;
+        }
+        self.padding_bytes as f64 / self.archive_bytes as f64
+    }
+
+    /// The report as pretty printed JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Could not serialize archive stats")
+    }
+}
+
+/// Space taken up by a tail block, as counted by ArchiveStats::new
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TailBlockStats {
+    pub header_bytes: u64,
+    pub stored_bytes: u64,
+    /// Bytes taken by the tails of entries
+    pub used_bytes: u64
+}
+
+/// Format a number of bytes with a binary unit, like "1.5 MiB"
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        return format!("{} B", bytes);
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+impl fmt::Display for ArchiveStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Archive size: {} ({} bytes)", format_size(self.archive_bytes), self.archive_bytes)?;
+        writeln!(f, "Chunk size: {}", self.chunk_size)?;
+        writeln!(f, "Entries: {}", self.entry_count)?;
+        writeln!(f, "Logical size: {}", format_size(self.logical_bytes))?;
+        writeln!(f, "Stored data: {}", format_size(self.stored_bytes))?;
+        writeln!(f, "Headers: {}", format_size(self.header_bytes))?;
+        writeln!(f, "Padding: {} ({:.1}% of the archive)", format_size(self.padding_bytes), self.padding_ratio() * 100.0)?;
+        if self.tail_block_count > 0 {
+            writeln!(f, "Tail blocks: {}", self.tail_block_count)?;
+        }
+
+        writeln!(f, "\nSize histogram:")?;
+        for bucket in &self.histogram {
+            let label = match bucket.below {
+                Some(1) => "empty".to_string(),
+                Some(bound) => format!("< {}", format_size(bound)),
+                None => format!(">= {}", format_size(HISTOGRAM_BOUNDS[HISTOGRAM_BOUNDS.len() - 1]))
+            };
+            writeln!(f, "  {:>10}: {} entries, {}, {} padding", label, bucket.entry_count, format_size(bucket.logical_bytes), format_size(bucket.padding_bytes))?;
+        }
+
+        writeln!(f, "\nExtensions:")?;
+        for extension in &self.extensions {
+            let label = if extension.extension.is_empty() { "(none)" } else { extension.extension.as_str() };
+            writeln!(f, "  {}: {} entries, {}, {} stored, {} padding", label, extension.entry_count, format_size(extension.logical_bytes), format_size(extension.stored_bytes), format_size(extension.padding_bytes))?;
+        }
+
+        write!(f, "\nLargest entries:")?;
+        for entry in &self.largest {
+            write!(f, "\n  {}: {}, {} padding", entry.path.display(), format_size(entry.logical_bytes), format_size(entry.padding_bytes))?;
+        }
+        Ok(())
+    }
+}
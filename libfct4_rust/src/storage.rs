@@ -0,0 +1,587 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use crate::fct_archive::{ARCHIVE_HEADER_MAGIC, EXTENDED_ARCHIVE_HEADER_MAGIC};
+use crate::fs_operations;
+
+/// Marks the footer of a file with an embedded archive, like a self-extracting archive
+pub const EMBEDDED_FOOTER_MAGIC: &[u8; 8] = b"FCT4SFX\0";
+/// The footer holds the offset of the archive as u64 followed by the magic
+pub const EMBEDDED_FOOTER_SIZE: u64 = 16;
+/// Size of the runs data is moved in when it cannot be copied inside the kernel.
+/// Big runs keep the number of reads and writes low, whatever the chunk size is.
+pub const COPY_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+/// The files an archive is stored in, read and written as one continuous stream
+pub enum ArchiveStorage {
+    Single(File),
+    Volumes(VolumeSet),
+    Embedded(EmbeddedArchive)
+}
+
+/// An archive stored inside a bigger file, between the offset given by the footer and the footer itself.
+/// Embedded archives are read only, as the footer has to stay at the end of the file.
+pub struct EmbeddedArchive {
+    file: File,
+    start: u64,
+    length: u64,
+    position: u64
+}
+
+/// An archive split into volumes named like the archive with a numeric extension (name.fct.001, name.fct.002, ...).
+/// Every volume continues the stream where the previous one ends.
+pub struct VolumeSet {
+    base_path: PathBuf,
+    volumes: Vec<File>,
+    // position of every volume's first byte in the stream
+    starts: Vec<u64>,
+    lengths: Vec<u64>,
+    position: u64
+}
+
+/// Path of the volume with the given index, counted from 0, of the archive at the base path
+pub fn volume_path(base_path: &Path, index: usize) -> PathBuf {
+    let mut path = base_path.as_os_str().to_owned();
+    path.push(format!(".{:03}", index + 1));
+    PathBuf::from(path)
+}
+
+/// The path an archive is known by: the path itself, or the base path if the first volume is given
+pub fn archive_base_path(path: &Path) -> PathBuf {
+    match path.extension() {
+        Some(extension) if extension == "001" && path.is_file() => path.with_extension(""),
+        _ => path.to_path_buf()
+    }
+}
+
+/// The file the archive at the base path starts with, which is the first volume of split archives
+pub fn first_file(base_path: &Path) -> PathBuf {
+    match is_volume_set(base_path) {
+        true => volume_path(base_path, 0),
+        false => base_path.to_path_buf()
+    }
+}
+
+/// Whether the archive at the base path is split into volumes
+pub fn is_volume_set(base_path: &Path) -> bool {
+    !base_path.is_file() && volume_path(base_path, 0).is_file()
+}
+
+// paths of all volumes of the archive at the base path, in order
+fn existing_volumes(base_path: &Path) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    loop {
+        let path = volume_path(base_path, paths.len());
+        if !path.is_file() {
+            return paths;
+        }
+        paths.push(path);
+    }
+}
+
+/// Whether the file at the path belongs to the archive at the base path, as the archive itself or as one of its volumes
+pub fn is_archive_file(base_path: &Path, path: &Path) -> bool {
+    if path == base_path {
+        return true;
+    }
+    match (path.parent(), path.file_name(), base_path.file_name()) {
+        (Some(parent), Some(name), Some(base_name)) if Some(parent) == base_path.parent() => {
+            let (name, base_name) = (name.to_string_lossy(), base_name.to_string_lossy());
+            match name.strip_prefix(base_name.as_ref()).and_then(|suffix| suffix.strip_prefix('.')) {
+                Some(number) => number.len() == 3 && number.chars().all(|c| c.is_ascii_digit()),
+                None => false
+            }
+        },
+        _ => false
+    }
+}
+
+/// All files the archive at the base path is stored in
+pub fn archive_files(base_path: &Path) -> Vec<PathBuf> {
+    match is_volume_set(base_path) {
+        true => existing_volumes(base_path),
+        false => vec![base_path.to_path_buf()]
+    }
+}
+
+/// Delete an archive together with all of its volumes
+pub fn remove_archive(base_path: &Path) -> io::Result<()> {
+    if base_path.is_file() {
+        fs::remove_file(base_path)?;
+    }
+    for path in existing_volumes(base_path) {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// A path next to the archive for a temporary archive that takes nothing existing, neither a file nor volumes
+pub fn temporary_path(archive_path: &Path) -> PathBuf {
+    let mut file_name = archive_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".tmp");
+    let path = archive_path.with_file_name(file_name);
+    let taken = |candidate: &Path| candidate.exists() || volume_path(candidate, 0).exists();
+    match taken(&path) {
+        true => fs_operations::find_free_name(&path, taken),
+        false => path
+    }
+}
+
+/// Replace the archive at the target path with the one at the source path, moving all of its volumes.
+/// Every file is renamed over the one it replaces, and only the files of the old archive that are left over are
+/// deleted afterwards, so the old archive is not lost if a rename fails.
+pub fn replace_archive(source_path: &Path, target_path: &Path) -> io::Result<()> {
+    let old_volumes = existing_volumes(target_path);
+    if !is_volume_set(source_path) {
+        fs::rename(source_path, target_path)?;
+        for path in old_volumes {
+            fs::remove_file(path)?;
+        }
+        return Ok(());
+    }
+    let volumes = existing_volumes(source_path);
+    for (index, path) in volumes.iter().enumerate() {
+        fs::rename(path, volume_path(target_path, index))?;
+    }
+    if target_path.is_file() {
+        fs::remove_file(target_path)?;
+    }
+    for path in old_volumes.iter().skip(volumes.len()) {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Write the footer that locates an archive embedded at the given offset, after the archive
+pub fn write_embedded_footer<W: Write>(writer: &mut W, offset: u64) -> io::Result<()> {
+    writer.write_all(&offset.to_le_bytes())?;
+    writer.write_all(EMBEDDED_FOOTER_MAGIC)
+}
+
+// offset and length of an archive embedded in the file, None if the file is an archive itself or has no footer
+fn embedded_range(file: &mut File) -> io::Result<Option<(u64, u64)>> {
+    let mut magic = [0u8; 3];
+    if file.read_exact(&mut magic).is_ok() && (magic == ARCHIVE_HEADER_MAGIC.as_bytes() || magic == EXTENDED_ARCHIVE_HEADER_MAGIC.as_bytes()) {
+        file.seek(SeekFrom::Start(0))?;
+        return Ok(None);
+    }
+    let file_length = file.seek(SeekFrom::End(0))?;
+    if file_length < EMBEDDED_FOOTER_SIZE {
+        file.seek(SeekFrom::Start(0))?;
+        return Ok(None);
+    }
+    let mut footer = [0u8; EMBEDDED_FOOTER_SIZE as usize];
+    file.seek(SeekFrom::Start(file_length - EMBEDDED_FOOTER_SIZE))?;
+    file.read_exact(&mut footer)?;
+    file.seek(SeekFrom::Start(0))?;
+    let offset = u64::from_le_bytes(footer[..8].try_into().unwrap());
+    if &footer[8..] != EMBEDDED_FOOTER_MAGIC || offset > file_length - EMBEDDED_FOOTER_SIZE {
+        return Ok(None);
+    }
+    Ok(Some((offset, file_length - EMBEDDED_FOOTER_SIZE - offset)))
+}
+
+// archives that cannot be written to, like a running self-extracting archive, are still opened for reading
+fn open_file(path: &Path) -> io::Result<File> {
+    match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => Ok(file),
+        Err(_) => OpenOptions::new().read(true).open(path)
+    }
+}
+
+impl ArchiveStorage {
+    /// Open an existing archive, which is either a single file, a volume set or embedded in another file
+    pub fn open(base_path: &Path) -> io::Result<Self> {
+        if !is_volume_set(base_path) {
+            let mut file = open_file(base_path)?;
+            return match embedded_range(&mut file)? {
+                Some((start, length)) => Ok(ArchiveStorage::Embedded(EmbeddedArchive { file, start, length, position: 0 })),
+                None => Ok(ArchiveStorage::Single(file))
+            };
+        }
+        let mut volume_set = VolumeSet { base_path: base_path.to_path_buf(), volumes: Vec::new(), starts: Vec::new(), lengths: Vec::new(), position: 0 };
+        let mut start = 0;
+        for path in existing_volumes(base_path) {
+            let file = open_file(&path)?;
+            let length = file.metadata()?.len();
+            volume_set.volumes.push(file);
+            volume_set.starts.push(start);
+            volume_set.lengths.push(length);
+            start += length;
+        }
+        Ok(ArchiveStorage::Volumes(volume_set))
+    }
+
+    /// Create a new archive, as a volume set if it is going to be split
+    pub fn create(base_path: &Path, split: bool) -> io::Result<Self> {
+        if !split {
+            let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(base_path)?;
+            return Ok(ArchiveStorage::Single(file));
+        }
+        // volumes left over from an earlier archive of the same name would be read as part of the new one
+        remove_archive(base_path)?;
+        let mut volume_set = VolumeSet { base_path: base_path.to_path_buf(), volumes: Vec::new(), starts: Vec::new(), lengths: Vec::new(), position: 0 };
+        volume_set.start_volume(0)?;
+        Ok(ArchiveStorage::Volumes(volume_set))
+    }
+
+    /// Number of files the archive is stored in
+    pub fn volume_count(&self) -> usize {
+        match self {
+            ArchiveStorage::Single(_) | ArchiveStorage::Embedded(_) => 1,
+            ArchiveStorage::Volumes(volume_set) => volume_set.volumes.len()
+        }
+    }
+
+    /// Whether bytes written at the given position would not fit into the last volume anymore.
+    /// Single files are never split.
+    pub fn needs_new_volume(&self, position: u64, length: u64, max_volume_size: u64) -> bool {
+        let volume_set = match self {
+            ArchiveStorage::Single(_) | ArchiveStorage::Embedded(_) => return false,
+            ArchiveStorage::Volumes(volume_set) => volume_set
+        };
+        let used = position.saturating_sub(volume_set.starts[volume_set.starts.len() - 1]);
+        // a volume takes at least one write, even if that is bigger than the volume size
+        used > 0 && used + length > max_volume_size
+    }
+
+    /// Number of bytes that still fit into the last volume when writing at the given position.
+    /// Single files are never split, so everything fits.
+    pub fn volume_space(&self, position: u64, max_volume_size: u64) -> u64 {
+        let volume_set = match self {
+            ArchiveStorage::Single(_) | ArchiveStorage::Embedded(_) => return u64::MAX,
+            ArchiveStorage::Volumes(volume_set) => volume_set
+        };
+        let used = position.saturating_sub(volume_set.starts[volume_set.starts.len() - 1]);
+        max_volume_size.saturating_sub(used)
+    }
+
+    /// Continue the archive in a new volume starting at the given position, which has to be the end of the archive
+    pub fn start_volume(&mut self, position: u64) -> io::Result<()> {
+        match self {
+            ArchiveStorage::Single(_) | ArchiveStorage::Embedded(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "The archive is not split into volumes")),
+            ArchiveStorage::Volumes(volume_set) => volume_set.start_volume(position)
+        }
+    }
+
+    /// Cut the archive back to the given length, removing the volumes that start behind it
+    pub fn truncate(&mut self, length: u64) -> io::Result<()> {
+        match self {
+            ArchiveStorage::Single(file) => file.set_len(length),
+            ArchiveStorage::Embedded(_) => Err(io::Error::new(io::ErrorKind::PermissionDenied, "Embedded archives are read only")),
+            ArchiveStorage::Volumes(volume_set) => volume_set.truncate(length)
+        }
+    }
+}
+
+impl VolumeSet {
+    // append a new, empty volume starting at the given position, which has to be the end of the stream
+    fn start_volume(&mut self, position: u64) -> io::Result<()> {
+        if position != self.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Volumes can only be started at the end of the archive"));
+        }
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(volume_path(&self.base_path, self.volumes.len()))?;
+        self.volumes.push(file);
+        self.starts.push(position);
+        self.lengths.push(0);
+        Ok(())
+    }
+
+    fn truncate(&mut self, length: u64) -> io::Result<()> {
+        // the first volume holds the archive header and is always kept
+        while self.volumes.len() > 1 && self.starts[self.volumes.len() - 1] >= length {
+            self.volumes.pop();
+            self.starts.pop();
+            self.lengths.pop();
+            fs::remove_file(volume_path(&self.base_path, self.volumes.len()))?;
+        }
+        let last = self.volumes.len() - 1;
+        let volume_length = length.saturating_sub(self.starts[last]);
+        self.volumes[last].set_len(volume_length)?;
+        self.lengths[last] = volume_length;
+        self.position = std::cmp::min(self.position, length);
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        match self.volumes.len() {
+            0 => 0,
+            n => self.starts[n - 1] + self.lengths[n - 1]
+        }
+    }
+
+    // index of the volume holding the byte at the current position, the last volume holds everything past the end
+    fn current_volume(&self) -> usize {
+        self.volume_at(self.position)
+    }
+
+    fn volume_at(&self, position: u64) -> usize {
+        self.starts.iter().rposition(|start| *start <= position).unwrap_or_default()
+    }
+}
+
+// read at an offset of a file without using its cursor, so that threads can read from the same file at once
+#[cfg(unix)]
+fn read_file_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+// moves the cursor, which positional reads do not depend on
+#[cfg(windows)]
+fn read_file_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+impl ArchiveStorage {
+    /// Read at the given position of the stream without moving the cursor.
+    /// Like read, reads stop at the end of a volume.
+    pub fn read_at(&self, buf: &mut [u8], position: u64) -> io::Result<usize> {
+        match self {
+            ArchiveStorage::Single(file) => read_file_at(file, buf, position),
+            ArchiveStorage::Embedded(embedded) => {
+                if position >= embedded.length {
+                    return Ok(0);
+                }
+                let wanted = std::cmp::min(buf.len() as u64, embedded.length - position) as usize;
+                read_file_at(&embedded.file, &mut buf[..wanted], embedded.start + position)
+            },
+            ArchiveStorage::Volumes(volume_set) => {
+                let index = volume_set.volume_at(position);
+                let offset = position - volume_set.starts[index];
+                if offset >= volume_set.lengths[index] {
+                    return Ok(0);
+                }
+                let wanted = std::cmp::min(buf.len() as u64, volume_set.lengths[index] - offset) as usize;
+                read_file_at(&volume_set.volumes[index], &mut buf[..wanted], offset)
+            }
+        }
+    }
+
+    /// The file holding the byte at the given position of the stream, the position of the byte in that file and the
+    /// number of bytes of the stream following in the file from there. None past the end of the stream.
+    pub fn file_at(&self, position: u64) -> Option<(&File, u64, u64)> {
+        match self {
+            ArchiveStorage::Single(file) => match file.metadata() {
+                Ok(metadata) if position < metadata.len() => Some((file, position, metadata.len() - position)),
+                _ => None
+            },
+            ArchiveStorage::Embedded(embedded) if position < embedded.length => {
+                Some((&embedded.file, embedded.start + position, embedded.length - position))
+            },
+            ArchiveStorage::Embedded(_) => None,
+            ArchiveStorage::Volumes(volume_set) => {
+                let index = volume_set.volume_at(position);
+                let offset = position - volume_set.starts[index];
+                match offset < volume_set.lengths[index] {
+                    true => Some((&volume_set.volumes[index], offset, volume_set.lengths[index] - offset)),
+                    false => None
+                }
+            }
+        }
+    }
+
+    /// Copy bytes of the stream to a file at its cursor, which is left behind the copied bytes, without moving the
+    /// cursor of the stream. The bytes are copied inside the kernel where the file systems allow it and through the
+    /// buffer otherwise, which is only allocated when it is needed.
+    pub fn copy_to(&self, position: u64, length: u64, file: &mut File, buffer: &mut Vec<u8>) -> io::Result<()> {
+        let start = file.stream_position()?;
+        let mut copied: u64 = 0;
+        while copied < length {
+            let (source, source_offset, available) = match self.file_at(position + copied) {
+                Some(run) => run,
+                None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Entry data is truncated"))
+            };
+            let wanted = std::cmp::min(available, length - copied);
+            let kernel_copied = fs_operations::copy_file_range(source, source_offset, file, start + copied, wanted)?;
+            if kernel_copied > 0 {
+                copied += kernel_copied;
+                continue;
+            }
+            if buffer.is_empty() {
+                buffer.resize(COPY_BUFFER_SIZE, 0);
+            }
+            let wanted = std::cmp::min(wanted, buffer.len() as u64) as usize;
+            let read = read_file_at(source, &mut buffer[..wanted], source_offset)?;
+            if read == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Entry data is truncated"));
+            }
+            file.seek(SeekFrom::Start(start + copied))?;
+            file.write_all(&buffer[..read])?;
+            copied += read as u64;
+        }
+        file.seek(SeekFrom::Start(start + length))?;
+        Ok(())
+    }
+}
+
+impl Read for ArchiveStorage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let volume_set = match self {
+            ArchiveStorage::Single(file) => return file.read(buf),
+            ArchiveStorage::Embedded(embedded) => return embedded.read(buf),
+            ArchiveStorage::Volumes(volume_set) => volume_set
+        };
+        let index = volume_set.current_volume();
+        let offset = volume_set.position - volume_set.starts[index];
+        if offset >= volume_set.lengths[index] {
+            return Ok(0);
+        }
+        // reads stop at the end of a volume, the next read continues in the following one
+        let wanted = std::cmp::min(buf.len() as u64, volume_set.lengths[index] - offset) as usize;
+        let file = &mut volume_set.volumes[index];
+        file.seek(SeekFrom::Start(offset))?;
+        let read = file.read(&mut buf[..wanted])?;
+        volume_set.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for ArchiveStorage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let volume_set = match self {
+            ArchiveStorage::Single(file) => return file.write(buf),
+            ArchiveStorage::Embedded(_) => return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Embedded archives are read only")),
+            ArchiveStorage::Volumes(volume_set) => volume_set
+        };
+        let index = volume_set.current_volume();
+        let offset = volume_set.position - volume_set.starts[index];
+        let last = index == volume_set.volumes.len() - 1;
+        if offset > volume_set.lengths[index] {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot write past the end of the archive"));
+        }
+        // only the last volume grows, the others are overwritten up to their end
+        let wanted = match last {
+            true => buf.len(),
+            false => std::cmp::min(buf.len() as u64, volume_set.lengths[index] - offset) as usize
+        };
+        let file = &mut volume_set.volumes[index];
+        file.seek(SeekFrom::Start(offset))?;
+        let written = file.write(&buf[..wanted])?;
+        volume_set.position += written as u64;
+        if last && offset + written as u64 > volume_set.lengths[index] {
+            volume_set.lengths[index] = offset + written as u64;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ArchiveStorage::Single(file) => file.flush(),
+            ArchiveStorage::Embedded(_) => Ok(()),
+            ArchiveStorage::Volumes(volume_set) => {
+                for file in &mut volume_set.volumes {
+                    file.flush()?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Seek for ArchiveStorage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let volume_set = match self {
+            ArchiveStorage::Single(file) => return file.seek(pos),
+            ArchiveStorage::Embedded(embedded) => return embedded.seek(pos),
+            ArchiveStorage::Volumes(volume_set) => volume_set
+        };
+        let target = match pos {
+            SeekFrom::Start(offset) => offset as i128,
+            SeekFrom::Current(offset) => volume_set.position as i128 + offset as i128,
+            SeekFrom::End(offset) => volume_set.len() as i128 + offset as i128
+        };
+        if target < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek before the start of the archive"));
+        }
+        volume_set.position = target as u64;
+        Ok(volume_set.position)
+    }
+}
+
+impl EmbeddedArchive {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.length {
+            return Ok(0);
+        }
+        // the footer is not part of the archive
+        let wanted = std::cmp::min(buf.len() as u64, self.length - self.position) as usize;
+        self.file.seek(SeekFrom::Start(self.start + self.position))?;
+        let read = self.file.read(&mut buf[..wanted])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => offset as i128,
+            SeekFrom::Current(offset) => self.position as i128 + offset as i128,
+            SeekFrom::End(offset) => self.length as i128 + offset as i128
+        };
+        if target < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek before the start of the archive"));
+        }
+        self.position = target as u64;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fct_archive::{ArchiveOptions, FctArchive};
+    use crate::fct_archive::tests::{assert_contents, create_archive, noise, test_dir};
+
+    #[test]
+    fn embedded_footer_locates_the_archive() {
+        let dir = test_dir("embedded");
+        let files = vec![("first", noise(5000, 18)), ("second", noise(700, 19))];
+        let options = ArchiveOptions { tail_pack: true, ..Default::default() };
+        let mut archive = create_archive(&dir, 512, &options, &files);
+        let stub_path = dir.join("stub");
+        let stub = noise(3000, 20);
+        std::fs::write(&stub_path, &stub).unwrap();
+        let sfx_path = dir.join("sfx");
+        archive.write_self_extracting(&stub_path, &sfx_path).unwrap();
+
+        let archive_bytes = std::fs::read(dir.join("test.fct")).unwrap();
+        let sfx_bytes = std::fs::read(&sfx_path).unwrap();
+        assert_eq!(sfx_bytes.len() as u64, stub.len() as u64 + archive_bytes.len() as u64 + EMBEDDED_FOOTER_SIZE);
+        assert!(sfx_bytes[..stub.len()] == stub[..]);
+        // positions of the storage are positions of the archive inside the file
+        let storage = ArchiveStorage::open(&sfx_path).unwrap();
+        assert!(matches!(storage, ArchiveStorage::Embedded(_)));
+        let mut read = vec![0u8; archive_bytes.len()];
+        let mut position = 0;
+        while position < read.len() {
+            position += storage.read_at(&mut read[position..], position as u64).unwrap();
+        }
+        assert!(read == archive_bytes);
+        assert_eq!(storage.read_at(&mut [0u8; 16], archive_bytes.len() as u64).unwrap(), 0);
+
+        let mut embedded = FctArchive::open(&sfx_path).unwrap();
+        assert_contents(&mut embedded, &files);
+        // writing would move the footer, so embedded archives stay as they are
+        let added = dir.join("input").join("first");
+        assert!(embedded.add_file_relative_to(&added, &dir.join("input")).is_err());
+        assert!(embedded.remove_files(&[0]).is_err());
+        assert!(std::fs::read(&sfx_path).unwrap() == sfx_bytes);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn files_without_a_valid_footer_are_not_embedded() {
+        let dir = test_dir("not-embedded");
+        let path = dir.join("file");
+        // the wrong magic, and an offset past the footer
+        let mut wrong_magic = noise(100, 21);
+        wrong_magic.extend_from_slice(&10u64.to_le_bytes());
+        wrong_magic.extend_from_slice(b"FCT4SFX1");
+        let mut past_footer = noise(100, 22);
+        write_embedded_footer(&mut past_footer, 200).unwrap();
+        for contents in [wrong_magic, past_footer, noise(10, 23)] {
+            std::fs::write(&path, contents).unwrap();
+            assert!(matches!(ArchiveStorage::open(&path).unwrap(), ArchiveStorage::Single(_)));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,322 @@
+use std::borrow::Cow;
+use std::path::Path;
+use crate::fct_archive::{ARCHIVE_HEADER_MAGIC, ARCHIVE_HEADER_SIZE, EXTENDED_ARCHIVE_HEADER_MAGIC, EXTENDED_ARCHIVE_HEADER_SIZE, VOLUME_SIZE_FIELD_SIZE};
+use crate::file_parser::{self, ArchiveLayout, Extent, ExtentSource, FileParser};
+
+/// Chunk size, feature flags and the offset of the first entry from the archive header at the start of the data
+pub(crate) fn parse_archive_header(data: &[u8]) -> Result<(u16, u16, usize), &'static str> {
+    if data.len() < ARCHIVE_HEADER_SIZE {
+        return Err("Invalid archive header");
+    }
+    let chunk_size = u16::from_le_bytes([data[3], data[4]]);
+    let (features, data_start) = if &data[..3] == ARCHIVE_HEADER_MAGIC.as_bytes() {
+        (0, ARCHIVE_HEADER_SIZE)
+    }
+    else if &data[..3] == EXTENDED_ARCHIVE_HEADER_MAGIC.as_bytes() && data.len() >= EXTENDED_ARCHIVE_HEADER_SIZE {
+        (u16::from_le_bytes([data[5], data[6]]), EXTENDED_ARCHIVE_HEADER_SIZE)
+    }
+    else {
+        return Err("Invalid archive header");
+    };
+    if features & !file_parser::KNOWN_FEATURES != 0 {
+        return Err("Archive uses unsupported features");
+    }
+    let data_start = match features & file_parser::FEATURE_MULTI_VOLUME {
+        0 => data_start,
+        _ => data_start + VOLUME_SIZE_FIELD_SIZE
+    };
+    if data.len() < data_start {
+        return Err("Invalid archive header");
+    }
+    Ok((chunk_size, features, data_start))
+}
+
+/// Archive header for the given chunk size and feature flags, with the volume size of split archives
+pub(crate) fn generate_archive_header(chunk_size: u16, features: u16, volume_size: Option<u64>) -> Vec<u8> {
+    let mut header = Vec::with_capacity(EXTENDED_ARCHIVE_HEADER_SIZE + VOLUME_SIZE_FIELD_SIZE);
+    // plain archives keep the original header so that they stay readable by older versions
+    if features == 0 {
+        header.extend_from_slice(ARCHIVE_HEADER_MAGIC.as_bytes());
+        header.extend_from_slice(&chunk_size.to_le_bytes());
+        return header;
+    }
+    header.extend_from_slice(EXTENDED_ARCHIVE_HEADER_MAGIC.as_bytes());
+    header.extend_from_slice(&chunk_size.to_le_bytes());
+    header.extend_from_slice(&features.to_le_bytes());
+    if let Some(volume_size) = volume_size {
+        header.extend_from_slice(&volume_size.to_le_bytes());
+    }
+    header
+}
+
+/// Read-only view of an archive held in memory, like a loaded file or a memory map.
+/// Entries are parsed while iterating over them, and their contents are borrowed from the data wherever they are
+/// stored in one piece. Every header is checked against the size of the data, so damaged archives give errors.
+/// Split archives can only be viewed if they consist of a single volume.
+#[derive(Debug, Clone, Copy)]
+pub struct FctView<'a> {
+    data: &'a [u8],
+    chunk_size: u16,
+    features: u16,
+    data_start: usize
+}
+
+/// An entry of a viewed archive
+#[derive(Debug, Clone)]
+pub struct ViewEntry<'a> {
+    pub header: FileParser,
+    data: &'a [u8],
+    extents: Vec<Extent>
+}
+
+impl<'a> FctView<'a> {
+    /// Read the archive header, leaving the entries until they are asked for
+    pub fn new(data: &'a [u8]) -> Result<Self, &'static str> {
+        let (chunk_size, features, data_start) = parse_archive_header(data)?;
+        Ok(FctView { data, chunk_size, features, data_start })
+    }
+
+    pub fn chunk_size(&self) -> u16 {
+        self.chunk_size
+    }
+
+    /// Feature flags of the archive
+    pub fn features(&self) -> u16 {
+        self.features
+    }
+
+    /// Iterate over the entries in the order they are stored in, stopping after the first damaged one
+    pub fn entries(&self) -> ViewEntries<'a> {
+        ViewEntries {
+            view: *self,
+            offset: self.data_start,
+            layout: ArchiveLayout { chunk_size: self.chunk_size, features: self.features, chunk_offsets: Vec::new(), tail_blocks: Vec::new() },
+            failed: false
+        }
+    }
+
+    /// Find the entry with the given stored path, reading the entries up to it
+    pub fn find<P: AsRef<Path>>(&self, path: P) -> Result<Option<ViewEntry<'a>>, &'static str> {
+        for entry in self.entries() {
+            let entry = entry?;
+            if entry.header.file_path == path.as_ref() {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'a> ViewEntry<'a> {
+    /// Size of the entry's contents
+    pub fn len(&self) -> u64 {
+        self.header.logical_size()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The contents as a slice of the archive data, if they are stored in one piece
+    pub fn as_slice(&self) -> Option<&'a [u8]> {
+        match self.extents.as_slice() {
+            [] => Some(&self.data[..0]),
+            [Extent { length, source: ExtentSource::Archive(offset) }] => Some(&self.data[*offset as usize..(*offset + *length) as usize]),
+            _ => None
+        }
+    }
+
+    /// The contents, borrowed if they are stored in one piece and put together otherwise
+    pub fn contents(&self) -> Cow<'a, [u8]> {
+        if let Some(slice) = self.as_slice() {
+            return Cow::Borrowed(slice);
+        }
+        let mut contents = Vec::with_capacity(self.len() as usize);
+        for extent in &self.extents {
+            match extent.source {
+                ExtentSource::Archive(offset) => contents.extend_from_slice(&self.data[offset as usize..(offset + extent.length) as usize]),
+                ExtentSource::Zero => contents.resize(contents.len() + extent.length as usize, 0)
+            }
+        }
+        Cow::Owned(contents)
+    }
+}
+
+/// Iterator over the entries of a viewed archive
+#[derive(Debug, Clone)]
+pub struct ViewEntries<'a> {
+    view: FctView<'a>,
+    offset: usize,
+    // chunk store and tail blocks of the records read so far
+    layout: ArchiveLayout,
+    failed: bool
+}
+
+impl<'a> ViewEntries<'a> {
+    // parse the record at the current offset and move past it
+    fn next_record(&mut self) -> Result<FileParser, &'static str> {
+        let data = self.view.data;
+        let mut reader = &data[self.offset..];
+        let mut header = FileParser::from_archive_with_features(&mut reader, self.view.features, self.view.chunk_size)?;
+        if header.last_chunk_size > header.chunk_size {
+            return Err("Final Chunk is too big");
+        }
+        let data_offset = data.len() - reader.len();
+        let end = match (data_offset as u64).checked_add(header.stored_size()) {
+            Some(end) if end <= data.len() as u64 => end,
+            _ => return Err("Entry data is incomplete")
+        };
+        header.data_offset = data_offset as u64;
+        self.offset = end as usize;
+        Ok(header)
+    }
+
+    fn next_entry(&mut self) -> Result<Option<ViewEntry<'a>>, &'static str> {
+        loop {
+            if self.offset >= self.view.data.len() {
+                return Ok(None);
+            }
+            let header = self.next_record()?;
+            self.layout.add_record(&header);
+            if header.is_tail_block() {
+                continue;
+            }
+            let extents = header.extents(&self.layout)?;
+            // references into tail blocks are not covered by the size of the entry's own data
+            for extent in &extents {
+                if let ExtentSource::Archive(offset) = extent.source {
+                    if offset + extent.length > self.view.data.len() as u64 {
+                        return Err("Entry data is incomplete");
+                    }
+                }
+            }
+            return Ok(Some(ViewEntry { header, data: self.view.data, extents }));
+        }
+    }
+}
+
+impl<'a> Iterator for ViewEntries<'a> {
+    type Item = Result<ViewEntry<'a>, &'static str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.next_entry() {
+            Ok(entry) => entry.map(Ok),
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// An archive file mapped into memory, to be read through a view
+#[cfg(feature = "memmap2")]
+pub struct MappedArchive {
+    map: memmap2::Mmap
+}
+
+#[cfg(feature = "memmap2")]
+impl MappedArchive {
+    /// Map the archive file at the given path.
+    /// The file must not be changed while it is mapped, as the views would change under the borrowed contents.
+    pub fn open<P: AsRef<Path>>(archive_path: P) -> Result<Self, &'static str> {
+        let file = match std::fs::File::open(archive_path) {
+            Ok(file) => file,
+            Err(_) => return Err("Error opening archive file")
+        };
+        match unsafe { memmap2::Mmap::map(&file) } {
+            Ok(map) => Ok(MappedArchive { map }),
+            Err(_) => Err("Could not map archive file")
+        }
+    }
+
+    pub fn view(&self) -> Result<FctView<'_>, &'static str> {
+        FctView::new(&self.map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fct_archive::ArchiveOptions;
+    use crate::fct_archive::tests::{create_archive, noise, test_dir};
+
+    // the bytes of an archive of the files with the given options
+    fn archive_bytes(name: &str, options: &ArchiveOptions, files: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let dir = test_dir(name);
+        drop(create_archive(&dir, 512, options, files));
+        let bytes = std::fs::read(dir.join("test.fct")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        bytes
+    }
+
+    fn test_files() -> Vec<(&'static str, Vec<u8>)> {
+        let mut sparse = noise(600, 24);
+        sparse.resize(3000, 0);
+        vec![("plain", noise(2000, 25)), ("copy", noise(2000, 25)), ("sparse", sparse), ("tail", noise(100, 26)), ("empty", Vec::new())]
+    }
+
+    #[test]
+    fn view_reads_entries_of_all_features() {
+        let files = test_files();
+        let plain = archive_bytes("view-plain", &ArchiveOptions::default(), &files);
+        let options = ArchiveOptions { dedup: true, sparse: true, tail_pack: true, entry_chunk_size: true, ..Default::default() };
+        let extended = archive_bytes("view-extended", &options, &files);
+        for bytes in [&plain, &extended] {
+            let view = FctView::new(bytes).unwrap();
+            let entries: Vec<ViewEntry> = view.entries().map(|entry| entry.unwrap()).collect();
+            assert_eq!(entries.len(), files.len());
+            for (entry, (name, contents)) in entries.iter().zip(&files) {
+                assert_eq!(entry.header.file_path, Path::new(name));
+                assert!(entry.contents()[..] == contents[..], "contents of {} differ", name);
+            }
+            assert!(view.find("tail").unwrap().unwrap().contents()[..] == files[3].1[..]);
+            assert!(view.find("missing").unwrap().is_none());
+        }
+        // entries of plain archives are stored in one piece and borrowed from the data
+        let view = FctView::new(&plain).unwrap();
+        let first = view.entries().next().unwrap().unwrap();
+        assert!(first.as_slice().unwrap() == &files[0].1[..]);
+        assert!(matches!(first.contents(), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn view_checks_bounds_of_damaged_archives() {
+        let files = test_files();
+        let options = ArchiveOptions { dedup: true, sparse: true, tail_pack: true, ..Default::default() };
+        let bytes = archive_bytes("view-damaged", &options, &files);
+        // every truncation gives the complete entries in front of it and then at most one error
+        for length in 0..bytes.len() {
+            let view = match FctView::new(&bytes[..length]) {
+                Ok(view) => view,
+                Err(_) => continue
+            };
+            let mut entries = view.entries();
+            for (name, contents) in &files {
+                match entries.next() {
+                    Some(Ok(entry)) => {
+                        assert_eq!(entry.header.file_path, Path::new(name));
+                        assert!(entry.contents()[..] == contents[..]);
+                    },
+                    _ => break
+                }
+            }
+            assert!(entries.next().is_none());
+        }
+        // damaged bytes anywhere give errors instead of reading out of bounds
+        for position in 0..bytes.len() {
+            for value in [0x00, 0x7f, 0xff] {
+                let mut damaged = bytes.clone();
+                damaged[position] = value;
+                if let Ok(view) = FctView::new(&damaged) {
+                    for entry in view.entries().flatten() {
+                        assert_eq!(entry.contents().len() as u64, entry.len());
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,493 @@
+use std::collections::HashMap;
+use std::io::{Seek, SeekFrom, Write};
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use crate::fct_archive::FctArchive;
+use crate::{file_parser, fs_operations, storage};
+use crate::error::*;
+
+/// When the watched archive is moved aside and a new, empty one is started in its place
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RotationPolicy {
+    /// Rotate once the archive is at least this many bytes big
+    pub max_size: Option<u64>,
+    /// Rotate once the archive holds at least this many entries
+    pub max_entries: Option<usize>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchOptions {
+    /// How long a file has to stay untouched before it counts as complete and is added
+    pub debounce: Duration,
+    pub rotation: RotationPolicy
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        WatchOptions { debounce: Duration::from_secs(2), rotation: RotationPolicy::default() }
+    }
+}
+
+/// Adds the files that appear below a directory to an archive once they are complete.
+/// The archive is append only: files written again after being added are added again as a new entry, which is the one
+/// extracted last. Files whose size and modification time are the same as when they were added are not added again.
+pub struct ArchiveWatcher {
+    archive: FctArchive,
+    options: WatchOptions,
+    inotify: Inotify,
+    // the watched directory, which is scanned again if events were lost
+    directory: PathBuf,
+    // files with unfinished writes and the time of their last change
+    pending: HashMap<PathBuf, Instant>,
+    // size and modification time of the archived files, the latter in seconds and, if known, nanoseconds
+    archived: HashMap<PathBuf, (u64, u64, Option<u32>)>,
+    // the archive and the archives rotated out of the way, which must not be added to themselves
+    archive_paths: Vec<PathBuf>,
+    entry_count: usize
+}
+
+impl ArchiveWatcher {
+    /// Start watching the directory and all directories below it
+    pub fn new(mut archive: FctArchive, directory: &PathBuf, options: WatchOptions) -> Result<Self, &'static str> {
+        // entries are stored relative to the current directory, which needs the paths of events to be absolute
+        let directory = unwrap_or_return_error!(std::fs::canonicalize(directory), "Could not find directory");
+        let mut inotify = Inotify::new()?;
+        inotify.add_watches(&directory)?;
+        let current_dir = unwrap_or_return_error!(std::env::current_dir(), "Could not get current directory");
+        let mut archived = HashMap::new();
+        // entries of archives without modification times could be outdated, so only the others count as archived
+        for header in archive.get_headers() {
+            if header.features & file_parser::FEATURE_MODIFIED_TIME != 0 {
+                archived.insert(absolute(&current_dir.join(&header.file_path)), (header.logical_size(), header.modified, None));
+            }
+        }
+        let entry_count = archive.get_headers().len();
+        let archive_path = absolute(&archive.archive_path);
+        Ok(ArchiveWatcher {
+            archive,
+            options,
+            inotify,
+            directory,
+            pending: HashMap::new(),
+            archived,
+            archive_paths: vec![archive_path],
+            entry_count
+        })
+    }
+
+    /// The archive files are currently added to
+    pub fn archive(&mut self) -> &mut FctArchive {
+        &mut self.archive
+    }
+
+    /// Add files as they are completed until the stop flag is set
+    pub fn run(&mut self, stop: &AtomicBool) -> Result<(), &'static str> {
+        while !stop.load(Ordering::Relaxed) {
+            // wake up when the next pending file is due, but check the stop flag regularly
+            let now = Instant::now();
+            let timeout = self.pending.values()
+                .map(|changed| (*changed + self.options.debounce).saturating_duration_since(now))
+                .min()
+                .unwrap_or(Duration::from_secs(1))
+                .min(Duration::from_secs(1));
+            for event in self.inotify.wait(timeout)? {
+                self.handle_event(event);
+            }
+            self.add_completed_files()?;
+        }
+        Ok(())
+    }
+
+    fn handle_event(&mut self, event: WatchEvent) {
+        match event {
+            WatchEvent::DirectoryAdded(path) => self.scan(&path),
+            WatchEvent::FileChanged(path) => self.queue(path),
+            WatchEvent::FileRemoved(path) => {
+                self.pending.remove(&path);
+            },
+            WatchEvent::Overflow => {
+                // the lost events could have been about any file, so all of them are checked again
+                println!("Too many changes at once, scanning the directory again");
+                let directory = self.directory.clone();
+                self.scan(&directory);
+            }
+        }
+    }
+
+    // watch a directory and queue the files below it, which can be created before the directory is watched
+    fn scan(&mut self, directory: &Path) {
+        if let Err(e) = self.inotify.add_watches(directory) {
+            println!("Could not watch {}: {}", directory.display(), e);
+        }
+        // the readable part of the directory is still added when some of it cannot be read
+        let mut files = match fs_operations::expand_paths_iter([directory.to_path_buf()], &fs_operations::ExpandOptions::default()) {
+            Ok(files) => files,
+            Err(e) => return println!("Could not scan {}: {}", directory.display(), e)
+        };
+        for file_path in files.by_ref() {
+            self.queue(file_path);
+        }
+        for (path, e) in files.take_failed() {
+            println!("{}: {}", e, path.display());
+        }
+    }
+
+    // wait for the file to be complete, unless it is one of the archives
+    fn queue(&mut self, path: PathBuf) {
+        if !self.archive_paths.iter().any(|archive_path| storage::is_archive_file(archive_path, &path)) {
+            self.pending.insert(path, Instant::now());
+        }
+    }
+
+    // whether the file is the same as when it was archived
+    fn is_archived(&self, path: &Path, metadata: &Metadata) -> bool {
+        let (size, seconds, nanoseconds) = match self.archived.get(path) {
+            Some(archived) => *archived,
+            None => return false
+        };
+        let modified = modified_time(metadata);
+        metadata.len() == size && modified.as_secs() == seconds && nanoseconds.is_none_or(|n| n == modified.subsec_nanos())
+    }
+
+    // add the files that have not been changed for the debounce time
+    fn add_completed_files(&mut self) -> Result<(), &'static str> {
+        let now = Instant::now();
+        let mut completed: Vec<PathBuf> = self.pending.iter()
+            .filter(|(_, changed)| now.duration_since(**changed) >= self.options.debounce)
+            .map(|(path, _)| path.clone())
+            .collect();
+        if completed.is_empty() {
+            return Ok(());
+        }
+        completed.sort();
+        for path in completed {
+            self.pending.remove(&path);
+            let metadata = match std::fs::metadata(&path) {
+                Ok(metadata) if metadata.is_file() => metadata,
+                _ => continue
+            };
+            if self.is_archived(&path, &metadata) {
+                continue;
+            }
+            match self.archive.add_file(&path) {
+                Ok(_) => {
+                    self.entry_count += 1;
+                    let modified = modified_time(&metadata);
+                    self.archived.insert(path, (metadata.len(), modified.as_secs(), Some(modified.subsec_nanos())));
+                },
+                Err(e) => println!("Error adding file: {}", e)
+            }
+            if self.rotation_due()? {
+                self.rotate()?;
+            }
+        }
+        unwrap_or_return_error!(self.archive.archive_file.flush(), "Could not write to archive");
+        Ok(())
+    }
+
+    fn rotation_due(&mut self) -> Result<bool, &'static str> {
+        let rotation = self.options.rotation;
+        if let Some(max_entries) = rotation.max_entries {
+            if self.entry_count >= max_entries {
+                return Ok(true);
+            }
+        }
+        if let Some(max_size) = rotation.max_size {
+            let size = unwrap_or_return_error!(self.archive.archive_file.seek(SeekFrom::End(0)), "Could not get archive size");
+            return Ok(size >= max_size);
+        }
+        Ok(false)
+    }
+
+    // move the full archive to a free numbered name next to it and continue in a new archive with the same format
+    fn rotate(&mut self) -> Result<(), &'static str> {
+        unwrap_or_return_error!(self.archive.archive_file.flush(), "Could not write to archive");
+        let archive_path = self.archive.archive_path.clone();
+        let rotated_path = fs_operations::find_free_name(&archive_path, |path| path.exists() || storage::is_volume_set(path));
+        unwrap_or_return_error!(storage::replace_archive(&archive_path, &rotated_path), "Could not rotate archive");
+        self.archive = FctArchive::create_with_options(&archive_path, self.archive.chunk_size, &self.archive.options())?;
+        self.entry_count = 0;
+        println!("Rotated archive to {}", rotated_path.display());
+        self.archive_paths.push(absolute(&rotated_path));
+        Ok(())
+    }
+}
+
+fn modified_time(metadata: &Metadata) -> Duration {
+    metadata.modified().ok().and_then(|time| time.duration_since(UNIX_EPOCH).ok()).unwrap_or_default()
+}
+
+// canonical path of a file that may not exist yet
+fn absolute(path: &Path) -> PathBuf {
+    match (path.parent().map(|parent| if parent.as_os_str().is_empty() { Path::new(".") } else { parent }), path.file_name()) {
+        (Some(parent), Some(name)) => match std::fs::canonicalize(parent) {
+            Ok(parent) => parent.join(name),
+            Err(_) => path.to_path_buf()
+        },
+        _ => path.to_path_buf()
+    }
+}
+
+enum WatchEvent {
+    DirectoryAdded(PathBuf),
+    /// A file was created, written to, closed after writing or moved into the directory
+    FileChanged(PathBuf),
+    /// A file was deleted or moved out of the directory
+    FileRemoved(PathBuf),
+    /// The kernel dropped events
+    Overflow
+}
+
+#[cfg(target_os = "linux")]
+struct Inotify {
+    fd: i32,
+    // watched directory of every watch descriptor
+    watches: HashMap<i32, PathBuf>
+}
+
+#[cfg(target_os = "linux")]
+impl Inotify {
+    fn new() -> Result<Self, &'static str> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        if fd < 0 {
+            return Err("Could not start watching");
+        }
+        Ok(Inotify { fd, watches: HashMap::new() })
+    }
+
+    // watch a directory and all directories below it
+    fn add_watches(&mut self, directory: &Path) -> Result<(), &'static str> {
+        use std::os::unix::ffi::OsStrExt;
+        let mask = libc::IN_CREATE | libc::IN_MODIFY | libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_MOVED_FROM | libc::IN_DELETE;
+        let path = unwrap_or_return_error!(std::ffi::CString::new(directory.as_os_str().as_bytes()), "Invalid directory path");
+        let wd = unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), mask) };
+        if wd < 0 {
+            return Err("Could not watch directory");
+        }
+        self.watches.insert(wd, directory.to_path_buf());
+        let entries = unwrap_or_return_error!(std::fs::read_dir(directory), "Could not read directory");
+        for entry in entries.flatten() {
+            if entry.file_type().map(|file_type| file_type.is_dir()).unwrap_or(false) {
+                self.add_watches(&entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    // wait up to the timeout for events and return them
+    fn wait(&mut self, timeout: Duration) -> Result<Vec<WatchEvent>, &'static str> {
+        let mut poll_fd = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+        let ready = unsafe { libc::poll(&mut poll_fd, 1, timeout.as_millis() as libc::c_int) };
+        if ready < 0 {
+            // interrupted by a signal, which is no reason to stop
+            if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+                return Ok(Vec::new());
+            }
+            return Err("Could not wait for changes");
+        }
+        let mut events = Vec::new();
+        let mut buffer = [0u8; 4096];
+        loop {
+            let length = unsafe { libc::read(self.fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+            if length <= 0 {
+                return Ok(events);
+            }
+            self.parse_events(&buffer[..length as usize], &mut events);
+        }
+    }
+
+    // events are a struct inotify_event each, followed by the NUL padded name of the file
+    fn parse_events(&mut self, buffer: &[u8], events: &mut Vec<WatchEvent>) {
+        use std::os::unix::ffi::OsStrExt;
+        const EVENT_HEADER_SIZE: usize = 16;
+        let mut offset = 0;
+        while offset + EVENT_HEADER_SIZE <= buffer.len() {
+            let wd = i32::from_ne_bytes(buffer[offset..offset + 4].try_into().unwrap());
+            let mask = u32::from_ne_bytes(buffer[offset + 4..offset + 8].try_into().unwrap());
+            let name_length = u32::from_ne_bytes(buffer[offset + 12..offset + 16].try_into().unwrap()) as usize;
+            let name_bytes = &buffer[offset + EVENT_HEADER_SIZE..offset + EVENT_HEADER_SIZE + name_length];
+            offset += EVENT_HEADER_SIZE + name_length;
+
+            if mask & libc::IN_Q_OVERFLOW != 0 {
+                events.push(WatchEvent::Overflow);
+                continue;
+            }
+            if mask & libc::IN_IGNORED != 0 {
+                self.watches.remove(&wd);
+                continue;
+            }
+            let directory = match self.watches.get(&wd) {
+                Some(directory) => directory,
+                None => continue
+            };
+            let name_end = name_bytes.iter().position(|byte| *byte == 0).unwrap_or(name_bytes.len());
+            let path = directory.join(std::ffi::OsStr::from_bytes(&name_bytes[..name_end]));
+            let event = if mask & (libc::IN_DELETE | libc::IN_MOVED_FROM) != 0 {
+                WatchEvent::FileRemoved(path)
+            }
+            else if mask & libc::IN_ISDIR != 0 {
+                match mask & (libc::IN_CREATE | libc::IN_MOVED_TO) {
+                    0 => continue,
+                    _ => WatchEvent::DirectoryAdded(path)
+                }
+            }
+            else {
+                WatchEvent::FileChanged(path)
+            };
+            events.push(event);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+// watching needs inotify, so other systems only get an error
+#[cfg(not(target_os = "linux"))]
+struct Inotify;
+
+#[cfg(not(target_os = "linux"))]
+impl Inotify {
+    fn new() -> Result<Self, &'static str> {
+        Err("Watching directories is only supported on Linux")
+    }
+
+    fn add_watches(&mut self, _directory: &Path) -> Result<(), &'static str> {
+        Err("Watching directories is only supported on Linux")
+    }
+
+    fn wait(&mut self, _timeout: Duration) -> Result<Vec<WatchEvent>, &'static str> {
+        Err("Watching directories is only supported on Linux")
+    }
+}
+
+// the watcher is driven through its events here, so that the tests do not depend on the timing of inotify
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::fct_archive::ArchiveOptions;
+    use crate::fct_archive::tests::{noise, test_dir};
+
+    fn start_watcher(dir: &Path, archive_path: &Path, options: WatchOptions) -> ArchiveWatcher {
+        let archive = FctArchive::create_with_options(archive_path, 1024, &ArchiveOptions::default()).unwrap();
+        ArchiveWatcher::new(archive, &dir.join("input"), options).unwrap()
+    }
+
+    fn entry_count(archive_path: &Path) -> usize {
+        FctArchive::open(&archive_path.to_path_buf()).unwrap().get_headers().len()
+    }
+
+    #[test]
+    fn files_are_added_once_they_stay_unchanged() {
+        let dir = test_dir("watch-debounce");
+        let mut watcher = start_watcher(&dir, &dir.join("test.fct"), WatchOptions { debounce: Duration::from_secs(3600), ..Default::default() });
+        let input = std::fs::canonicalize(dir.join("input")).unwrap();
+        std::fs::write(input.join("a"), noise(3000, 1)).unwrap();
+        std::fs::write(input.join("b"), noise(100, 2)).unwrap();
+        watcher.handle_event(WatchEvent::FileChanged(input.join("a")));
+        watcher.handle_event(WatchEvent::FileChanged(input.join("b")));
+        watcher.add_completed_files().unwrap();
+        assert!(watcher.archive().get_headers().is_empty());
+
+        // a change restarts the wait, a removal ends it
+        watcher.options.debounce = Duration::from_secs(5);
+        let past = Instant::now() - Duration::from_secs(10);
+        watcher.pending.values_mut().for_each(|changed| *changed = past);
+        watcher.handle_event(WatchEvent::FileChanged(input.join("b")));
+        watcher.handle_event(WatchEvent::FileRemoved(input.join("b")));
+        watcher.handle_event(WatchEvent::FileChanged(input.join("b")));
+        watcher.add_completed_files().unwrap();
+        assert_eq!(watcher.archive().get_headers().len(), 1);
+        assert!(watcher.pending.contains_key(&input.join("b")));
+
+        // files are added again only when they were written to since
+        watcher.options.debounce = Duration::ZERO;
+        watcher.handle_event(WatchEvent::FileChanged(input.join("a")));
+        watcher.add_completed_files().unwrap();
+        assert_eq!(watcher.archive().get_headers().len(), 2);
+        watcher.handle_event(WatchEvent::FileChanged(input.join("a")));
+        watcher.handle_event(WatchEvent::FileChanged(input.join("b")));
+        watcher.add_completed_files().unwrap();
+        assert_eq!(watcher.archive().get_headers().len(), 2);
+        std::fs::write(input.join("a"), noise(4000, 3)).unwrap();
+        watcher.handle_event(WatchEvent::FileChanged(input.join("a")));
+        watcher.add_completed_files().unwrap();
+        let sizes: Vec<u64> = watcher.archive().get_headers().iter().map(|header| header.logical_size()).collect();
+        assert_eq!(sizes, vec![3000, 100, 4000]);
+    }
+
+    #[test]
+    fn archives_in_the_watched_directory_are_not_added() {
+        let dir = test_dir("watch-archives");
+        let input = std::fs::canonicalize(dir.join("input")).unwrap();
+        let archive_path = input.join("test.fct");
+        let rotation = RotationPolicy { max_entries: Some(1), ..Default::default() };
+        let mut watcher = start_watcher(&dir, &archive_path, WatchOptions { debounce: Duration::ZERO, rotation });
+        std::fs::write(input.join("a"), noise(100, 1)).unwrap();
+        std::fs::write(input.join("b"), noise(100, 2)).unwrap();
+        watcher.handle_event(WatchEvent::Overflow);
+        watcher.add_completed_files().unwrap();
+        assert_eq!(entry_count(&input.join("test.1.fct")), 1);
+        assert_eq!(entry_count(&input.join("test.2.fct")), 1);
+
+        // neither the archive, the rotated archives nor the unchanged files are picked up by a rescan
+        watcher.handle_event(WatchEvent::Overflow);
+        let mut pending: Vec<&PathBuf> = watcher.pending.keys().collect();
+        pending.sort();
+        assert_eq!(pending, vec![&input.join("a"), &input.join("b")]);
+        watcher.add_completed_files().unwrap();
+        assert!(watcher.archive().get_headers().is_empty());
+        assert!(!input.join("test.3.fct").exists());
+
+        // the same holds for the files of a new directory
+        std::fs::create_dir(input.join("sub")).unwrap();
+        std::fs::copy(input.join("test.1.fct"), input.join("sub").join("c")).unwrap();
+        std::fs::write(input.join("sub").join("d"), noise(100, 3)).unwrap();
+        watcher.handle_event(WatchEvent::DirectoryAdded(input.join("sub")));
+        let mut pending: Vec<&PathBuf> = watcher.pending.keys().collect();
+        pending.sort();
+        assert_eq!(pending, vec![&input.join("sub").join("c"), &input.join("sub").join("d")]);
+    }
+
+    #[test]
+    fn archives_rotate_by_entries_and_size() {
+        let dir = test_dir("watch-rotation");
+        let input = std::fs::canonicalize(dir.join("input")).unwrap();
+        let archive_path = dir.join("test.fct");
+        for (index, name) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+            std::fs::write(input.join(name), noise(1000, index as u32)).unwrap();
+        }
+
+        let rotation = RotationPolicy { max_entries: Some(2), ..Default::default() };
+        let mut watcher = start_watcher(&dir, &archive_path, WatchOptions { debounce: Duration::ZERO, rotation });
+        watcher.handle_event(WatchEvent::Overflow);
+        watcher.add_completed_files().unwrap();
+        drop(watcher);
+        assert_eq!(entry_count(&dir.join("test.1.fct")), 2);
+        assert_eq!(entry_count(&dir.join("test.2.fct")), 2);
+        assert_eq!(entry_count(&archive_path), 1);
+
+        // every entry takes a 1024 byte chunk, so the third one brings the archive past 3000 bytes
+        let dir = test_dir("watch-rotation-size");
+        let input = std::fs::canonicalize(dir.join("input")).unwrap();
+        let archive_path = dir.join("test.fct");
+        for (index, name) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+            std::fs::write(input.join(name), noise(1000, index as u32)).unwrap();
+        }
+        let rotation = RotationPolicy { max_size: Some(3000), ..Default::default() };
+        let mut watcher = start_watcher(&dir, &archive_path, WatchOptions { debounce: Duration::ZERO, rotation });
+        watcher.handle_event(WatchEvent::Overflow);
+        watcher.add_completed_files().unwrap();
+        drop(watcher);
+        assert!(std::fs::metadata(dir.join("test.1.fct")).unwrap().len() >= 3000);
+        assert_eq!(entry_count(&dir.join("test.1.fct")), 3);
+        assert_eq!(entry_count(&archive_path), 2);
+        assert!(!dir.join("test.2.fct").exists());
+    }
+}